@@ -9,7 +9,7 @@ fn new_array_for_rhai(l:rhai_bytecode::INT,v:rhai_bytecode::rhai::Dynamic)->rhai
     return rhai_bytecode::rhai::Dynamic::from_array(vec![v; l as usize]);
 }
 
-fn new_array_for_rhai_bytecode(args: &[Rc<RefCell<SimpleDynamicValue>>]) -> anyhow::Result<Rc<RefCell<SimpleDynamicValue>>>  {
+fn new_array_for_rhai_bytecode(args: &[Rc<RefCell<SimpleDynamicValue>>]) -> rhai_bytecode::Result<Rc<RefCell<SimpleDynamicValue>>>  {
     let l=args[0].borrow().to_size()? as usize; // Never panics when single-threaded.
     let element = args[1].borrow().clone(); // Never panics when single-threaded.
     let mut new_ary=rhai_bytecode::VEC::with_capacity(l);
@@ -25,6 +25,30 @@ fn compress_data( dat:&[u8]) -> Vec<u8> {
     return encoder.finish().expect("Failed to finish compression");
 }
 
+// Times runs of a script compiled with and without fused byte codes, printing the speedup of fusing them.
+fn report_fusion_speedup(executer: &mut rhai_bytecode::Executer<SimpleDynamicValue>, name: &str, script: &str, rounds: usize) {
+    let fused_program = rhai_bytecode::CompiledProgram::from_script(executer, &[], script).unwrap();
+    executer.set_fuse_instructions(false);
+    let unfused_program = rhai_bytecode::CompiledProgram::from_script(executer, &[], script).unwrap();
+    executer.set_fuse_instructions(true);
+    let mut times_fused = Vec::<f64>::new();
+    let mut times_unfused = Vec::<f64>::new();
+    for _r in 0..rounds {
+        let now = std::time::Instant::now();
+        let res_fused = fused_program.run(executer, &[]).unwrap();
+        times_fused.push(now.elapsed().as_secs_f64());
+        let now = std::time::Instant::now();
+        let res_unfused = unfused_program.run(executer, &[]).unwrap();
+        times_unfused.push(now.elapsed().as_secs_f64());
+        assert_eq!(format!("{:?}", res_fused), format!("{:?}", res_unfused));
+    }
+    times_fused.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    times_unfused.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    println!("{}: {} byte codes unfused, {} fused", name, unfused_program.byte_codes.len(), fused_program.byte_codes.len());
+    println!("Median time unfused: {}, fused: {} ({:.2}x speedup)",
+        times_unfused[rounds / 2], times_fused[rounds / 2], times_unfused[rounds / 2] / times_fused[rounds / 2]);
+}
+
 fn main() {
     const ROUNDS: usize = 9;
     let script = "//! This script uses the Sieve of Eratosthenes to calculate prime numbers.
@@ -48,43 +72,61 @@ total_primes_found";
     let ast = engine.compile(script).unwrap();
     let mut executer = sample::new_executer().unwrap();
     executer.add_fn("new_array", new_array_for_rhai_bytecode,2,2).unwrap();
-    let mut variable_names = Vec::<String>::new();
-    let byte_codes= rhai_bytecode::ast_to_byte_codes(&executer, &mut variable_names, &ast).unwrap();
-    let json = serde_json::to_string(&byte_codes).unwrap();
+    let program = rhai_bytecode::CompiledProgram::from_ast(&executer, &[], &ast).unwrap();
+    let json = serde_json::to_string(&program).unwrap();
     println!("Serilized JSON = {}", json);
+    println!("Disassembly:\n{}", program.disassemble());
     let compressed_script=compress_data(script.as_bytes());
     let compressed_byte_codes=compress_data(json.as_bytes());
     println!("Original script length = {} ({}% of original script)", script.len(),100);
     println!("Compressed script length = {} ({}% of original script)", compressed_script.len(),compressed_script.len()*100/script.len());
     println!("JSON length = {} ({}% of original script)", json.len(),json.len()*100/script.len());
     println!("Compressed JSON length = {} ({}% of original JSON)", compressed_byte_codes.len(),compressed_byte_codes.len()*100/json.len());
-    let byte_codes_restored = serde_json::from_str::<Vec<rhai_bytecode::ByteCode>>(&json).unwrap();
+    let binary = program.to_bytes().unwrap();
+    let compressed_binary=compress_data(&binary);
+    println!("Binary length = {} ({}% of original script)", binary.len(),binary.len()*100/script.len());
+    println!("Compressed binary length = {} ({}% of original binary)", compressed_binary.len(),compressed_binary.len()*100/binary.len());
+    let program_restored = serde_json::from_str::<rhai_bytecode::CompiledProgram>(&json).unwrap();
+    let program_from_binary = rhai_bytecode::CompiledProgram::from_bytes(&binary).unwrap();
+    assert_eq!(serde_json::to_string(&program_from_binary).unwrap(), json);
+    let linked_program = program_restored.link(&executer).unwrap();
+    let mut optimized_program = program_restored.clone();
+    let removed = rhai_bytecode::optimize(&mut optimized_program, rhai_bytecode::OptimizationLevel::Full);
+    println!("Optimized disassembly ({} of {} byte codes removed):\n{}", removed, program.byte_codes.len(), optimized_program.disassemble());
+    let linked_optimized_program = optimized_program.link(&executer).unwrap();
     let mut times_byte_code = Vec::<f64>::new();
+    let mut times_optimized = Vec::<f64>::new();
     let mut times_ast = Vec::<f64>::new();
-    println!("Round\tResults\t\tTime");
-    println!("\tBytecode\tAST\tBytecode\tAST");
+    println!("Round\tResults\t\t\tTime");
+    println!("\tBytecode\tOptimized\tAST\tBytecode\tOptimized\tAST");
     for r in 0..ROUNDS {
         let now = std::time::Instant::now();
-        let res_byte_code = rhai_bytecode::run_byte_codes::<SimpleDynamicValue>(
-            &executer,
-            &byte_codes_restored,
-            &vec![],
-        )
-        .unwrap();
+        let res_byte_code = linked_program.run(&[]).unwrap();
         let time_byte_code=now.elapsed().as_secs_f64();
         let now = std::time::Instant::now();
+        let res_optimized = linked_optimized_program.run(&[]).unwrap();
+        let time_optimized=now.elapsed().as_secs_f64();
+        let now = std::time::Instant::now();
         let res_ast = engine
             .eval_ast::<rhai_bytecode::rhai::Dynamic>(&ast)
             .unwrap();
         let time_ast=now.elapsed().as_secs_f64();
-        println!("{}\t{:?}\t{:?}\t{}\t{}",r, res_byte_code,res_ast,time_byte_code,time_ast);
+        println!("{}\t{:?}\t{:?}\t{:?}\t{}\t{}\t{}",r, res_byte_code,res_optimized,res_ast,time_byte_code,time_optimized,time_ast);
         times_byte_code.push(time_byte_code);
+        times_optimized.push(time_optimized);
         times_ast.push(time_ast);
         // Results should be 78498.
     }
     times_byte_code.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    times_optimized.sort_by(|a, b| a.partial_cmp(b).unwrap());
     times_ast.sort_by(|a, b| a.partial_cmp(b).unwrap());
     println!("Median time:");
     println!("Bytecode: {} ({}%)",times_byte_code[ROUNDS / 2],((times_byte_code[ROUNDS / 2] * 100.0) / times_ast[ROUNDS / 2]+0.5) as u16);
+    println!("Optimized bytecode: {} ({}%)",times_optimized[ROUNDS / 2],((times_optimized[ROUNDS / 2] * 100.0) / times_ast[ROUNDS / 2]+0.5) as u16);
     println!("AST: {} (100%)",times_ast[ROUNDS / 2]);
+    let (res_profiled, profile) = linked_program.run_with_profile(&[]).unwrap();
+    println!("Profiled run: {:?}", res_profiled);
+    print!("{}", profile);
+    report_fusion_speedup(&mut executer, "Sieve of Eratosthenes", script, ROUNDS);
+    report_fusion_speedup(&mut executer, "scripts/speed_test.rhai", include_str!("../../../scripts/speed_test.rhai"), ROUNDS);
 }
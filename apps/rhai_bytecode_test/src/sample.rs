@@ -1,6 +1,7 @@
 use std::cell::RefCell;
+use std::collections::BTreeMap;
 use std::rc::Rc;
-use rhai_bytecode::{self, DynamicConstant,DynamicValue};
+use rhai_bytecode::{self, DynamicConstant,DynamicValue,Error,ErrorKind};
 
 macro_rules! add_int_int {
     ($a:ident, $b:ident) => {
@@ -19,8 +20,8 @@ macro_rules! multiply_int_int {
 }
 macro_rules! divide_int_int {
     ($a:ident, $b:ident) => {
-        if *$a == 0 {
-            anyhow::bail!("Divisor can not be zero!");
+        if *$b == 0 {
+            return Err(ErrorKind::Exception(DynamicConstant::String("Divisor can not be zero!".to_string())).into());
         }else{
             return Ok(Self::Integer(*$a / *$b));
         }
@@ -28,7 +29,7 @@ macro_rules! divide_int_int {
 }
 macro_rules! create_simple_arithmetic{
     ($func_name:ident,$macro_int_int:tt,$op:tt)=>{
-        fn $func_name(&self,other: &Self) -> anyhow::Result<Self>{
+        fn $func_name(&self,other: &Self) -> rhai_bytecode::Result<Self>{
             match (self,other) {
                 (Self::Integer(va), Self::Integer(vb)) => {
                     $macro_int_int!(va,vb);
@@ -43,12 +44,12 @@ macro_rules! create_simple_arithmetic{
                     return Ok(Self::Float(*va $op *vb));
                 }
                 _ => {
-                    anyhow::bail!(
+                    return Err(Error::type_error(format_args!(
                         "Cannot calculate \"{}\" for \"{:?}\" and \"{:?}\"!",
                         stringify!($op),
                         self,
                         other
-                    );
+                    )));
                 }
             }
         }
@@ -56,7 +57,7 @@ macro_rules! create_simple_arithmetic{
 }
 macro_rules! create_simple_compare{
     ($func_name:ident,$op:tt)=>{
-        fn $func_name(&self,other: &Self) -> anyhow::Result<bool>{
+        fn $func_name(&self,other: &Self) -> rhai_bytecode::Result<bool>{
             match (self,other){
                 (Self::Unit, Self::Unit) => {
                     return Ok(false $op false);
@@ -77,12 +78,12 @@ macro_rules! create_simple_compare{
                     return Ok(*va $op *vb);
                 }
                 _ => {
-                    anyhow::bail!(
+                    return Err(Error::type_error(format_args!(
                         "Cannot calculate \"{}\" for \"{:?}\" and \"{:?}\"!",
                         stringify!($op),
                         self,
                         other
-                    );
+                    )));
                 }
             }
         }
@@ -90,7 +91,7 @@ macro_rules! create_simple_compare{
 }
 macro_rules! create_simple_binary_function {
     ($func_name:ident)=>{
-        fn $func_name(args: &[Rc<RefCell<SimpleDynamicValue>>]) -> anyhow::Result<Rc<RefCell<SimpleDynamicValue>>>  {
+        fn $func_name(args: &[Rc<RefCell<SimpleDynamicValue>>]) -> rhai_bytecode::Result<Rc<RefCell<SimpleDynamicValue>>>  {
             let res=args[0].borrow().$func_name(&args[1].borrow())?; // Never panics when single-threaded.
             return Ok(Rc::new(RefCell::new(res)));
         }
@@ -98,7 +99,7 @@ macro_rules! create_simple_binary_function {
 }
 macro_rules! create_operator_assign_function {
     ($func_name:ident,$operator_name:ident)=>{
-        fn $func_name(args: &[Rc<RefCell<SimpleDynamicValue>>]) -> anyhow::Result<Rc<RefCell<SimpleDynamicValue>>>  {
+        fn $func_name(args: &[Rc<RefCell<SimpleDynamicValue>>]) -> rhai_bytecode::Result<Rc<RefCell<SimpleDynamicValue>>>  {
             let res=args[0].borrow().$operator_name(&args[1].borrow())?; // Never panics when single-threaded.
             *(args[0].borrow_mut())=res; // Never panics when single-threaded.
             return Ok(args[0].clone());
@@ -107,25 +108,28 @@ macro_rules! create_operator_assign_function {
 }
 macro_rules! create_simple_compare_function {
     ($func_name:ident)=>{
-        fn $func_name(args: &[Rc<RefCell<SimpleDynamicValue>>]) -> anyhow::Result<Rc<RefCell<SimpleDynamicValue>>>  {
+        fn $func_name(args: &[Rc<RefCell<SimpleDynamicValue>>]) -> rhai_bytecode::Result<Rc<RefCell<SimpleDynamicValue>>>  {
             let res=args[0].borrow().$func_name(&args[1].borrow())?; // Never panics when single-threaded.
             return Ok(Rc::new(RefCell::new(SimpleDynamicValue::Bool(res))));
         }
     }
 }
 
-#[derive(Clone,Debug)]
+#[derive(Clone,Debug,serde::Serialize, serde::Deserialize)]
 pub(crate) enum SimpleDynamicValue {
     Unit,
     Bool(bool),
     Integer(rhai_bytecode::INT),
     Float(rhai_bytecode::FLOAT),
+    String(String),
     Array(rhai_bytecode::VEC<Rc<RefCell<SimpleDynamicValue>>>),
     Range(rhai_bytecode::INT,rhai_bytecode::INT),
+    Map(BTreeMap<String,Rc<RefCell<SimpleDynamicValue>>>),
+    FnPtr(Box<rhai_bytecode::FnPtr<SimpleDynamicValue>>),
 }
 
 impl DynamicValue for SimpleDynamicValue {
-    fn from_constant(v:DynamicConstant) -> anyhow::Result<Self> {
+    fn from_constant(v:DynamicConstant) -> rhai_bytecode::Result<Self> {
         match v {
             DynamicConstant::Unit => {
                 return Ok(Self::Unit);
@@ -139,6 +143,9 @@ impl DynamicValue for SimpleDynamicValue {
             DynamicConstant::Float(v) => {
                 return Ok(Self::Float(v));
             }
+            DynamicConstant::String(v) => {
+                return Ok(Self::String(v));
+            }
             DynamicConstant::Array(ary) => {
                 let mut new_ary = rhai_bytecode::VEC::<Rc<RefCell<Self>>>::with_capacity(ary.len());
                 for v in ary.iter() {
@@ -149,39 +156,91 @@ impl DynamicValue for SimpleDynamicValue {
             DynamicConstant::Range(start, len) => {
                 return Ok(Self::Range(start, len));
             }
+            DynamicConstant::Map(map) => {
+                let mut new_map = BTreeMap::<String, Rc<RefCell<Self>>>::new();
+                for (k, v) in map.into_iter() {
+                    new_map.insert(k, Rc::new(RefCell::new(Self::from_constant(v)?)));
+                }
+                return Ok(Self::Map(new_map));
+            }
             _=>{
-                anyhow::bail!("Connot convert from dynamic constant \"{:?}\"! Unsupported type!", v);
+                return Err(Error::type_error(format_args!("Connot convert from dynamic constant \"{:?}\"! Unsupported type!", v)));
             }
         }
     }
-    fn from_unit() -> anyhow::Result<Self> {
+    fn from_unit() -> rhai_bytecode::Result<Self> {
         return Ok(Self::Unit);
     }
-    fn from_bool(v:bool) -> anyhow::Result<Self> {
+    fn from_bool(v:bool) -> rhai_bytecode::Result<Self> {
         return Ok(Self::Bool(v));
     }
-    fn from_integer(v:rhai_bytecode::INT) -> anyhow::Result<Self> {
+    fn from_integer(v:rhai_bytecode::INT) -> rhai_bytecode::Result<Self> {
         return Ok(Self::Integer(v));
     }
-    fn from_float(v:rhai_bytecode::FLOAT) -> anyhow::Result<Self> {
+    fn from_float(v:rhai_bytecode::FLOAT) -> rhai_bytecode::Result<Self> {
         return Ok(Self::Float(v));
     }
-    fn from_char(v:char) -> anyhow::Result<Self> {
-        anyhow::bail!("Connot convert from char \"{}\"! Unsupported type!", v);
+    fn from_char(v:char) -> rhai_bytecode::Result<Self> {
+        return Err(Error::type_error(format_args!("Connot convert from char \"{}\"! Unsupported type!", v)));
     }
-    fn from_string(v:String) -> anyhow::Result<Self> {
-        anyhow::bail!("Connot convert from string \"{}\"! Unsupported type!", v);
+    fn from_string(v:String) -> rhai_bytecode::Result<Self> {
+        return Ok(Self::String(v));
     }
-    fn from_array(v:rhai_bytecode::VEC<Rc<RefCell<Self>>>) -> anyhow::Result<Self> {
+    fn from_array(v:rhai_bytecode::VEC<Rc<RefCell<Self>>>) -> rhai_bytecode::Result<Self> {
         return Ok(Self::Array(v));
     }
+    fn from_map(v:BTreeMap<String,Rc<RefCell<Self>>>) -> rhai_bytecode::Result<Self> {
+        return Ok(Self::Map(v));
+    }
+    fn from_fn_ptr(v:rhai_bytecode::FnPtr<Self>) -> rhai_bytecode::Result<Self> {
+        return Ok(Self::FnPtr(Box::new(v)));
+    }
     fn is_unit(&self) -> bool {
         match self {
             Self::Unit => {return true;}
             _ => {return false;}
         }
     }
-    fn to_bool(&self) -> anyhow::Result<bool> {
+    fn to_constant(&self) -> Option<DynamicConstant> {
+        match self {
+            Self::Unit => {
+                return Some(DynamicConstant::Unit);
+            }
+            Self::Bool(v) => {
+                return Some(DynamicConstant::Bool(*v));
+            }
+            Self::Integer(v) => {
+                return Some(DynamicConstant::Integer(*v));
+            }
+            Self::Float(v) => {
+                return Some(DynamicConstant::Float(*v));
+            }
+            Self::String(v) => {
+                return Some(DynamicConstant::String(v.clone()));
+            }
+            Self::Array(ary) => {
+                let mut new_ary = rhai_bytecode::VEC::<DynamicConstant>::with_capacity(ary.len());
+                for v in ary.iter() {
+                    new_ary.push(v.borrow().to_constant()?); // Never panics when single-threaded.
+                }
+                return Some(DynamicConstant::Array(new_ary));
+            }
+            Self::Range(start, len) => {
+                return Some(DynamicConstant::Range(*start, *len));
+            }
+            Self::Map(map) => {
+                let mut new_map = BTreeMap::<String, DynamicConstant>::new();
+                for (k, v) in map.iter() {
+                    new_map.insert(k.clone(), v.borrow().to_constant()?); // Never panics when single-threaded.
+                }
+                return Some(DynamicConstant::Map(new_map));
+            }
+            Self::FnPtr(_) => {
+                return None;
+            }
+        }
+    }
+    fn to_bool(&self) -> rhai_bytecode::Result<bool> {
         match self {
             Self::Bool(v) => {
                 return Ok(*v);
@@ -193,36 +252,111 @@ impl DynamicValue for SimpleDynamicValue {
                 return Ok(!v.is_nan() && *v != 0.0);
             }
             _ => {
-                anyhow::bail!("Cannot convert \"{:?}\" to bool!", self);
+                return Err(Error::type_error(format_args!("Cannot convert \"{:?}\" to bool!", self)));
             }
         }
     }
-    fn to_size(&self) -> anyhow::Result<rhai_bytecode::SIZE> {
+    fn to_size(&self) -> rhai_bytecode::Result<rhai_bytecode::SIZE> {
         match self {
             Self::Integer(v) => {
                 return Ok(*v as rhai_bytecode::SIZE);
             }
             _ => {
-                anyhow::bail!("Cannot convert \"{:?}\" to size!", self);
+                return Err(Error::type_error(format_args!("Cannot convert \"{:?}\" to size!", self)));
+            }
+        }
+    }
+    fn to_fn_ptr(&self) -> rhai_bytecode::Result<rhai_bytecode::FnPtr<Self>> {
+        match self {
+            Self::FnPtr(v) => {
+                return Ok(v.as_ref().clone());
+            }
+            _ => {
+                return Err(Error::type_error(format_args!("Cannot convert \"{:?}\" to function pointer!", self)));
             }
         }
     }
-    fn index_into(&self,ind:rhai_bytecode::SIZE)->anyhow::Result<Rc<RefCell<Self>>> {
+    fn to_display_string(&self) -> rhai_bytecode::Result<String> {
+        match self {
+            Self::Unit => {
+                return Ok(String::new());
+            }
+            Self::Bool(v) => {
+                return Ok(v.to_string());
+            }
+            Self::Integer(v) => {
+                return Ok(v.to_string());
+            }
+            Self::Float(v) => {
+                return Ok(format!("{:?}", v));
+            }
+            Self::String(v) => {
+                return Ok(v.clone());
+            }
+            Self::Array(ary) => {
+                let mut items = Vec::<String>::with_capacity(ary.len());
+                for v in ary.iter() {
+                    items.push(v.borrow().to_element_string()?); // Never panics when single-threaded.
+                }
+                return Ok(format!("[{}]", items.join(", ")));
+            }
+            Self::Range(start, len) => {
+                return Ok(format!("{}..{}", start, start + len));
+            }
+            Self::Map(map) => {
+                let mut items = Vec::<String>::with_capacity(map.len());
+                for (k, v) in map.iter() {
+                    items.push(format!("{:?}: {}", k, v.borrow().to_element_string()?)); // Never panics when single-threaded.
+                }
+                return Ok(format!("#{{{}}}", items.join(", ")));
+            }
+            Self::FnPtr(v) => {
+                return Ok(format!("Fn({})", v.name));
+            }
+        }
+    }
+    fn index_into(&self,ind:rhai_bytecode::SIZE)->rhai_bytecode::Result<Rc<RefCell<Self>>> {
         match self {
             Self::Array(vec) => {
                 let index= ind as usize;
                 if index >= vec.len() {
-                    anyhow::bail!("Index \"{}\" out of range!",ind);
+                    return Err(Error::type_error(format_args!("Index \"{}\" out of range!",ind)));
                 } else {
                     return Ok(vec[index].clone());
                 }
             }
             _ => {
-                anyhow::bail!("Cannot index into \"{:?}\"!",self);
+                return Err(Error::type_error(format_args!("Cannot index into \"{:?}\"!",self)));
             }
         }
     }
-    fn iter(&self,index:rhai_bytecode::SIZE) -> anyhow::Result<Option<Rc<RefCell<Self>>>> {
+    fn get_property(&self,name:&str) -> rhai_bytecode::Result<Rc<RefCell<Self>>> {
+        match self {
+            Self::Map(map) => match map.get(name) {
+                Some(v) => {
+                    return Ok(v.clone());
+                }
+                None => {
+                    return Ok(Rc::new(RefCell::new(Self::Unit)));
+                }
+            },
+            _ => {
+                return Err(Error::type_error(format_args!("Cannot get property \"{}\" of \"{:?}\"!",name,self)));
+            }
+        }
+    }
+    fn set_property(&mut self,name:&str,value:Rc<RefCell<Self>>) -> rhai_bytecode::Result<()> {
+        match self {
+            Self::Map(map) => {
+                map.insert(name.to_string(), value);
+                return Ok(());
+            }
+            _ => {
+                return Err(Error::type_error(format_args!("Cannot set property \"{}\" of \"{:?}\"!",name,self)));
+            }
+        }
+    }
+    fn iter(&self,index:rhai_bytecode::SIZE) -> rhai_bytecode::Result<Option<Rc<RefCell<Self>>>> {
         match self {
             Self::Array(vec) => {
                 let ind= index as usize;
@@ -241,14 +375,41 @@ impl DynamicValue for SimpleDynamicValue {
                 }
             }
             _=> {
-                anyhow::bail!("Cannot iterate over \"{:?}\"!",self);
+                return Err(Error::type_error(format_args!("Cannot iterate over \"{:?}\"!",self)));
+            }
+        }
+    }
+    fn deep_clone(&self) -> Self {
+        match self {
+            Self::Array(ary) => {
+                return Self::Array(ary.iter().map(|v| Rc::new(RefCell::new(v.borrow().deep_clone()))).collect()); // Never panics when single-threaded.
+            }
+            Self::Map(map) => {
+                return Self::Map(map.iter().map(|(k, v)| (k.clone(), Rc::new(RefCell::new(v.borrow().deep_clone())))).collect()); // Never panics when single-threaded.
+            }
+            _ => {
+                return self.clone();
             }
         }
     }
 }
 
 impl SimpleDynamicValue {
-    fn not(&self) -> anyhow::Result<Self> {
+    // Elements of arrays and maps are shown like Rhai does, e.g. strings are quoted.
+    fn to_element_string(&self) -> rhai_bytecode::Result<String> {
+        match self {
+            Self::Unit => {
+                return Ok("()".to_string());
+            }
+            Self::String(v) => {
+                return Ok(format!("{:?}", v));
+            }
+            _ => {
+                return self.to_display_string();
+            }
+        }
+    }
+    fn not(&self) -> rhai_bytecode::Result<Self> {
         match self {
             Self::Unit => {
                 return Ok(Self::Bool(true));
@@ -263,14 +424,14 @@ impl SimpleDynamicValue {
                 return Ok(Self::Bool(v.is_nan()||*v == 0.0));
             }
             _=>{
-                anyhow::bail!(
+                return Err(Error::type_error(format_args!(
                     "Operator \"!\" can not be applied to \"{:?}\"!",
                     self
-                );
+                )));
             }
         }
     }
-    fn negative(&self) -> anyhow::Result<Self> {
+    fn negative(&self) -> rhai_bytecode::Result<Self> {
         match self {
             Self::Integer(va) => {
                 return Ok(Self::Integer(-va));
@@ -279,10 +440,10 @@ impl SimpleDynamicValue {
                 return Ok(Self::Float(-va));
             }
             _=>{
-                anyhow::bail!(
+                return Err(Error::type_error(format_args!(
                     "Operator \"-\" can not be applied to \"{:?}\"!",
                     self
-                );
+                )));
             }
         }
     }
@@ -290,25 +451,25 @@ impl SimpleDynamicValue {
     create_simple_arithmetic!(subtract,subtract_int_int,-);
     create_simple_arithmetic!(multiply,multiply_int_int,*);
     create_simple_arithmetic!(divide,divide_int_int,/);
-    fn modulus(&self,other: &Self) -> anyhow::Result<Self> {
+    fn modulus(&self,other: &Self) -> rhai_bytecode::Result<Self> {
         match (self,other) {
             (Self::Integer(va), Self::Integer(vb)) => {
                 if *vb == 0 {
-                    anyhow::bail!("Divisor can not be zero!");
+                    return Err(ErrorKind::Exception(DynamicConstant::String("Divisor can not be zero!".to_string())).into());
                 }else{
                     return Ok(Self::Integer(*va % *vb));
                 }
             }
             _ => {
-                anyhow::bail!(
+                return Err(Error::type_error(format_args!(
                     "Operator \"%\" can not be applied to \"{:?}\" and \"{:?}\"!",
                     self,
                     other
-                );
+                )));
             }
         }
     }
-    fn power(&self,other: &Self) -> anyhow::Result<Self> {
+    fn power(&self,other: &Self) -> rhai_bytecode::Result<Self> {
         match (self,other) {
             (Self::Integer(va), Self::Integer(vb)) => {
                 match (*vb).try_into() {
@@ -330,11 +491,11 @@ impl SimpleDynamicValue {
                 return Ok(Self::Float(va.powf(*vb)));
             }
             _ => {
-                anyhow::bail!(
+                return Err(Error::type_error(format_args!(
                     "Operator \"^\" can not be applied to \"{:?}\" and \"{:?}\"!",
                     self,
                     other
-                );
+                )));
             }
         }
     }
@@ -346,11 +507,11 @@ impl SimpleDynamicValue {
     create_simple_compare!(greater_than_equal_to,>=);
 }
 
-fn not(args: &[Rc<RefCell<SimpleDynamicValue>>]) -> anyhow::Result<Rc<RefCell<SimpleDynamicValue>>>  {
+fn not(args: &[Rc<RefCell<SimpleDynamicValue>>]) -> rhai_bytecode::Result<Rc<RefCell<SimpleDynamicValue>>>  {
     return Ok(Rc::new(RefCell::new(args[0].borrow().not()?))); // Never panics when single-threaded.
 }
 create_simple_binary_function!(add);
-fn subtract(args: &[Rc<RefCell<SimpleDynamicValue>>]) -> anyhow::Result<Rc<RefCell<SimpleDynamicValue>>>  {
+fn subtract(args: &[Rc<RefCell<SimpleDynamicValue>>]) -> rhai_bytecode::Result<Rc<RefCell<SimpleDynamicValue>>>  {
     let res=if args.len() == 1 { // Negative.
         args[0].borrow().negative()? // Never panics when single-threaded.
     }else{
@@ -362,7 +523,7 @@ create_simple_binary_function!(multiply);
 create_simple_binary_function!(divide);
 create_simple_binary_function!(modulus);
 create_simple_binary_function!(power);
-fn assign(args: &[Rc<RefCell<SimpleDynamicValue>>]) -> anyhow::Result<Rc<RefCell<SimpleDynamicValue>>>  {
+fn assign(args: &[Rc<RefCell<SimpleDynamicValue>>]) -> rhai_bytecode::Result<Rc<RefCell<SimpleDynamicValue>>>  {
     let rhs=args[1].borrow().clone(); // Never panics when single-threaded.
     *(args[0].borrow_mut())=rhs; // Never panics when single-threaded.
     return Ok(args[0].clone());
@@ -377,70 +538,174 @@ create_simple_compare_function!(less_than);
 create_simple_compare_function!(greater_than);
 create_simple_compare_function!(less_than_equal_to);
 create_simple_compare_function!(greater_than_equal_to);
-fn range(args: &[Rc<RefCell<SimpleDynamicValue>>]) -> anyhow::Result<Rc<RefCell<SimpleDynamicValue>>>  {
+fn range(args: &[Rc<RefCell<SimpleDynamicValue>>]) -> rhai_bytecode::Result<Rc<RefCell<SimpleDynamicValue>>>  {
     let a=args[0].borrow(); // Never panics when single-threaded.
     let b=args[1].borrow(); // Never panics when single-threaded.
     match (&*a,&*b) {
         (SimpleDynamicValue::Integer(va), SimpleDynamicValue::Integer(vb)) => {
             let l = vb-va;
             if l < 0{
-                anyhow::bail!("Range start \"{}\" is greater than end \"{}\"!", va, vb);
+                return Err(Error::host(format_args!("Range start \"{}\" is greater than end \"{}\"!", va, vb)));
             } else{
                 return Ok(Rc::new(RefCell::new(SimpleDynamicValue::Range(*va,l))));
             }
         }
         _=>{
-            anyhow::bail!(
+            return Err(Error::type_error(format_args!(
                 "Operator \"..\" can not be applied to \"{:?}\" and \"{:?}\"!",
                 a,
                 b
-            );
+            )));
         }
     }
 }
-fn range_inclusive(args: &[Rc<RefCell<SimpleDynamicValue>>]) -> anyhow::Result<Rc<RefCell<SimpleDynamicValue>>>  {
+fn range_inclusive(args: &[Rc<RefCell<SimpleDynamicValue>>]) -> rhai_bytecode::Result<Rc<RefCell<SimpleDynamicValue>>>  {
     let a=args[0].borrow(); // Never panics when single-threaded.
     let b=args[1].borrow(); // Never panics when single-threaded.
     match (&*a,&*b) {
         (SimpleDynamicValue::Integer(va), SimpleDynamicValue::Integer(vb)) => {
             let l = vb-va;
             if l < 0{
-                anyhow::bail!("Range start \"{}\" is greater than end \"{}\"!", va, vb);
+                return Err(Error::host(format_args!("Range start \"{}\" is greater than end \"{}\"!", va, vb)));
             } else{
                 return Ok(Rc::new(RefCell::new(SimpleDynamicValue::Range(*va,l+1))));
             }
         }
         _=>{
-            anyhow::bail!(
+            return Err(Error::type_error(format_args!(
                 "Operator \"..=\" can not be applied to \"{:?}\" and \"{:?}\"!",
                 a,
                 b
-            );
+            )));
         }
     }
 }
+fn len(args: &[Rc<RefCell<SimpleDynamicValue>>]) -> rhai_bytecode::Result<Rc<RefCell<SimpleDynamicValue>>>  {
+    let a=args[0].borrow(); // Never panics when single-threaded.
+    let len = match &*a {
+        SimpleDynamicValue::String(v) => v.chars().count(),
+        SimpleDynamicValue::Array(v) => v.len(),
+        SimpleDynamicValue::Map(v) => v.len(),
+        _ => {
+            return Err(Error::type_error(format_args!("Method \"len\" can not be applied to \"{:?}\"!", a)));
+        }
+    };
+    return Ok(Rc::new(RefCell::new(SimpleDynamicValue::Integer(len as rhai_bytecode::INT))));
+}
+fn push(args: &[Rc<RefCell<SimpleDynamicValue>>]) -> rhai_bytecode::Result<Rc<RefCell<SimpleDynamicValue>>>  {
+    let item=args[1].borrow().deep_clone(); // Never panics when single-threaded.
+    let mut a=args[0].borrow_mut(); // Never panics when single-threaded.
+    match &mut *a {
+        SimpleDynamicValue::Array(v) => {
+            v.push(Rc::new(RefCell::new(item)));
+            return Ok(Rc::new(RefCell::new(SimpleDynamicValue::Unit)));
+        }
+        _ => {
+            return Err(Error::type_error(format_args!("Method \"push\" can not be applied to \"{:?}\"!", a)));
+        }
+    }
+}
+fn type_of(args: &[Rc<RefCell<SimpleDynamicValue>>]) -> rhai_bytecode::Result<Rc<RefCell<SimpleDynamicValue>>>  {
+    let name = match &*args[0].borrow() { // Never panics when single-threaded.
+        SimpleDynamicValue::Unit => "()",
+        SimpleDynamicValue::Bool(_) => "bool",
+        SimpleDynamicValue::Integer(_) => std::any::type_name::<rhai_bytecode::INT>(),
+        SimpleDynamicValue::Float(_) => std::any::type_name::<rhai_bytecode::FLOAT>(),
+        SimpleDynamicValue::String(_) => "string",
+        SimpleDynamicValue::Array(_) => "array",
+        SimpleDynamicValue::Range(..) => "range",
+        SimpleDynamicValue::Map(_) => "map",
+        SimpleDynamicValue::FnPtr(_) => "Fn",
+    };
+    return Ok(Rc::new(RefCell::new(SimpleDynamicValue::String(name.to_string()))));
+}
 
-pub(crate) fn new_executer() -> anyhow::Result<rhai_bytecode::Executer<SimpleDynamicValue>> {
+pub(crate) fn new_executer() -> rhai_bytecode::Result<rhai_bytecode::Executer<SimpleDynamicValue>> {
     let mut executer = rhai_bytecode::Executer::<SimpleDynamicValue>::new();
-    executer.add_fn("!", not,1,1)?;
-    executer.add_fn("+", add,2,2)?;
-    executer.add_fn("-", subtract,1,2)?;
-    executer.add_fn("*", multiply,2,2)?;
-    executer.add_fn("/", divide,2,2)?;
-    executer.add_fn("%", modulus,2,2)?;
-    executer.add_fn("^", power,2,2)?;
+    executer.add_pure_fn("!", not,1,1)?;
+    executer.add_pure_fn("+", add,2,2)?;
+    executer.add_pure_fn("-", subtract,1,2)?;
+    executer.add_pure_fn("*", multiply,2,2)?;
+    executer.add_pure_fn("/", divide,2,2)?;
+    executer.add_pure_fn("%", modulus,2,2)?;
+    executer.add_pure_fn("^", power,2,2)?;
     executer.add_fn("=", assign,2,2)?;
     executer.add_fn("+=", add_assign,2,2)?;
     executer.add_fn("-=", subtract_assign,2,2)?;
     executer.add_fn("*=", multiply_assign,2,2)?;
     executer.add_fn("/=", divide_assign,2,2)?;
-    executer.add_fn("==", equals,2,2)?;
-    executer.add_fn("!=", not_equals,2,2)?;
-    executer.add_fn("<", less_than,2,2)?;
-    executer.add_fn(">", greater_than,2,2)?;
-    executer.add_fn("<=", less_than_equal_to,2,2)?;
-    executer.add_fn(">=", greater_than_equal_to,2,2)?;
-    executer.add_fn("..", range,2,2)?;
-    executer.add_fn("..=", range_inclusive,2,2)?;
+    executer.add_pure_fn("==", equals,2,2)?;
+    executer.add_pure_fn("!=", not_equals,2,2)?;
+    executer.add_pure_fn("<", less_than,2,2)?;
+    executer.add_pure_fn(">", greater_than,2,2)?;
+    executer.add_pure_fn("<=", less_than_equal_to,2,2)?;
+    executer.add_pure_fn(">=", greater_than_equal_to,2,2)?;
+    executer.add_pure_fn("..", range,2,2)?;
+    executer.add_pure_fn("..=", range_inclusive,2,2)?;
+    executer.add_pure_fn("type_of", type_of,1,1)?;
+    executer.add_method("len", len,1,1)?;
+    executer.add_method("push", push,2,2)?;
     return Ok(executer);
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(script: &str) -> rhai_bytecode::Result<SimpleDynamicValue> {
+        let executer = new_executer()?;
+        let byte_codes = rhai_bytecode::script_to_byte_codes(&executer, &mut Vec::new(), script)?;
+        return rhai_bytecode::run_byte_codes(&executer, &byte_codes, &[]);
+    }
+
+    #[test]
+    fn switch_cases() {
+        let script = "switch {} { 1 => 10, 2..4 => 100, _ => 1000 }";
+        for (value, expected) in [(0, 1000), (1, 10), (3, 100), (4, 1000)] {
+            let res = run(&script.replace("{}", &value.to_string()));
+            assert!(matches!(res, Ok(SimpleDynamicValue::Integer(v)) if v == expected));
+        }
+        let res = run("let x = 2; switch 5 { 1 => 1, 5 if x > 2 => 2, 5 => 3, _ => 4 }");
+        assert!(matches!(res, Ok(SimpleDynamicValue::Integer(3))));
+        let res = run("switch 9 { 1 => 10 }");
+        assert!(matches!(res, Ok(SimpleDynamicValue::Unit)));
+    }
+
+    #[test]
+    fn division_by_zero_is_catchable() {
+        let res = run(r#"let r = ""; try { r = 1 / 0; } catch (e) { r = e; } r"#);
+        assert!(matches!(res, Ok(SimpleDynamicValue::String(s)) if s == "Divisor can not be zero!"));
+        let res = run(r#"let r = ""; try { r = 1 % 0; } catch (e) { r = e; } r"#);
+        assert!(matches!(res, Ok(SimpleDynamicValue::String(s)) if s == "Divisor can not be zero!"));
+    }
+
+    #[test]
+    fn assignment_copies_nested_values() {
+        let res = run("let a = [1, 2, 3]; a[1] = 9; let b = a; b[0] = 7; a[0] + a[1]");
+        assert!(matches!(res, Ok(SimpleDynamicValue::Integer(10))));
+        let res = run("let a = [[1], #{x: [2]}]; let b = a; b[0][0] = 5; b[1].x[0] = 6; a[0][0] + a[1].x[0]");
+        assert!(matches!(res, Ok(SimpleDynamicValue::Integer(3))));
+    }
+
+    #[test]
+    fn method_calls() {
+        let res = run(r#"let a = [1]; a.push(2); a.push([3]); a.len() + "abc".len() + #{x: 1}.len()"#);
+        assert!(matches!(res, Ok(SimpleDynamicValue::Integer(7))));
+        let res = run("let a = []; let b = [1]; a.push(b); b[0] = 2; a[0][0]");
+        assert!(matches!(res, Ok(SimpleDynamicValue::Integer(1))));
+        assert!(run("let a = 1; a.push(2)").is_err());
+    }
+
+    #[test]
+    fn method_calls_fall_back_to_functions() {
+        let res = run("[1].type_of() + type_of(true)");
+        assert!(matches!(res, Ok(SimpleDynamicValue::String(s)) if s == "arraybool"));
+        assert!(run("[1].no_such_method()").is_err());
+    }
+
+    #[test]
+    fn division_by_non_zero() {
+        assert!(matches!(run("0 / 3"), Ok(SimpleDynamicValue::Integer(0))));
+        assert!(matches!(run("7 / 2"), Ok(SimpleDynamicValue::Integer(3))));
+    }
+}
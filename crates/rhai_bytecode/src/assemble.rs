@@ -0,0 +1,599 @@
+//! Assembly-like text format, parsed back into byte codes.
+//!
+//! Each line holds an optional label like `loop:`, then an instruction named like its `ByteCode` variant,
+//! with comma separated operands. Text after `;` is a comment, and a leading number is taken as the address,
+//! which must match, so that the output of `disassemble` can be read back:
+//!
+//! ```text
+//!         IntegerConstant 0
+//!         VarInit count
+//!         PopStack
+//! loop:   Variable count
+//!         IntegerConstant 10
+//!         FnCall "<", 2
+//!         JumpIfFalse end
+//!         Variable count
+//!         IntegerConstant 1
+//!         FnCall "+=", 2
+//!         PopStack
+//!         Jump loop
+//! end:    Variable count
+//! ```
+//!
+//! Variables are written by name, allocated in order of appearance, or by index like `%3`.
+//! Names are local to each function, which starts at an `Enter` instruction.
+//! Host functions and methods are written by their quoted names (or by index like `#3`),
+//! and jump targets by label. Literal `Switch` cases are written by value or by their hash like `#123`.
+
+use std::collections::HashMap;
+
+use crate::{ByteCode, DynamicConstant, DynamicValue, ErrorKind, Executer, SwitchTable, FLOAT, INT, SIZE, VEC};
+
+// Error message of a line, which `assemble` prefixes with the line number.
+type Result<T> = std::result::Result<T, String>;
+
+/// Parses text into byte codes for `run_byte_codes`, resolving function names with the `Executer`.
+///
+/// Variables of the main script are named by `variable_names`, which may hold initial variables,
+/// and gets the names of the variables found.
+pub fn assemble<B: DynamicValue+std::fmt::Debug>(
+    executer: &Executer<B>,
+    variable_names: &mut Vec<String>,
+    text: &str,
+) -> crate::Result<Vec<ByteCode>> {
+    let mut byte_codes = Vec::<ByteCode>::new();
+    let mut labels = HashMap::<String, SIZE>::new();
+    // Labels used by each instruction, in the order of `for_each_jump_target`, or the target of a `Call`.
+    let mut fixups = Vec::<(usize, usize, Vec<String>)>::new();
+    let mut local_names = Vec::<String>::new();
+    let mut in_function = false;
+    for (line_index, line) in text.lines().enumerate() {
+        let line_number = line_index + 1;
+        let mut cursor = Cursor { text: line, pos: 0 };
+        let mut parse_line = || -> Result<()> {
+            if let Some(address) = cursor.address() {
+                if address != byte_codes.len() {
+                    return Err(format!("Address {} does not match the instruction position {}!", address, byte_codes.len()));
+                }
+            }
+            while let Some(label) = cursor.label() {
+                if labels.insert(label.to_string(), byte_codes.len() as SIZE).is_some() {
+                    return Err(format!("Label \"{}\" is defined more than once!", label));
+                }
+            }
+            if cursor.at_end() {
+                return Ok(());
+            }
+            let mnemonic = cursor.identifier()?;
+            if mnemonic == "Enter" {
+                in_function = true;
+                local_names.clear();
+            }
+            let names = if in_function { &mut local_names } else { &mut *variable_names };
+            let mut targets = Vec::<String>::new();
+            let byte_code = parse_instruction(executer, &mut cursor, mnemonic, names, &mut targets)?;
+            cursor.expect_end()?;
+            if !targets.is_empty() {
+                fixups.push((byte_codes.len(), line_number, targets));
+            }
+            byte_codes.push(byte_code);
+            return Ok(());
+        };
+        if let Err(err) = parse_line() {
+            return Err(ErrorKind::Assemble {
+                line: line_number,
+                message: err,
+            }.into());
+        }
+    }
+    for (pos, line_number, targets) in fixups {
+        let mut resolved = Vec::<SIZE>::with_capacity(targets.len());
+        for label in targets.iter() {
+            match labels.get(label) {
+                Some(target) => resolved.push(*target),
+                None => {
+                    return Err(ErrorKind::Assemble {
+                        line: line_number,
+                        message: format!("Label \"{}\" is not defined!", label),
+                    }.into());
+                }
+            }
+        }
+        let mut resolved = resolved.into_iter();
+        match &mut byte_codes[pos] {
+            ByteCode::Call(target, _) => {
+                *target = resolved.next().unwrap_or_default();
+            }
+            byte_code => {
+                crate::for_each_jump_target(byte_code, |target| *target = resolved.next().unwrap_or_default());
+            }
+        }
+    }
+    return Ok(byte_codes);
+}
+
+fn parse_instruction<B: DynamicValue+std::fmt::Debug>(
+    executer: &Executer<B>,
+    cursor: &mut Cursor,
+    mnemonic: &str,
+    names: &mut Vec<String>,
+    targets: &mut Vec<String>,
+) -> Result<ByteCode> {
+    let byte_code = match mnemonic {
+        "Constant" => ByteCode::Constant(cursor.size()?),
+        "DynamicConstant" => ByteCode::DynamicConstant(cursor.constant()?),
+        "UnitConstant" => ByteCode::UnitConstant,
+        "BoolConstant" => match cursor.constant()? {
+            DynamicConstant::Bool(v) => ByteCode::BoolConstant(v),
+            _ => return Err("Expected a bool!".to_string()),
+        },
+        "IntegerConstant" => match cursor.constant()? {
+            DynamicConstant::Integer(v) => ByteCode::IntegerConstant(v),
+            _ => return Err("Expected an integer!".to_string()),
+        },
+        "FloatConstant" => match cursor.constant()? {
+            DynamicConstant::Float(v) => ByteCode::FloatConstant(v),
+            DynamicConstant::Integer(v) => ByteCode::FloatConstant(v as FLOAT),
+            _ => return Err("Expected a float!".to_string()),
+        },
+        "CharConstant" => match cursor.constant()? {
+            DynamicConstant::Char(v) => ByteCode::CharConstant(v),
+            _ => return Err("Expected a char!".to_string()),
+        },
+        "StringConstant" => ByteCode::StringConstant(cursor.string()?),
+        "InterpolatedString" => ByteCode::InterpolatedString(cursor.size()?),
+        "ConstructArray" => ByteCode::ConstructArray(cursor.size()?),
+        "ConstructMap" => {
+            cursor.expect('[')?;
+            let mut keys = VEC::new();
+            if !cursor.eat(']') {
+                loop {
+                    keys.push(cursor.string()?);
+                    if cursor.eat(']') {
+                        break;
+                    }
+                    cursor.expect(',')?;
+                }
+            }
+            ByteCode::ConstructMap(keys)
+        }
+        "Variable" => ByteCode::Variable(cursor.variable(names)?),
+        "VarInit" => ByteCode::VarInit(cursor.variable(names)?),
+        "VarStore" => ByteCode::VarStore(cursor.variable(names)?),
+        "FnCall" => {
+            let fn_id = cursor.function(&executer.fn_names, "Function")?;
+            cursor.expect(',')?;
+            ByteCode::FnCall(fn_id, cursor.size()?)
+        }
+        "MethodCall" => {
+            let method_id = cursor.function(&executer.method_names, "Method")?;
+            cursor.expect(',')?;
+            ByteCode::MethodCall(method_id, cursor.size()?)
+        }
+        "Call" => {
+            targets.push(cursor.identifier()?.to_string());
+            cursor.expect(',')?;
+            ByteCode::Call(0, cursor.size()?)
+        }
+        "Enter" => {
+            let name = cursor.string()?;
+            cursor.expect(',')?;
+            let param_count = cursor.size()?;
+            cursor.expect(',')?;
+            ByteCode::Enter(name, param_count, cursor.size()?)
+        }
+        "FnPtr" => ByteCode::FnPtr(cursor.string()?),
+        "Curry" => ByteCode::Curry(cursor.size()?),
+        "Closure" => ByteCode::Closure(cursor.size()?),
+        "CallPtr" => ByteCode::CallPtr(cursor.size()?),
+        "Jump" | "JumpIfTrue" | "JumpIfFalse" | "JumpIfNotNull" | "PushHandler" => {
+            targets.push(cursor.identifier()?.to_string());
+            match mnemonic {
+                "Jump" => ByteCode::Jump(0),
+                "JumpIfTrue" => ByteCode::JumpIfTrue(0),
+                "JumpIfFalse" => ByteCode::JumpIfFalse(0),
+                "JumpIfNotNull" => ByteCode::JumpIfNotNull(0),
+                _ => ByteCode::PushHandler(0),
+            }
+        }
+        "Index" => ByteCode::Index,
+        "GetProperty" => ByteCode::GetProperty(cursor.string()?),
+        "SetProperty" => ByteCode::SetProperty(cursor.string()?),
+        "Iter" => {
+            let loop_range_id = cursor.variable(names)?;
+            cursor.expect(',')?;
+            let loop_index_id = cursor.variable(names)?;
+            cursor.expect(',')?;
+            let loop_var_id = cursor.variable(names)?;
+            cursor.expect(',')?;
+            targets.push(cursor.identifier()?.to_string());
+            ByteCode::Iter(loop_range_id, loop_index_id, loop_var_id, 0)
+        }
+        "Switch" => {
+            let default = cursor.identifier()?.to_string();
+            let mut table = SwitchTable {
+                cases: VEC::new(),
+                ranges: VEC::new(),
+                default: 0,
+            };
+            let mut range_targets = Vec::<String>::new();
+            while cursor.eat(',') {
+                let value = if cursor.eat('#') { None } else { Some(cursor.constant()?) };
+                let hash = match value {
+                    None => cursor.hash()?,
+                    Some(DynamicConstant::Range(start, len)) => {
+                        cursor.expect('=')?;
+                        cursor.expect('>')?;
+                        table.ranges.push((start, len, 0));
+                        range_targets.push(cursor.identifier()?.to_string());
+                        continue;
+                    }
+                    Some(value) => crate::switch_case_hash(&value),
+                };
+                cursor.expect('=')?;
+                cursor.expect('>')?;
+                table.cases.push((hash, 0));
+                targets.push(cursor.identifier()?.to_string());
+            }
+            targets.extend(range_targets);
+            targets.push(default);
+            ByteCode::Switch(Box::new(table))
+        }
+        "PopHandler" => ByteCode::PopHandler,
+        "Throw" => ByteCode::Throw,
+        "Return" => ByteCode::Return,
+        "PopStack" => ByteCode::PopStack,
+        "FnCallVarInt" | "FnCallVarVar" | "JumpIfFalseVarInt" => {
+            let fn_id = cursor.function(&executer.fn_names, "Function")?;
+            cursor.expect(',')?;
+            let var_id = cursor.variable(names)?;
+            cursor.expect(',')?;
+            match mnemonic {
+                "FnCallVarVar" => ByteCode::FnCallVarVar(fn_id, var_id, cursor.variable(names)?),
+                _ => {
+                    let v = match cursor.constant()? {
+                        DynamicConstant::Integer(v) => v,
+                        _ => return Err("Expected an integer!".to_string()),
+                    };
+                    if mnemonic == "FnCallVarInt" {
+                        ByteCode::FnCallVarInt(fn_id, var_id, v)
+                    } else {
+                        cursor.expect(',')?;
+                        targets.push(cursor.identifier()?.to_string());
+                        ByteCode::JumpIfFalseVarInt(fn_id, var_id, v, 0)
+                    }
+                }
+            }
+        }
+        _ => return Err(format!("Unknown instruction \"{}\"!", mnemonic)),
+    };
+    return Ok(byte_code);
+}
+
+// Position in a line being parsed.
+struct Cursor<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn rest(&self) -> &'a str {
+        return &self.text[self.pos..];
+    }
+    fn skip_spaces(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+    fn peek(&mut self) -> Option<char> {
+        self.skip_spaces();
+        return self.rest().chars().next();
+    }
+    fn at_end(&mut self) -> bool {
+        return matches!(self.peek(), None | Some(';'));
+    }
+    fn expect_end(&mut self) -> Result<()> {
+        if !self.at_end() {
+            return Err(format!("Unexpected \"{}\"!", self.rest()));
+        }
+        return Ok(());
+    }
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += c.len_utf8();
+            return true;
+        }
+        return false;
+    }
+    fn expect(&mut self, c: char) -> Result<()> {
+        if !self.eat(c) {
+            return Err(format!("Expected '{}' at \"{}\"!", c, self.rest()));
+        }
+        return Ok(());
+    }
+    // Takes characters while `f` holds.
+    fn take_while(&mut self, f: impl Fn(char) -> bool) -> &'a str {
+        self.skip_spaces();
+        let rest = self.rest();
+        let len = rest.find(|c| !f(c)).unwrap_or(rest.len());
+        self.pos += len;
+        return &rest[..len];
+    }
+    fn identifier(&mut self) -> Result<&'a str> {
+        let ident = self.take_while(|c| c.is_alphanumeric() || c == '_' || c == '$');
+        if ident.is_empty() || ident.starts_with(|c: char| c.is_ascii_digit()) {
+            return Err(format!("Expected a name at \"{}\"!", self.rest()));
+        }
+        return Ok(ident);
+    }
+    // A leading address, as written by `disassemble`.
+    fn address(&mut self) -> Option<usize> {
+        let start = self.pos;
+        let digits = self.take_while(|c| c.is_ascii_digit());
+        match digits.parse() {
+            Ok(address) if self.rest().starts_with(char::is_whitespace) => {
+                return Some(address);
+            }
+            _ => {
+                self.pos = start;
+                return None;
+            }
+        }
+    }
+    fn label(&mut self) -> Option<&'a str> {
+        let start = self.pos;
+        match self.identifier() {
+            Ok(label) if self.rest().starts_with(':') => {
+                self.pos += 1;
+                return Some(label);
+            }
+            _ => {
+                self.pos = start;
+                return None;
+            }
+        }
+    }
+    fn size(&mut self) -> Result<SIZE> {
+        let digits = self.take_while(|c| c.is_ascii_digit());
+        match digits.parse() {
+            Ok(v) => {
+                return Ok(v);
+            }
+            Err(_) => {
+                return Err(format!("Expected a number at \"{}\"!", self.rest()));
+            }
+        }
+    }
+    fn hash(&mut self) -> Result<u64> {
+        let digits = self.take_while(|c| c.is_ascii_digit());
+        match digits.parse() {
+            Ok(v) => {
+                return Ok(v);
+            }
+            Err(_) => {
+                return Err(format!("Expected a hash at \"{}\"!", self.rest()));
+            }
+        }
+    }
+    fn index(&mut self, prefix: char) -> Result<Option<SIZE>> {
+        if self.eat(prefix) {
+            return Ok(Some(self.size()?));
+        }
+        return Ok(None);
+    }
+    fn variable(&mut self, names: &mut Vec<String>) -> Result<SIZE> {
+        if let Some(var_id) = self.index('%')? {
+            return Ok(var_id);
+        }
+        let name = self.identifier()?;
+        match names.iter().position(|n| n == name) {
+            Some(var_id) => {
+                return Ok(var_id as SIZE);
+            }
+            None => {
+                names.push(name.to_string());
+                return Ok((names.len() - 1) as SIZE);
+            }
+        }
+    }
+    fn function(&mut self, names: &[String], type_str: &str) -> Result<SIZE> {
+        if let Some(index) = self.index('#')? {
+            return Ok(index);
+        }
+        let name = self.string()?;
+        match names.iter().position(|n| *n == name) {
+            Some(index) => {
+                return Ok(index as SIZE);
+            }
+            None => {
+                return Err(format!("{} \"{}\" does not exist!", type_str, name));
+            }
+        }
+    }
+    // Reads a quoted literal with Rust escapes, up to the closing `quote`.
+    fn quoted(&mut self, quote: char) -> Result<String> {
+        self.expect(quote)?;
+        let mut value = String::new();
+        let mut chars = self.rest().char_indices();
+        while let Some((i, c)) = chars.next() {
+            let c = match c {
+                _ if c == quote => {
+                    self.pos += i + 1;
+                    return Ok(value);
+                }
+                '\\' => match chars.next() {
+                    Some((_, 'n')) => '\n',
+                    Some((_, 'r')) => '\r',
+                    Some((_, 't')) => '\t',
+                    Some((_, '0')) => '\0',
+                    Some((_, 'u')) => {
+                        let hex = chars.by_ref().skip(1).take_while(|(_, c)| *c != '}').map(|(_, c)| c).collect::<String>();
+                        match u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32) {
+                            Some(c) => c,
+                            None => return Err(format!("Invalid escape \"\\u{{{}}}\"!", hex)),
+                        }
+                    }
+                    Some((_, c)) => c,
+                    None => break,
+                },
+                c => c,
+            };
+            value.push(c);
+        }
+        return Err(format!("Missing closing {}!", quote));
+    }
+    fn string(&mut self) -> Result<String> {
+        return self.quoted('"');
+    }
+    // Reads a constant written like `format_constant` does.
+    fn constant(&mut self) -> Result<DynamicConstant> {
+        match self.peek() {
+            Some('"') => {
+                return Ok(DynamicConstant::String(self.string()?));
+            }
+            Some('\'') => {
+                let value = self.quoted('\'')?;
+                let mut chars = value.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => {
+                        return Ok(DynamicConstant::Char(c));
+                    }
+                    _ => {
+                        return Err(format!("Invalid char '{}'!", value));
+                    }
+                }
+            }
+            Some('(') => {
+                self.pos += 1;
+                self.expect(')')?;
+                return Ok(DynamicConstant::Unit);
+            }
+            Some('[') => {
+                self.pos += 1;
+                let mut items = VEC::new();
+                if !self.eat(']') {
+                    loop {
+                        items.push(self.constant()?);
+                        if self.eat(']') {
+                            break;
+                        }
+                        self.expect(',')?;
+                    }
+                }
+                return Ok(DynamicConstant::Array(items));
+            }
+            Some('#') => {
+                self.pos += 1;
+                self.expect('{')?;
+                let mut map = std::collections::BTreeMap::new();
+                if !self.eat('}') {
+                    loop {
+                        let key = if self.peek() == Some('"') {
+                            self.string()?
+                        } else {
+                            self.identifier()?.to_string()
+                        };
+                        self.expect(':')?;
+                        map.insert(key, self.constant()?);
+                        if self.eat('}') {
+                            break;
+                        }
+                        self.expect(',')?;
+                    }
+                }
+                return Ok(DynamicConstant::Map(map));
+            }
+            _ => {}
+        }
+        let token = self.take_while(|c| c.is_alphanumeric() || c == '_' || c == '-' || c == '+' || c == '.');
+        match token {
+            "true" => {
+                return Ok(DynamicConstant::Bool(true));
+            }
+            "false" => {
+                return Ok(DynamicConstant::Bool(false));
+            }
+            _ => {}
+        }
+        if let Some((start, end)) = token.split_once("..") {
+            if let (Ok(start), Ok(end)) = (start.parse::<INT>(), end.parse::<i128>()) {
+                if let Ok(len) = INT::try_from(end - start as i128) {
+                    return Ok(DynamicConstant::Range(start, len));
+                }
+            }
+        } else if let Ok(v) = token.parse::<INT>() {
+            return Ok(DynamicConstant::Integer(v));
+        } else if let Ok(v) = token.parse::<FLOAT>() {
+            return Ok(DynamicConstant::Float(v));
+        }
+        return Err(format!("Invalid constant \"{}\"!", token));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_value::{new_executer, Value};
+    use crate::run_byte_codes;
+
+    #[cfg(not(feature = "no_function"))]
+    #[test]
+    fn disassembly_round_trip() {
+        let executer = new_executer();
+        let script = "fn f(a) { if a > 1 { a - 1 } else { 0 } } let x = [1, 2]; let n = 0; while n < 5 { n += f(n); n += 1; } x[1] + n";
+        let mut variable_names = Vec::new();
+        let byte_codes = crate::script_to_byte_codes(&executer, &mut variable_names, script).unwrap();
+        let text = crate::disassemble(&executer, &byte_codes, &variable_names);
+        let assembled = assemble(&executer, &mut Vec::new(), &text).unwrap();
+        assert_eq!(format!("{:?}", assembled), format!("{:?}", byte_codes));
+        assert_eq!(run_byte_codes(&executer, &assembled, &[]).unwrap(), run_byte_codes(&executer, &byte_codes, &[]).unwrap());
+    }
+
+    #[test]
+    fn assemble_labels_and_names() {
+        let executer = new_executer();
+        let text = "
+                IntegerConstant 0
+                VarInit count
+                PopStack
+        loop:   Variable count      ; Counts to 10.
+                IntegerConstant 10
+                FnCall \"<\", 2
+                JumpIfFalse end
+                Variable count
+                IntegerConstant 1
+                FnCall \"+=\", 2
+                PopStack
+                Jump loop
+        end:    Variable count
+        ";
+        let mut variable_names = Vec::new();
+        let byte_codes = assemble(&executer, &mut variable_names, text).unwrap();
+        assert_eq!(variable_names, vec!["count".to_string()]);
+        assert_eq!(run_byte_codes(&executer, &byte_codes, &[]).unwrap(), Value::Integer(10));
+    }
+
+    #[test]
+    fn switch_round_trip() {
+        let executer = new_executer();
+        let script = "let s = 0; for i in 0..5 { s += switch i { 1 => 10, 2..4 => 100, _ => 1000 }; } s";
+        let mut variable_names = Vec::new();
+        let byte_codes = crate::script_to_byte_codes(&executer, &mut variable_names, script).unwrap();
+        let text = crate::disassemble(&executer, &byte_codes, &variable_names);
+        let assembled = assemble(&executer, &mut Vec::new(), &text).unwrap();
+        assert_eq!(format!("{:?}", assembled), format!("{:?}", byte_codes));
+        assert_eq!(run_byte_codes(&executer, &assembled, &[]).unwrap(), Value::Integer(2210));
+        // Cases written by value are hashed like Rhai does.
+        let by_value = assemble(&executer, &mut Vec::new(), "Switch end, 1 => end\nend: UnitConstant").unwrap();
+        let by_hash = format!("Switch end, #{} => end\nend: UnitConstant", crate::switch_case_hash(&DynamicConstant::Integer(1)));
+        assert_eq!(format!("{:?}", by_value), format!("{:?}", assemble(&executer, &mut Vec::new(), &by_hash).unwrap()));
+    }
+
+    #[test]
+    fn assemble_errors() {
+        let executer = new_executer();
+        let error = |text: &str| assemble(&executer, &mut Vec::new(), text).unwrap_err().to_string();
+        assert!(error("UnitConstant\nNoSuchInstruction").contains("2"));
+        assert!(error("Jump nowhere").contains("nowhere"));
+        assert!(error("FnCall \"no such function\", 2").contains("no such function"));
+    }
+}
@@ -0,0 +1,700 @@
+//! Compact binary format for compiled programs.
+//!
+//! The data starts with a header: the magic bytes "RHBC", the format version and the size in bytes of `SIZE`.
+//! Values are encoded in declaration order without field names, integers as LEB128 varints
+//! (signed ones zigzag encoded), and enum variants by their index.
+//! So any change to the declaration of `CompiledProgram` or its content needs a new `FORMAT_VERSION`.
+
+use serde::de::{self, DeserializeSeed, IntoDeserializer, Visitor};
+use serde::ser::{self, Serialize};
+
+use crate::{FORMAT_VERSION, SIZE};
+
+const MAGIC: &[u8; 4] = b"RHBC";
+// Deepest nesting of sequences and maps, e.g. of `DynamicConstant::Array`, so that corrupted data cannot overflow the stack.
+const MAX_DEPTH: usize = 128;
+
+#[derive(Debug)]
+struct Error(String);
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return f.write_str(&self.0);
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<Error> for crate::Error {
+    fn from(err: Error) -> Self {
+        return crate::ErrorKind::Format(err.0).into();
+    }
+}
+
+impl ser::Error for Error {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        return Error(msg.to_string());
+    }
+}
+
+impl de::Error for Error {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        return Error(msg.to_string());
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Encodes a value to the binary format, with the header.
+pub(crate) fn encode<T: Serialize>(value: &T) -> crate::Result<Vec<u8>> {
+    let mut writer = Writer { out: MAGIC.to_vec() };
+    writer.write_varint(FORMAT_VERSION as u64);
+    writer.out.push(std::mem::size_of::<SIZE>() as u8);
+    value.serialize(&mut writer)?;
+    return Ok(writer.out);
+}
+
+/// Decodes a value from the binary format, checking the header.
+pub(crate) fn decode<T: serde::de::DeserializeOwned>(bytes: &[u8]) -> crate::Result<T> {
+    if !bytes.starts_with(MAGIC) {
+        return Err(crate::ErrorKind::Format("Not a bytecode binary!".to_string()).into());
+    }
+    let mut reader = Reader {
+        input: &bytes[MAGIC.len()..],
+        depth: 0,
+    };
+    let version = reader.read_varint()?;
+    if version != FORMAT_VERSION as u64 {
+        return Err(crate::ErrorKind::Format(format!("Unsupported bytecode format version {}, expecting {}!", version, FORMAT_VERSION)).into());
+    }
+    let size_bytes = reader.read_byte()? as usize;
+    if size_bytes != std::mem::size_of::<SIZE>() {
+        return Err(crate::ErrorKind::Format(format!(
+            "Bytecode uses {}-bit sizes, but {}-bit sizes are expected!",
+            size_bytes * 8,
+            std::mem::size_of::<SIZE>() * 8
+        )).into());
+    }
+    let value = T::deserialize(&mut reader)?;
+    if !reader.input.is_empty() {
+        return Err(crate::ErrorKind::Format("Unexpected data after the end of bytecode!".to_string()).into());
+    }
+    return Ok(value);
+}
+
+struct Writer {
+    out: Vec<u8>,
+}
+
+impl Writer {
+    fn write_varint(&mut self, mut v: u64) {
+        while v >= 0x80 {
+            self.out.push((v as u8) | 0x80);
+            v >>= 7;
+        }
+        self.out.push(v as u8);
+    }
+    fn write_signed(&mut self, v: i64) {
+        self.write_varint(((v << 1) ^ (v >> 63)) as u64);
+    }
+    fn write_len(&mut self, len: Option<usize>) -> Result<()> {
+        match len {
+            Some(len) => {
+                self.write_varint(len as u64);
+                return Ok(());
+            }
+            None => {
+                return Err(Error("Length of sequence or map is required!".to_string()));
+            }
+        }
+    }
+}
+
+impl ser::Serializer for &mut Writer {
+    type Ok = ();
+    type Error = Error;
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = Self;
+    type SerializeMap = Self;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Self;
+
+    fn serialize_bool(self, v: bool) -> Result<()> {
+        self.out.push(v as u8);
+        return Ok(());
+    }
+    fn serialize_i8(self, v: i8) -> Result<()> {
+        self.write_signed(v as i64);
+        return Ok(());
+    }
+    fn serialize_i16(self, v: i16) -> Result<()> {
+        self.write_signed(v as i64);
+        return Ok(());
+    }
+    fn serialize_i32(self, v: i32) -> Result<()> {
+        self.write_signed(v as i64);
+        return Ok(());
+    }
+    fn serialize_i64(self, v: i64) -> Result<()> {
+        self.write_signed(v);
+        return Ok(());
+    }
+    fn serialize_u8(self, v: u8) -> Result<()> {
+        self.out.push(v);
+        return Ok(());
+    }
+    fn serialize_u16(self, v: u16) -> Result<()> {
+        self.write_varint(v as u64);
+        return Ok(());
+    }
+    fn serialize_u32(self, v: u32) -> Result<()> {
+        self.write_varint(v as u64);
+        return Ok(());
+    }
+    fn serialize_u64(self, v: u64) -> Result<()> {
+        self.write_varint(v);
+        return Ok(());
+    }
+    fn serialize_f32(self, v: f32) -> Result<()> {
+        self.out.extend_from_slice(&v.to_le_bytes());
+        return Ok(());
+    }
+    fn serialize_f64(self, v: f64) -> Result<()> {
+        self.out.extend_from_slice(&v.to_le_bytes());
+        return Ok(());
+    }
+    fn serialize_char(self, v: char) -> Result<()> {
+        self.write_varint(v as u64);
+        return Ok(());
+    }
+    fn serialize_str(self, v: &str) -> Result<()> {
+        return self.serialize_bytes(v.as_bytes());
+    }
+    fn serialize_bytes(self, v: &[u8]) -> Result<()> {
+        self.write_varint(v.len() as u64);
+        self.out.extend_from_slice(v);
+        return Ok(());
+    }
+    fn serialize_none(self) -> Result<()> {
+        self.out.push(0);
+        return Ok(());
+    }
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<()> {
+        self.out.push(1);
+        return value.serialize(self);
+    }
+    fn serialize_unit(self) -> Result<()> {
+        return Ok(());
+    }
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<()> {
+        return Ok(());
+    }
+    fn serialize_unit_variant(self, _name: &'static str, variant_index: u32, _variant: &'static str) -> Result<()> {
+        self.write_varint(variant_index as u64);
+        return Ok(());
+    }
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(self, _name: &'static str, value: &T) -> Result<()> {
+        return value.serialize(self);
+    }
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        self.write_varint(variant_index as u64);
+        return value.serialize(self);
+    }
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self> {
+        self.write_len(len)?;
+        return Ok(self);
+    }
+    fn serialize_tuple(self, _len: usize) -> Result<Self> {
+        return Ok(self);
+    }
+    fn serialize_tuple_struct(self, _name: &'static str, _len: usize) -> Result<Self> {
+        return Ok(self);
+    }
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self> {
+        self.write_varint(variant_index as u64);
+        return Ok(self);
+    }
+    fn serialize_map(self, len: Option<usize>) -> Result<Self> {
+        self.write_len(len)?;
+        return Ok(self);
+    }
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self> {
+        return Ok(self);
+    }
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self> {
+        self.write_varint(variant_index as u64);
+        return Ok(self);
+    }
+    fn is_human_readable(&self) -> bool {
+        return false;
+    }
+}
+
+impl ser::SerializeSeq for &mut Writer {
+    type Ok = ();
+    type Error = Error;
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        return value.serialize(&mut **self);
+    }
+    fn end(self) -> Result<()> {
+        return Ok(());
+    }
+}
+
+impl ser::SerializeTuple for &mut Writer {
+    type Ok = ();
+    type Error = Error;
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        return value.serialize(&mut **self);
+    }
+    fn end(self) -> Result<()> {
+        return Ok(());
+    }
+}
+
+impl ser::SerializeTupleStruct for &mut Writer {
+    type Ok = ();
+    type Error = Error;
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        return value.serialize(&mut **self);
+    }
+    fn end(self) -> Result<()> {
+        return Ok(());
+    }
+}
+
+impl ser::SerializeTupleVariant for &mut Writer {
+    type Ok = ();
+    type Error = Error;
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        return value.serialize(&mut **self);
+    }
+    fn end(self) -> Result<()> {
+        return Ok(());
+    }
+}
+
+impl ser::SerializeMap for &mut Writer {
+    type Ok = ();
+    type Error = Error;
+    fn serialize_key<T: ?Sized + Serialize>(&mut self, key: &T) -> Result<()> {
+        return key.serialize(&mut **self);
+    }
+    fn serialize_value<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        return value.serialize(&mut **self);
+    }
+    fn end(self) -> Result<()> {
+        return Ok(());
+    }
+}
+
+impl ser::SerializeStruct for &mut Writer {
+    type Ok = ();
+    type Error = Error;
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, _key: &'static str, value: &T) -> Result<()> {
+        return value.serialize(&mut **self);
+    }
+    fn end(self) -> Result<()> {
+        return Ok(());
+    }
+}
+
+impl ser::SerializeStructVariant for &mut Writer {
+    type Ok = ();
+    type Error = Error;
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, _key: &'static str, value: &T) -> Result<()> {
+        return value.serialize(&mut **self);
+    }
+    fn end(self) -> Result<()> {
+        return Ok(());
+    }
+}
+
+struct Reader<'de> {
+    input: &'de [u8],
+    // Sequences and maps being read.
+    depth: usize,
+}
+
+impl<'de> Reader<'de> {
+    fn read_byte(&mut self) -> Result<u8> {
+        match self.input.split_first() {
+            Some((b, rest)) => {
+                self.input = rest;
+                return Ok(*b);
+            }
+            None => {
+                return Err(Error("Unexpected end of bytecode!".to_string()));
+            }
+        }
+    }
+    fn read_bytes(&mut self, len: usize) -> Result<&'de [u8]> {
+        if len > self.input.len() {
+            return Err(Error("Unexpected end of bytecode!".to_string()));
+        }
+        let (bytes, rest) = self.input.split_at(len);
+        self.input = rest;
+        return Ok(bytes);
+    }
+    fn read_varint(&mut self) -> Result<u64> {
+        let mut v = 0u64;
+        let mut shift = 0;
+        loop {
+            let b = self.read_byte()?;
+            if shift == 63 && b > 1 {
+                return Err(Error("Integer too large!".to_string()));
+            }
+            v |= ((b & 0x7f) as u64) << shift;
+            if b & 0x80 == 0 {
+                return Ok(v);
+            }
+            shift += 7;
+            if shift > 63 {
+                return Err(Error("Integer too large!".to_string()));
+            }
+        }
+    }
+    fn read_unsigned<T: TryFrom<u64>>(&mut self) -> Result<T> {
+        let v = self.read_varint()?;
+        return T::try_from(v).map_err(|_| Error(format!("Integer {} out of range!", v)));
+    }
+    fn read_signed<T: TryFrom<i64>>(&mut self) -> Result<T> {
+        let v = self.read_varint()?;
+        let v = ((v >> 1) as i64) ^ -((v & 1) as i64);
+        return T::try_from(v).map_err(|_| Error(format!("Integer {} out of range!", v)));
+    }
+    fn read_len(&mut self) -> Result<usize> {
+        let len = self.read_unsigned::<usize>()?;
+        // Every element takes at least one byte, so a longer length must be corrupted.
+        if len > self.input.len() {
+            return Err(Error("Unexpected end of bytecode!".to_string()));
+        }
+        return Ok(len);
+    }
+    fn nested<T>(&mut self, read: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        if self.depth >= MAX_DEPTH {
+            return Err(Error("Bytecode is nested too deeply!".to_string()));
+        }
+        self.depth += 1;
+        let res = read(self);
+        self.depth -= 1;
+        return res;
+    }
+}
+
+impl<'de> de::Deserializer<'de> for &mut Reader<'de> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value> {
+        return Err(Error("The binary format is not self-describing!".to_string()));
+    }
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.read_byte()? {
+            0 => {
+                return visitor.visit_bool(false);
+            }
+            1 => {
+                return visitor.visit_bool(true);
+            }
+            b => {
+                return Err(Error(format!("Invalid bool {}!", b)));
+            }
+        }
+    }
+    fn deserialize_i8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        return visitor.visit_i8(self.read_signed()?);
+    }
+    fn deserialize_i16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        return visitor.visit_i16(self.read_signed()?);
+    }
+    fn deserialize_i32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        return visitor.visit_i32(self.read_signed()?);
+    }
+    fn deserialize_i64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        return visitor.visit_i64(self.read_signed()?);
+    }
+    fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        return visitor.visit_u8(self.read_byte()?);
+    }
+    fn deserialize_u16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        return visitor.visit_u16(self.read_unsigned()?);
+    }
+    fn deserialize_u32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        return visitor.visit_u32(self.read_unsigned()?);
+    }
+    fn deserialize_u64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        return visitor.visit_u64(self.read_varint()?);
+    }
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        let bytes = self.read_bytes(4)?;
+        return visitor.visit_f32(f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]));
+    }
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        let mut buf = [0u8; 8];
+        buf.copy_from_slice(self.read_bytes(8)?);
+        return visitor.visit_f64(f64::from_le_bytes(buf));
+    }
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        let v = self.read_unsigned::<u32>()?;
+        match char::from_u32(v) {
+            Some(c) => {
+                return visitor.visit_char(c);
+            }
+            None => {
+                return Err(Error(format!("Invalid char {}!", v)));
+            }
+        }
+    }
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        let len = self.read_len()?;
+        match std::str::from_utf8(self.read_bytes(len)?) {
+            Ok(s) => {
+                return visitor.visit_borrowed_str(s);
+            }
+            Err(err) => {
+                return Err(Error(err.to_string()));
+            }
+        }
+    }
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        return self.deserialize_str(visitor);
+    }
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        let len = self.read_len()?;
+        return visitor.visit_borrowed_bytes(self.read_bytes(len)?);
+    }
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        return self.deserialize_bytes(visitor);
+    }
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.read_byte()? {
+            0 => {
+                return visitor.visit_none();
+            }
+            1 => {
+                return visitor.visit_some(self);
+            }
+            b => {
+                return Err(Error(format!("Invalid option tag {}!", b)));
+            }
+        }
+    }
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        return visitor.visit_unit();
+    }
+    fn deserialize_unit_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value> {
+        return visitor.visit_unit();
+    }
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value> {
+        return visitor.visit_newtype_struct(self);
+    }
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        let len = self.read_len()?;
+        return self.nested(|reader| visitor.visit_seq(Elements { reader, remaining: len }));
+    }
+    fn deserialize_tuple<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value> {
+        return visitor.visit_seq(Elements { reader: self, remaining: len });
+    }
+    fn deserialize_tuple_struct<V: Visitor<'de>>(self, _name: &'static str, len: usize, visitor: V) -> Result<V::Value> {
+        return visitor.visit_seq(Elements { reader: self, remaining: len });
+    }
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        let len = self.read_len()?;
+        return self.nested(|reader| visitor.visit_map(Elements { reader, remaining: len }));
+    }
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        return visitor.visit_seq(Elements { reader: self, remaining: fields.len() });
+    }
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        return visitor.visit_enum(self);
+    }
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        return visitor.visit_u32(self.read_unsigned()?);
+    }
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value> {
+        return Err(Error("The binary format is not self-describing!".to_string()));
+    }
+    fn is_human_readable(&self) -> bool {
+        return false;
+    }
+}
+
+struct Elements<'a, 'de> {
+    reader: &'a mut Reader<'de>,
+    remaining: usize,
+}
+
+impl<'de> de::SeqAccess<'de> for Elements<'_, 'de> {
+    type Error = Error;
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+        return seed.deserialize(&mut *self.reader).map(Some);
+    }
+    fn size_hint(&self) -> Option<usize> {
+        return Some(self.remaining);
+    }
+}
+
+impl<'de> de::MapAccess<'de> for Elements<'_, 'de> {
+    type Error = Error;
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+        return seed.deserialize(&mut *self.reader).map(Some);
+    }
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value> {
+        return seed.deserialize(&mut *self.reader);
+    }
+    fn size_hint(&self) -> Option<usize> {
+        return Some(self.remaining);
+    }
+}
+
+impl<'de> de::EnumAccess<'de> for &mut Reader<'de> {
+    type Error = Error;
+    type Variant = Self;
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self)> {
+        let index = self.read_unsigned::<u32>()?;
+        let value = seed.deserialize(IntoDeserializer::<Error>::into_deserializer(index))?;
+        return Ok((value, self));
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for &mut Reader<'de> {
+    type Error = Error;
+    fn unit_variant(self) -> Result<()> {
+        return Ok(());
+    }
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value> {
+        return seed.deserialize(self);
+    }
+    fn tuple_variant<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value> {
+        return de::Deserializer::deserialize_tuple(self, len, visitor);
+    }
+    fn struct_variant<V: Visitor<'de>>(self, fields: &'static [&'static str], visitor: V) -> Result<V::Value> {
+        return visitor.visit_seq(Elements { reader: self, remaining: fields.len() });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::{ByteCode, CompiledProgram, DynamicConstant, ErrorKind, Import, FORMAT_VERSION};
+
+    fn program(constants: Vec<DynamicConstant>) -> CompiledProgram {
+        return CompiledProgram {
+            version: FORMAT_VERSION,
+            byte_codes: vec![
+                ByteCode::IntegerConstant(-3),
+                ByteCode::FloatConstant(0.5),
+                ByteCode::StringConstant("é\n".to_string()),
+                ByteCode::FnCall(0, 2),
+                ByteCode::JumpIfFalse(6),
+                ByteCode::Constant(0),
+                ByteCode::Return,
+            ],
+            functions: vec![Import { name: "+".to_string(), arg_range: (1, 2) }],
+            methods: vec![],
+            variable_names: vec!["x".to_string()],
+            initial_variable_count: 1,
+            variable_count: 1,
+            constants,
+            positions: vec![],
+        };
+    }
+
+    fn is_format_error(bytes: &[u8]) -> bool {
+        return matches!(CompiledProgram::from_bytes(bytes).map_err(|err| err.into_kind()), Err(ErrorKind::Format(_)));
+    }
+
+    #[test]
+    fn round_trip() {
+        let map = BTreeMap::from([("a".to_string(), DynamicConstant::Char('z')), ("b".to_string(), DynamicConstant::Unit)]);
+        let constants = vec![
+            DynamicConstant::Array([DynamicConstant::Bool(true), DynamicConstant::Map(map)].into_iter().collect()),
+            DynamicConstant::Range(-1, 4),
+        ];
+        let program = program(constants);
+        let bytes = program.to_bytes().unwrap();
+        let decoded = CompiledProgram::from_bytes(&bytes).unwrap();
+        assert_eq!(format!("{:?}", decoded), format!("{:?}", program));
+    }
+
+    #[test]
+    fn truncated_input_is_rejected() {
+        let bytes = program(vec![DynamicConstant::String("abc".to_string())]).to_bytes().unwrap();
+        for len in 0..bytes.len() {
+            assert!(is_format_error(&bytes[..len]), "prefix of {} bytes decoded", len);
+        }
+    }
+
+    #[test]
+    fn corrupt_input_is_rejected() {
+        let bytes = program(vec![]).to_bytes().unwrap();
+        let mut bad_magic = bytes.clone();
+        bad_magic[0] = b'X';
+        assert!(is_format_error(&bad_magic));
+        let mut bad_version = bytes.clone();
+        bad_version[4] = (FORMAT_VERSION + 1) as u8;
+        assert!(is_format_error(&bad_version));
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(is_format_error(&trailing));
+        // The first byte code, after the header, the version and the byte code count, gets an unknown variant.
+        let mut bad_variant = bytes.clone();
+        bad_variant[8] = 0x7f;
+        match CompiledProgram::from_bytes(&bad_variant).map_err(|err| err.into_kind()) {
+            Err(ErrorKind::Format(message)) => assert!(message.contains("127"), "{}", message),
+            res => panic!("Unexpected result {:?}", res.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn deep_nesting_is_rejected() {
+        let mut constant = DynamicConstant::Unit;
+        for _ in 0..1000 {
+            constant = DynamicConstant::Array(std::iter::once(constant).collect());
+        }
+        let bytes = program(vec![constant]).to_bytes().unwrap();
+        match CompiledProgram::from_bytes(&bytes).map_err(|err| err.into_kind()) {
+            Err(ErrorKind::Format(message)) => assert_eq!(message, "Bytecode is nested too deeply!"),
+            res => panic!("Unexpected result {:?}", res.map(|_| ())),
+        }
+    }
+}
@@ -0,0 +1,219 @@
+//! Human-readable listing of byte codes.
+//!
+//! Each line shows the address, the instruction and its operands, then a comment with the names they refer to,
+//! and the script line and column where the source position changes, if kept.
+//! Jump targets get labels like `L12:`, variables are written `%index`, host functions by their quoted names, and literal `Switch` cases by their hash.
+
+use std::fmt::Write;
+
+use crate::{ByteCode, CompiledProgram, DynamicConstant, DynamicValue, Executer, SourcePosition, SIZE};
+
+// Names used to annotate the listing.
+struct Symbols<'a> {
+    functions: Vec<&'a str>,
+    methods: Vec<&'a str>,
+    variable_names: &'a [String],
+    constants: &'a [DynamicConstant],
+    positions: &'a [(SIZE,SourcePosition)],
+}
+
+/// Lists byte codes as compiled by `ast_to_byte_codes`, with function names from the `Executer`
+/// and the variable names filled in by the compiler.
+pub fn disassemble<B: DynamicValue+std::fmt::Debug>(
+    executer: &Executer<B>,
+    byte_codes: &[ByteCode],
+    variable_names: &[String],
+) -> String {
+    let symbols = Symbols {
+        functions: executer.fn_names.iter().map(|name| name.as_str()).collect(),
+        methods: executer.method_names.iter().map(|name| name.as_str()).collect(),
+        variable_names,
+        constants: &[],
+        positions: &[],
+    };
+    return list(byte_codes, &symbols);
+}
+
+impl CompiledProgram {
+    /// Lists the program, which needs no `Executer` since it keeps the names of its imports.
+    pub fn disassemble(&self) -> String {
+        let symbols = Symbols {
+            functions: self.functions.iter().map(|import| import.name.as_str()).collect(),
+            methods: self.methods.iter().map(|import| import.name.as_str()).collect(),
+            variable_names: &self.variable_names,
+            constants: &self.constants,
+            positions: &self.positions,
+        };
+        return list(&self.byte_codes, &symbols);
+    }
+}
+
+fn list(byte_codes: &[ByteCode], symbols: &Symbols) -> String {
+    let mut labels = vec![false; byte_codes.len() + 1];
+    for byte_code in byte_codes {
+        let mut byte_code = byte_code.clone();
+        if let ByteCode::Call(target, _) = byte_code {
+            mark_label(&mut labels, target);
+        }
+        crate::for_each_jump_target(&mut byte_code, |target| mark_label(&mut labels, *target));
+    }
+    let mut out = String::new();
+    // Function parameters and locals have no names.
+    let mut in_function = false;
+    let mut positions = symbols.positions.iter().peekable();
+    for (pos, byte_code) in byte_codes.iter().enumerate() {
+        if let ByteCode::Enter(..) = byte_code {
+            in_function = true;
+            out.push('\n');
+        }
+        if labels[pos] {
+            let _ = writeln!(out, "L{}:", pos);
+        }
+        let mut comments = Vec::<String>::new();
+        let instruction = format_instruction(byte_code, byte_codes, symbols, in_function, &mut comments);
+        if let Some((_, position)) = positions.next_if(|(start, _)| *start as usize == pos) {
+            comments.push(format!("line {}:{}", position.line, position.column));
+        }
+        let line = format!("{:>6}  {}", pos, instruction);
+        if comments.is_empty() {
+            let _ = writeln!(out, "{}", line);
+        } else {
+            let _ = writeln!(out, "{:<40} ; {}", line, comments.join(", "));
+        }
+    }
+    if labels[byte_codes.len()] {
+        let _ = writeln!(out, "L{}:", byte_codes.len());
+    }
+    return out;
+}
+
+fn mark_label(labels: &mut [bool], target: SIZE) {
+    if let Some(label) = labels.get_mut(target as usize) {
+        *label = true;
+    }
+}
+
+fn format_instruction(
+    byte_code: &ByteCode,
+    byte_codes: &[ByteCode],
+    symbols: &Symbols,
+    in_function: bool,
+    comments: &mut Vec<String>,
+) -> String {
+    let mut variable = |var_id: &SIZE| {
+        if !in_function {
+            if let Some(name) = symbols.variable_names.get(*var_id as usize) {
+                comments.push(name.to_owned());
+            }
+        }
+        return format!("%{}", var_id);
+    };
+    return match byte_code {
+        ByteCode::Constant(index) => {
+            if let Some(constant) = symbols.constants.get(*index as usize) {
+                comments.push(format_constant(constant));
+            }
+            format!("Constant {}", index)
+        }
+        ByteCode::DynamicConstant(constant) => format!("DynamicConstant {}", format_constant(constant)),
+        ByteCode::UnitConstant => "UnitConstant".to_string(),
+        ByteCode::BoolConstant(v) => format!("BoolConstant {}", v),
+        ByteCode::IntegerConstant(v) => format!("IntegerConstant {}", v),
+        ByteCode::FloatConstant(v) => format!("FloatConstant {:?}", v),
+        ByteCode::CharConstant(v) => format!("CharConstant {:?}", v),
+        ByteCode::StringConstant(v) => format!("StringConstant {:?}", v),
+        ByteCode::InterpolatedString(count) => format!("InterpolatedString {}", count),
+        ByteCode::ConstructArray(count) => format!("ConstructArray {}", count),
+        ByteCode::ConstructMap(keys) => {
+            let keys = keys.iter().map(|key| format!("{:?}", key)).collect::<Vec<_>>();
+            format!("ConstructMap [{}]", keys.join(", "))
+        }
+        ByteCode::Variable(var_id) => format!("Variable {}", variable(var_id)),
+        ByteCode::VarInit(var_id) => format!("VarInit {}", variable(var_id)),
+        ByteCode::VarStore(var_id) => format!("VarStore {}", variable(var_id)),
+        ByteCode::FnCall(fn_id, arg_count) => {
+            format!("FnCall {}, {}", format_name(&symbols.functions, *fn_id), arg_count)
+        }
+        ByteCode::MethodCall(method_id, arg_count) => {
+            format!("MethodCall {}, {}", format_name(&symbols.methods, *method_id), arg_count)
+        }
+        ByteCode::Call(target, arg_count) => {
+            if let Some(ByteCode::Enter(name, _, _)) = byte_codes.get(*target as usize) {
+                comments.push(name.to_owned());
+            }
+            format!("Call L{}, {}", target, arg_count)
+        }
+        ByteCode::Enter(name, param_count, var_count) => format!("Enter {:?}, {}, {}", name, param_count, var_count),
+        ByteCode::FnPtr(name) => format!("FnPtr {:?}", name),
+        ByteCode::Curry(count) => format!("Curry {}", count),
+        ByteCode::Closure(count) => format!("Closure {}", count),
+        ByteCode::CallPtr(count) => format!("CallPtr {}", count),
+        ByteCode::Jump(target) => format!("Jump L{}", target),
+        ByteCode::JumpIfTrue(target) => format!("JumpIfTrue L{}", target),
+        ByteCode::JumpIfFalse(target) => format!("JumpIfFalse L{}", target),
+        ByteCode::JumpIfNotNull(target) => format!("JumpIfNotNull L{}", target),
+        ByteCode::Index => "Index".to_string(),
+        ByteCode::GetProperty(name) => format!("GetProperty {:?}", name),
+        ByteCode::SetProperty(name) => format!("SetProperty {:?}", name),
+        ByteCode::Iter(loop_range_id, loop_index_id, loop_var_id, target) => format!(
+            "Iter {}, {}, {}, L{}",
+            variable(loop_range_id),
+            variable(loop_index_id),
+            variable(loop_var_id),
+            target
+        ),
+        ByteCode::Switch(table) => {
+            let mut operands = vec![format!("L{}", table.default)];
+            for (hash, target) in table.cases.iter() {
+                operands.push(format!("#{} => L{}", hash, target));
+            }
+            for (start, len, target) in table.ranges.iter() {
+                operands.push(format!("{} => L{}", format_constant(&DynamicConstant::Range(*start, *len)), target));
+            }
+            format!("Switch {}", operands.join(", "))
+        }
+        ByteCode::PushHandler(target) => format!("PushHandler L{}", target),
+        ByteCode::PopHandler => "PopHandler".to_string(),
+        ByteCode::Throw => "Throw".to_string(),
+        ByteCode::Return => "Return".to_string(),
+        ByteCode::PopStack => "PopStack".to_string(),
+        ByteCode::FnCallVarInt(fn_id, var_id, v) => {
+            format!("FnCallVarInt {}, {}, {}", format_name(&symbols.functions, *fn_id), variable(var_id), v)
+        }
+        ByteCode::FnCallVarVar(fn_id, var_id_a, var_id_b) => {
+            let var_a = variable(var_id_a);
+            format!("FnCallVarVar {}, {}, {}", format_name(&symbols.functions, *fn_id), var_a, variable(var_id_b))
+        }
+        ByteCode::JumpIfFalseVarInt(fn_id, var_id, v, target) => {
+            format!("JumpIfFalseVarInt {}, {}, {}, L{}", format_name(&symbols.functions, *fn_id), variable(var_id), v, target)
+        }
+    };
+}
+
+fn format_name(names: &[&str], index: SIZE) -> String {
+    return match names.get(index as usize) {
+        Some(name) => format!("{:?}", name),
+        None => format!("#{}", index),
+    };
+}
+
+/// Writes a constant like a Rhai literal, with ranges as `start..end`.
+fn format_constant(constant: &DynamicConstant) -> String {
+    return match constant {
+        DynamicConstant::Unit => "()".to_string(),
+        DynamicConstant::Bool(v) => v.to_string(),
+        DynamicConstant::Integer(v) => v.to_string(),
+        DynamicConstant::Float(v) => format!("{:?}", v),
+        DynamicConstant::Char(v) => format!("{:?}", v),
+        DynamicConstant::String(v) => format!("{:?}", v),
+        DynamicConstant::Array(ary) => {
+            let items = ary.iter().map(format_constant).collect::<Vec<_>>();
+            format!("[{}]", items.join(", "))
+        }
+        DynamicConstant::Range(start, len) => format!("{}..{}", start, *start as i128 + *len as i128),
+        DynamicConstant::Map(map) => {
+            let items = map.iter().map(|(key, v)| format!("{:?}: {}", key, format_constant(v))).collect::<Vec<_>>();
+            format!("#{{{}}}", items.join(", "))
+        }
+    };
+}
@@ -0,0 +1,202 @@
+//! Errors of compiling, loading, linking and running byte codes.
+
+use crate::{DynamicConstant, SourcePosition, VerifyReport};
+
+/// Error of any public API, so that callers can match on its `kind` instead of on messages.
+///
+/// The kind is boxed to keep results small, which matters for host functions called in a loop.
+pub struct Error(Box<ErrorKind>);
+
+/// What went wrong.
+#[derive(Debug, thiserror::Error)]
+pub enum ErrorKind {
+    /// Rhai failed to parse the script.
+    #[error(transparent)]
+    Parse(#[from] rhai::ParseError),
+    /// Script syntax which cannot be compiled to byte codes yet, e.g. "this".
+    #[error("{0} not supported yet!")]
+    Unsupported(String),
+    #[error("Undefined variable \"{0}\"!")]
+    UndefinedVariable(String),
+    /// Undefined host function or operator, or a function pointer to an undefined function while running.
+    #[error("Undefined function \"{0}\"!")]
+    UndefinedFunction(String),
+    #[error("Undefined method \"{0}\"!")]
+    UndefinedMethod(String),
+    #[error("Invalid \"break\" or \"continue\" statements without a loop!")]
+    BreakOutsideLoop,
+    /// Any other script which cannot be compiled.
+    #[error("{0}")]
+    Compile(String),
+    /// Text which `assemble` cannot parse.
+    #[error("Line {line}: {message}")]
+    Assemble { line: usize, message: String },
+    /// Host function or method which cannot be added to an `Executer`.
+    #[error("{0}")]
+    Registration(String),
+    /// Malformed program data, or written by another format version.
+    #[error("{0}")]
+    Format(String),
+    #[error(transparent)]
+    Link(#[from] LinkError),
+    #[error(transparent)]
+    Verify(#[from] VerifyReport),
+    /// Byte codes which cannot run, e.g. with too few values on the stack.
+    #[error("{0}")]
+    InvalidByteCode(String),
+    #[error("The program requires {expected} initial variables, but {given} given!")]
+    InitialVariables { expected: usize, given: usize },
+    /// Value of the wrong type for an operation, returned by `DynamicValue` implementations.
+    #[error("{0}")]
+    Type(String),
+    /// Failure of a host function.
+    #[error("{0}")]
+    Host(Box<dyn std::error::Error + Send + Sync>),
+    #[error(transparent)]
+    Limit(#[from] Limit),
+    /// Value returned by the callback set by `Executer::on_progress` to abort a run.
+    #[error("Run terminated with {0:?}!")]
+    Terminated(DynamicConstant),
+    /// Value thrown by a script or a host function, which scripts can `catch`.
+    ///
+    /// Only the run calling back into a script catches it from a host function, any other error aborts the whole run.
+    #[error("Exception \"{0:?}\"")]
+    Exception(DynamicConstant),
+    /// Returned by a host function to suspend a `Vm`, which is resumed with the result of the call.
+    ///
+    /// Fails any other run, and runs called back by `NativeCallContext::call_fn_ptr`.
+    #[error("Yield \"{0:?}\" outside of a resumable run!")]
+    Yield(DynamicConstant),
+    /// Stops a `Vm` at a breakpoint or after a step, which never fails a run.
+    #[error("Paused by the debugger!")]
+    Paused,
+    /// `Vm` resumed when not suspended by a yield, or continued when not paused.
+    #[error("{0}")]
+    Resume(String),
+    /// Thrown value which cannot be kept as a constant.
+    #[error("Uncaught exception \"{0}\"!")]
+    UncaughtException(String),
+    #[error(transparent)]
+    Runtime(RuntimeError),
+}
+
+impl Error {
+    pub fn kind(&self) -> &ErrorKind {
+        return &self.0;
+    }
+    pub fn into_kind(self) -> ErrorKind {
+        return *self.0;
+    }
+    /// Creates an `ErrorKind::Type`, e.g. from `format_args!`.
+    ///
+    /// Kept out of line, so that `DynamicValue` methods stay small enough to be inlined on hot paths.
+    #[cold]
+    pub fn type_error(message: impl std::fmt::Display) -> Self {
+        return ErrorKind::Type(message.to_string()).into();
+    }
+    /// Creates an `ErrorKind::Host` with a message, out of line like `type_error`.
+    #[cold]
+    pub fn host(message: impl std::fmt::Display) -> Self {
+        return ErrorKind::Host(message.to_string().into()).into();
+    }
+    /// Returns the kind of the error, looking through the location of a `RuntimeError`.
+    pub fn kind_without_location(&self) -> &ErrorKind {
+        return match self.kind() {
+            ErrorKind::Runtime(runtime_error) => runtime_error.error.kind(),
+            kind => kind,
+        };
+    }
+}
+
+impl<E: Into<ErrorKind>> From<E> for Error {
+    fn from(kind: E) -> Self {
+        return Error(Box::new(kind.into()));
+    }
+}
+
+impl std::fmt::Debug for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return self.0.fmt(f);
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return self.0.fmt(f);
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        return self.0.source();
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Resource limit exceeded by a run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum Limit {
+    /// Set by `Executer::set_max_call_levels`.
+    #[error("Too many levels of function calls!")]
+    CallLevels,
+    /// Set by `RunLimits::max_instructions`.
+    #[error("Too many instructions executed!")]
+    Instructions,
+    /// Set by `RunLimits::max_stack_depth`.
+    #[error("Too many values on the stack!")]
+    StackDepth,
+    /// Set by `RunLimits::max_array_length`.
+    #[error("Too many elements in an array!")]
+    ArrayLength,
+    /// Set by `RunLimits::deadline`.
+    #[error("Deadline of the run exceeded!")]
+    Deadline,
+}
+
+/// Error of a run, with the location of the byte code which failed.
+#[derive(Debug)]
+pub struct RuntimeError {
+    pub error: Error,
+    pub address: usize,
+    /// Known for a `CompiledProgram` built without the "no_position" feature.
+    pub position: Option<SourcePosition>,
+    /// Script function running, if not the main script.
+    pub script_function: Option<String>,
+    /// Host function or method called by the byte code.
+    pub host_function: Option<String>,
+}
+
+impl std::fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (at address {}", self.error, self.address)?;
+        if let Some(position) = &self.position {
+            write!(f, ", line {}, column {}", position.line, position.column)?;
+        }
+        if let Some(name) = &self.script_function {
+            write!(f, ", in function \"{}\"", name)?;
+        }
+        if let Some(name) = &self.host_function {
+            write!(f, ", calling \"{}\"", name)?;
+        }
+        return write!(f, ")");
+    }
+}
+
+impl std::error::Error for RuntimeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        return Some(&self.error);
+    }
+}
+
+/// Errors found when linking a `CompiledProgram`, one message per missing or incompatible import.
+#[derive(Clone,Debug)]
+pub struct LinkError(pub Vec<String>);
+
+impl std::fmt::Display for LinkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return write!(f, "Failed to link program:\n{}", self.0.join("\n"));
+    }
+}
+
+impl std::error::Error for LinkError {}
@@ -1,10 +1,33 @@
 use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{BuildHasher, Hash, Hasher};
 use std::rc::Rc;
 pub use rhai;
+mod assemble;
+pub use assemble::assemble;
+mod binary;
+mod disassemble;
+pub use disassemble::disassemble;
+mod error;
+pub use error::{Error, ErrorKind, Limit, LinkError, Result, RuntimeError};
+mod optimize;
+pub use optimize::{fold_constants, optimize, optimize_byte_codes, OptimizationLevel};
+mod profile;
+pub use profile::{HostCalls, Profile};
+mod verify;
+pub use verify::{verify, VerifyError, VerifyErrorKind, VerifyReport, MAX_VARIABLE_COUNT};
+mod vm;
+pub use vm::{Vm, VmSnapshot, VmStatus};
+#[cfg(test)]
+mod test_value;
 use rhai::{Expr, Stmt};
 
 thread_local! {
-    static COMPILE_ENGINE: std::cell::RefCell<rhai::Engine> = std::cell::RefCell::new(rhai::Engine::new_raw());
+    static COMPILE_ENGINE: std::cell::RefCell<rhai::Engine> = const { std::cell::RefCell::new(rhai::Engine::new_raw()) };
+    // Whether `switch_case_hash` gives the same hashes as Rhai, checked once.
+    static SWITCH_CASE_HASH_MATCHES: std::cell::OnceCell<bool> = const { std::cell::OnceCell::new() };
+    // Source positions of the byte codes being compiled, by address.
+    static SOURCE_POSITIONS: std::cell::RefCell<Vec<Option<SourcePosition>>> = const { std::cell::RefCell::new(Vec::new()) };
 }
 
 #[cfg(feature = "thin-vec")]
@@ -49,10 +72,27 @@ pub enum DynamicConstant {
     Array(VEC<DynamicConstant>),
     #[serde(rename="R")]
     Range(INT,INT),
+    #[serde(rename="M")]
+    Map(BTreeMap<String,DynamicConstant>),
 }
 
 impl DynamicConstant{
-    fn from_dynamic(dynamic: &rhai::Dynamic) -> anyhow::Result<Self> {
+    fn from_dynamic(dynamic: &rhai::Dynamic) -> Result<Self> {
+        #[cfg(not(feature = "no_object"))]
+        if dynamic.is_map() {
+            match dynamic.as_map_ref() {
+                Ok(map) => {
+                    let mut new_map=BTreeMap::new();
+                    for (key, item) in map.iter() {
+                        new_map.insert(key.to_string(), Self::from_dynamic(item)?);
+                    }
+                    return Ok(Self::Map(new_map));
+                }
+                Err(_) => {
+                    return Err(ErrorKind::Compile("Failed to convert rhai::Dynamic to map!".to_string()).into());
+                }
+            }
+        }
         if dynamic.is_unit() {
             return Ok(Self::Unit);
         } else if dynamic.is_bool() {
@@ -61,7 +101,7 @@ impl DynamicConstant{
                     return Ok(Self::Bool(v));
                 }
                 Err(_) => {
-                    anyhow::bail!("Failed to convert rhai::Dynamic to bool!");
+                    return Err(ErrorKind::Compile("Failed to convert rhai::Dynamic to bool!".to_string()).into());
                 }
             }
         } else if dynamic.is_char() {
@@ -70,7 +110,7 @@ impl DynamicConstant{
                     return Ok(Self::Char(v));
                 }
                 Err(_) => {
-                    anyhow::bail!("Failed to convert rhai::Dynamic to char!");
+                    return Err(ErrorKind::Compile("Failed to convert rhai::Dynamic to char!".to_string()).into());
                 }
             }
         } else if dynamic.is_int() {
@@ -79,7 +119,7 @@ impl DynamicConstant{
                     return Ok(Self::Integer(v));
                 }
                 Err(_) => {
-                    anyhow::bail!("Failed to convert rhai::Dynamic to int!");
+                    return Err(ErrorKind::Compile("Failed to convert rhai::Dynamic to int!".to_string()).into());
                 }
             }
         } else if dynamic.is_float() {
@@ -88,7 +128,7 @@ impl DynamicConstant{
                     return Ok(Self::Float(v));
                 }
                 Err(_) => {
-                    anyhow::bail!("Failed to convert rhai::Dynamic to float!");
+                    return Err(ErrorKind::Compile("Failed to convert rhai::Dynamic to float!".to_string()).into());
                 }
             }
         } else if dynamic.is_string() {
@@ -103,7 +143,7 @@ impl DynamicConstant{
                     return Ok(Self::Array(vec));
                 }
                 Err(_) => {
-                    anyhow::bail!("Failed to convert rhai::Dynamic to array!");
+                    return Err(ErrorKind::Compile("Failed to convert rhai::Dynamic to array!".to_string()).into());
                 }
             }
         }else if dynamic.type_id()== std::any::TypeId::of::<std::ops::Range<INT>>() {
@@ -111,13 +151,13 @@ impl DynamicConstant{
                 Ok(range) => {
                     let l=range.end-range.start;
                     if l < 0 {
-                        anyhow::bail!("Range \"{:?}\"'s start is greater than its end!",range);
+                        return Err(ErrorKind::Compile(format!("Range \"{:?}\"'s start is greater than its end!",range)).into());
                     } else {
                         return Ok(Self::Range(range.start,l));
                     }
                 }
                 Err(_) => {
-                    anyhow::bail!("Failed to convert rhai::Dynamic to range!");
+                    return Err(ErrorKind::Compile("Failed to convert rhai::Dynamic to range!".to_string()).into());
                 }
             }
         }else if dynamic.type_id()== std::any::TypeId::of::<std::ops::RangeInclusive<INT>>() {
@@ -126,39 +166,185 @@ impl DynamicConstant{
                     // I think this is enough, another type is not needed.
                     let l=range.end()-range.start();
                     if l < 0 {
-                        anyhow::bail!("Range \"{:?}\"'s start is greater than its end!",range);
+                        return Err(ErrorKind::Compile(format!("Range \"{:?}\"'s start is greater than its end!",range)).into());
                     } else {
                         return Ok(Self::Range(*range.start(),l+1));
                     }
                 }
                 Err(_) => {
-                    anyhow::bail!("Failed to convert rhai::Dynamic to range!");
+                    return Err(ErrorKind::Compile("Failed to convert rhai::Dynamic to range!".to_string()).into());
                 }
             }
         }else{
-            anyhow::bail!("Unsupported type \"{:?}\"!", dynamic.type_name());
+            return Err(ErrorKind::Compile(format!("Unsupported type \"{:?}\"!", dynamic.type_name())).into());
+        }
+    }
+    fn to_dynamic(&self) -> rhai::Dynamic {
+        match self {
+            Self::Unit => rhai::Dynamic::UNIT,
+            Self::Bool(v) => rhai::Dynamic::from_bool(*v),
+            Self::Integer(v) => rhai::Dynamic::from_int(*v),
+            Self::Float(v) => rhai::Dynamic::from_float(*v),
+            Self::Char(v) => rhai::Dynamic::from_char(*v),
+            Self::String(v) => rhai::Dynamic::from(v.clone()),
+            Self::Array(ary) => rhai::Dynamic::from_array(ary.iter().map(|v| v.to_dynamic()).collect()),
+            Self::Range(start, len) => rhai::Dynamic::from(*start..*start+*len),
+            #[cfg(not(feature = "no_object"))]
+            Self::Map(map) => rhai::Dynamic::from_map(map.iter().map(|(k, v)| (k.into(), v.to_dynamic())).collect()),
+            // Rhai has no maps, and "switch" cases are never maps.
+            #[cfg(feature = "no_object")]
+            Self::Map(_) => rhai::Dynamic::UNIT,
+        }
+    }
+}
+
+// Floats are compared by their bits, the same way Rhai hashes "switch" cases.
+impl PartialEq for DynamicConstant {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Unit, Self::Unit) => true,
+            (Self::Bool(va), Self::Bool(vb)) => va == vb,
+            (Self::Integer(va), Self::Integer(vb)) => va == vb,
+            (Self::Float(va), Self::Float(vb)) => va.to_bits() == vb.to_bits(),
+            (Self::Char(va), Self::Char(vb)) => va == vb,
+            (Self::String(va), Self::String(vb)) => va == vb,
+            (Self::Array(va), Self::Array(vb)) => va == vb,
+            (Self::Range(sa, la), Self::Range(sb, lb)) => sa == sb && la == lb,
+            (Self::Map(va), Self::Map(vb)) => va == vb,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for DynamicConstant {}
+
+impl Hash for DynamicConstant {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Self::Unit => {}
+            Self::Bool(v) => v.hash(state),
+            Self::Integer(v) => v.hash(state),
+            Self::Float(v) => v.to_bits().hash(state),
+            Self::Char(v) => v.hash(state),
+            Self::String(v) => v.hash(state),
+            Self::Array(ary) => ary.hash(state),
+            Self::Range(start, len) => {
+                start.hash(state);
+                len.hash(state);
+            }
+            Self::Map(map) => map.hash(state),
+        }
+    }
+}
+
+/// Jump table of a `switch` statement.
+///
+/// Literal cases are looked up by the hash Rhai gives their values, integer (or float) values not found there
+/// are matched against `ranges` (start, length, target) in order, everything else goes to `default`.
+/// The hashes depend on the Rhai build and `rhai::config::hashing`, so byte codes must run with the same ones.
+#[derive(Clone,Debug, serde::Serialize, serde::Deserialize)]
+pub struct SwitchTable {
+    #[serde(rename="C")]
+    pub cases: VEC<(u64, SIZE)>,
+    #[serde(rename="R")]
+    pub ranges: VEC<(INT, INT, SIZE)>,
+    #[serde(rename="D")]
+    pub default: SIZE,
+}
+
+impl SwitchTable {
+    fn range_target(&self, value: &DynamicConstant) -> SIZE {
+        for (start, len, target) in self.ranges.iter() {
+            let in_range = match value {
+                DynamicConstant::Integer(v) => match v.checked_sub(*start) {
+                    Some(offset) => offset >= 0 && offset < *len,
+                    None => false,
+                },
+                DynamicConstant::Float(v) => *v >= *start as FLOAT && *v < (*start as FLOAT) + (*len as FLOAT),
+                _ => false,
+            };
+            if in_range {
+                return *target;
+            }
+        }
+        return self.default;
+    }
+}
+
+/// Hash of a value as Rhai computes it for "switch" cases, which `SwitchTable::cases` are keyed on.
+pub fn switch_case_hash(value: &DynamicConstant) -> u64 {
+    let mut hasher = match rhai::config::hashing::get_hashing_seed() {
+        Some([seed1, seed2, seed3, seed4]) if (seed1 | seed2 | seed3 | seed4) != 0 => {
+            ahash::RandomState::with_seeds(*seed1, *seed2, *seed3, *seed4).build_hasher()
         }
+        _ => ahash::AHasher::default(),
+    };
+    value.to_dynamic().hash(&mut hasher);
+    return hasher.finish();
+}
+
+// Fails unless a hash of `switch_case_hash` is found in a "switch" parsed by Rhai.
+fn check_switch_case_hash() -> Result<()> {
+    let matches = SWITCH_CASE_HASH_MATCHES.with(|matches| {
+        return *matches.get_or_init(|| {
+            let mut engine = rhai::Engine::new_raw();
+            engine.set_optimization_level(rhai::OptimizationLevel::None);
+            return match engine.compile("switch x { 1 => () }") {
+                Ok(ast) => match ast.statements() {
+                    [Stmt::Switch(data, _)] => data.1.cases.contains_key(&switch_case_hash(&DynamicConstant::Integer(1))),
+                    _ => false,
+                },
+                Err(_) => false,
+            };
+        });
+    });
+    if !matches {
+        return Err(ErrorKind::Compile("This version of Rhai hashes \"switch\" cases differently, so they cannot be matched!".to_string()).into());
     }
+    return Ok(());
 }
 
 pub trait DynamicValue: Sized + Clone {
-    fn from_constant(v:DynamicConstant) -> anyhow::Result<Self>;
-    fn from_unit() -> anyhow::Result<Self>;
-    fn from_bool(v:bool) -> anyhow::Result<Self>;
-    fn from_integer(v:INT) -> anyhow::Result<Self>;
-    fn from_float(v:FLOAT) -> anyhow::Result<Self>;
-    fn from_char(v:char) -> anyhow::Result<Self>;
-    fn from_string(v:String) -> anyhow::Result<Self>;
-    fn from_array(v:VEC<Rc<RefCell<Self>>>) -> anyhow::Result<Self>;
+    fn from_constant(v:DynamicConstant) -> Result<Self>;
+    fn from_unit() -> Result<Self>;
+    fn from_bool(v:bool) -> Result<Self>;
+    fn from_integer(v:INT) -> Result<Self>;
+    fn from_float(v:FLOAT) -> Result<Self>;
+    fn from_char(v:char) -> Result<Self>;
+    fn from_string(v:String) -> Result<Self>;
+    fn from_array(v:VEC<Rc<RefCell<Self>>>) -> Result<Self>;
+    fn from_map(v:BTreeMap<String,Rc<RefCell<Self>>>) -> Result<Self>;
+    fn from_fn_ptr(v:FnPtr<Self>) -> Result<Self>;
     fn is_unit(&self) -> bool;
-    fn to_bool(&self) -> anyhow::Result<bool>;
-    fn to_size(&self) -> anyhow::Result<SIZE>;
-    fn index_into(&self,ind:SIZE)->anyhow::Result<Rc<RefCell<Self>>>;
-    fn iter(&self,index:SIZE) -> anyhow::Result<Option<Rc<RefCell<Self>>>>;
+    fn to_constant(&self) -> Option<DynamicConstant>;
+    fn to_bool(&self) -> Result<bool>;
+    fn to_size(&self) -> Result<SIZE>;
+    fn to_fn_ptr(&self) -> Result<FnPtr<Self>>;
+    /// Converts the value to text for interpolated strings, like Rhai's `to_string`.
+    fn to_display_string(&self) -> Result<String>;
+    fn index_into(&self,ind:SIZE)->Result<Rc<RefCell<Self>>>;
+    fn get_property(&self,name:&str) -> Result<Rc<RefCell<Self>>>;
+    fn set_property(&mut self,name:&str,value:Rc<RefCell<Self>>) -> Result<()>;
+    fn iter(&self,index:SIZE) -> Result<Option<Rc<RefCell<Self>>>>;
+    /// Copies the value along with the items of its arrays and maps, since assigning a value copies it in Rhai.
+    fn deep_clone(&self) -> Self;
+}
+
+/// Function pointer, created by "Fn(...)" or a closure.
+///
+/// Calling it calls the function `name` with the curried arguments placed before the others.
+/// Variables captured by a closure are curried as shared cells, which serde copies.
+#[derive(Clone,Debug,serde::Serialize, serde::Deserialize)]
+pub struct FnPtr<B> {
+    pub name: String,
+    pub curry: Vec<Rc<RefCell<B>>>,
 }
 
 #[derive(Clone,Debug,serde::Serialize, serde::Deserialize)]
 pub enum ByteCode {
+    #[serde(rename="K")]
+    Constant(SIZE),
     #[serde(rename="DC")]
     DynamicConstant(DynamicConstant),
     #[serde(rename="UC")]
@@ -177,10 +363,26 @@ pub enum ByteCode {
     InterpolatedString(SIZE),
     #[serde(rename="CA")]
     ConstructArray(SIZE),
+    #[serde(rename="CM")]
+    ConstructMap(VEC<String>),
     #[serde(rename="V")]
     Variable(SIZE),
     #[serde(rename="F")]
     FnCall(SIZE, SIZE),
+    #[serde(rename="M")]
+    MethodCall(SIZE, SIZE),
+    #[serde(rename="C")]
+    Call(SIZE, SIZE),
+    #[serde(rename="E")]
+    Enter(String, SIZE, SIZE),
+    #[serde(rename="FP")]
+    FnPtr(String),
+    #[serde(rename="CU")]
+    Curry(SIZE),
+    #[serde(rename="CL")]
+    Closure(SIZE),
+    #[serde(rename="CP")]
+    CallPtr(SIZE),
     #[serde(rename="J")]
     Jump(SIZE),
     #[serde(rename="JT")]
@@ -191,20 +393,124 @@ pub enum ByteCode {
     JumpIfNotNull(SIZE),
     #[serde(rename="VI")]
     VarInit(SIZE),
+    /// Same as `VarInit` followed by `PopStack`, produced by `optimize`.
+    #[serde(rename="VS")]
+    VarStore(SIZE),
     #[serde(rename="I")]
     Index,
+    #[serde(rename="GP")]
+    GetProperty(String),
+    #[serde(rename="SP")]
+    SetProperty(String),
     #[serde(rename="IT")]
     Iter(SIZE,SIZE,SIZE,SIZE),
+    #[serde(rename="SW")]
+    Switch(Box<SwitchTable>),
+    #[serde(rename="H")]
+    PushHandler(SIZE),
+    #[serde(rename="EH")]
+    PopHandler,
+    #[serde(rename="T")]
+    Throw,
     #[serde(rename="R")]
     Return,
     #[serde(rename="P")]
     PopStack,
+    /// Same as `Variable` and `IntegerConstant` followed by `FnCall` with 2 arguments, e.g. for "x -= 1".
+    ///
+    /// Fused byte codes like this one are produced by the compiler, unless turned off by `Executer::set_fuse_instructions`.
+    #[serde(rename="FVI")]
+    FnCallVarInt(SIZE, SIZE, INT),
+    /// Same as two `Variable` followed by `FnCall` with 2 arguments, e.g. for "i += p".
+    #[serde(rename="FVV")]
+    FnCallVarVar(SIZE, SIZE, SIZE),
+    /// Same as `FnCallVarInt` followed by `JumpIfFalse`, e.g. for "while x > 0".
+    ///
+    /// Only produced for pure functions, and rejected by `verify` for others, since a `Vm` cannot resume it after a yield.
+    #[serde(rename="JFVI")]
+    JumpIfFalseVarInt(SIZE, SIZE, INT, SIZE),
+}
+
+impl ByteCode {
+    /// Name of the instruction, as written by `disassemble`.
+    pub fn name(&self) -> &'static str {
+        return match self {
+            Self::Constant(..) => "Constant",
+            Self::DynamicConstant(..) => "DynamicConstant",
+            Self::UnitConstant => "UnitConstant",
+            Self::BoolConstant(..) => "BoolConstant",
+            Self::IntegerConstant(..) => "IntegerConstant",
+            Self::FloatConstant(..) => "FloatConstant",
+            Self::CharConstant(..) => "CharConstant",
+            Self::StringConstant(..) => "StringConstant",
+            Self::InterpolatedString(..) => "InterpolatedString",
+            Self::ConstructArray(..) => "ConstructArray",
+            Self::ConstructMap(..) => "ConstructMap",
+            Self::Variable(..) => "Variable",
+            Self::FnCall(..) => "FnCall",
+            Self::MethodCall(..) => "MethodCall",
+            Self::Call(..) => "Call",
+            Self::Enter(..) => "Enter",
+            Self::FnPtr(..) => "FnPtr",
+            Self::Curry(..) => "Curry",
+            Self::Closure(..) => "Closure",
+            Self::CallPtr(..) => "CallPtr",
+            Self::Jump(..) => "Jump",
+            Self::JumpIfTrue(..) => "JumpIfTrue",
+            Self::JumpIfFalse(..) => "JumpIfFalse",
+            Self::JumpIfNotNull(..) => "JumpIfNotNull",
+            Self::VarInit(..) => "VarInit",
+            Self::VarStore(..) => "VarStore",
+            Self::Index => "Index",
+            Self::GetProperty(..) => "GetProperty",
+            Self::SetProperty(..) => "SetProperty",
+            Self::Iter(..) => "Iter",
+            Self::Switch(..) => "Switch",
+            Self::PushHandler(..) => "PushHandler",
+            Self::PopHandler => "PopHandler",
+            Self::Throw => "Throw",
+            Self::Return => "Return",
+            Self::PopStack => "PopStack",
+            Self::FnCallVarInt(..) => "FnCallVarInt",
+            Self::FnCallVarVar(..) => "FnCallVarVar",
+            Self::JumpIfFalseVarInt(..) => "JumpIfFalseVarInt",
+        };
+    }
+}
+
+/// Line and column in a script, both starting from 1.
+#[derive(Clone,Copy,Debug,PartialEq,Eq,serde::Serialize, serde::Deserialize)]
+pub struct SourcePosition {
+    pub line: u32,
+    pub column: u32,
 }
 
+type HostFn<B> = Box<dyn Fn(&NativeCallContext<'_, B>, &[Rc<RefCell<B>>]) -> Result<Rc<RefCell<B>>>>;
+
 pub struct Executer<B: DynamicValue+std::fmt::Debug> {
     fn_names: Vec<String>,
-    fns: Vec<Box<dyn Fn(&[Rc<RefCell<B>>]) -> anyhow::Result<Rc<RefCell<B>>>>>,
+    fns: Vec<HostFn<B>>,
     fn_arg_ranges: Vec<(SIZE,SIZE)>,
+    // Functions added by `add_pure_fn`.
+    fn_pure: Vec<bool>,
+    method_names: Vec<String>,
+    methods: Vec<HostFn<B>>,
+    method_arg_ranges: Vec<(SIZE,SIZE)>,
+    max_call_levels: usize,
+    progress: Option<Progress>,
+    fuse_instructions: bool,
+}
+
+impl<B: DynamicValue+std::fmt::Debug> Default for Executer<B> {
+    fn default() -> Self {
+        return Self::new();
+    }
+}
+
+// Callback set by `Executer::on_progress`.
+struct Progress {
+    interval: u64,
+    callback: Box<dyn Fn(u64) -> Option<DynamicConstant>>,
 }
 
 impl<B: DynamicValue+std::fmt::Debug> Executer<B> {
@@ -213,61 +519,245 @@ impl<B: DynamicValue+std::fmt::Debug> Executer<B> {
             fn_names: vec![],
             fns: vec![],
             fn_arg_ranges: vec![],
+            fn_pure: vec![],
+            method_names: vec![],
+            methods: vec![],
+            method_arg_ranges: vec![],
+            max_call_levels: 64,
+            progress: None,
+            fuse_instructions: true,
+        };
+    }
+    fn function_names(&self) -> FunctionNames<'_> {
+        return FunctionNames {
+            fns: &self.fn_names,
+            methods: &self.method_names,
+            scripts: vec![],
         };
     }
-    fn function_names(&self) -> &Vec<String> {
-        return &self.fn_names;
+    /// Sets the maximum depth of nested calls to script functions, 64 by default.
+    pub fn set_max_call_levels(&mut self, levels: usize) {
+        self.max_call_levels = levels;
+    }
+    /// Sets whether the compiler fuses common sequences of byte codes into single ones, e.g. `FnCallVarInt`, on by default.
+    ///
+    /// Fused byte codes run faster, since they are dispatched once and do not push their operands.
+    pub fn set_fuse_instructions(&mut self, fuse: bool) {
+        self.fuse_instructions = fuse;
+    }
+    /// Calls back every `interval` instructions of a run with the number of instructions executed so far,
+    /// like Rhai's `Engine::on_progress`.
+    ///
+    /// Returning a value aborts the run with an `ErrorKind::Terminated`, e.g. to cancel it from another thread
+    /// by a flag which the callback checks.
+    pub fn on_progress<F:Fn(u64) -> Option<DynamicConstant>+'static>(&mut self, interval: u64, callback: F) {
+        self.progress = Some(Progress {
+            interval: interval.max(1),
+            callback: Box::new(callback),
+        });
+    }
+    pub fn add_fn<F:Fn(&[Rc<RefCell<B>>]) -> Result<Rc<RefCell<B>>>+'static>(
+        &mut self,
+        name: impl ToString,
+        func: F,
+        min_args: SIZE,
+        max_args: SIZE,
+    ) -> Result<()> {
+        return self.add_fn_with_context(name, move |_, args| func(args), min_args, max_args);
+    }
+    /// Adds a function without side effects, whose result only depends on its arguments, e.g. an arithmetic operator.
+    ///
+    /// Its calls with constant arguments can be replaced by their results by `fold_constants`.
+    pub fn add_pure_fn<F:Fn(&[Rc<RefCell<B>>]) -> Result<Rc<RefCell<B>>>+'static>(
+        &mut self,
+        name: impl ToString,
+        func: F,
+        min_args: SIZE,
+        max_args: SIZE,
+    ) -> Result<()> {
+        self.add_fn(name, func, min_args, max_args)?;
+        *self.fn_pure.last_mut().unwrap() = true; // Never panics since a function was just added.
+        return Ok(());
     }
-    pub fn add_fn<F:Fn(&[Rc<RefCell<B>>]) -> anyhow::Result<Rc<RefCell<B>>>+'static>(
+    /// Adds a function which can call back function pointers through its `NativeCallContext`.
+    pub fn add_fn_with_context<F:Fn(&NativeCallContext<'_, B>, &[Rc<RefCell<B>>]) -> Result<Rc<RefCell<B>>>+'static>(
         &mut self,
         name: impl ToString,
         func: F,
         min_args: SIZE,
         max_args: SIZE,
-    ) -> anyhow::Result<()> {
+    ) -> Result<()> {
         let name_string = name.to_string();
         if self.fn_names.contains(&name_string) {
-            anyhow::bail!("Function \"{}\" already exists!", name_string);
+            return Err(ErrorKind::Registration(format!("Function \"{}\" already exists!", name_string)).into());
         } else {
             if min_args > max_args {
-                anyhow::bail!(
+                return Err(ErrorKind::Registration(format!(
                     "Minimum arguments for function \"{}\" is greater than maximum!",
                     name_string
-                );
+                )).into());
             }
             self.fns.push(Box::new(func));
             self.fn_arg_ranges.push((min_args, max_args));
+            self.fn_pure.push(false);
             self.fn_names.push(name_string);
             return Ok(());
         }
     }
-    fn check_fn_arg_count(&self, index: SIZE, arg_count: SIZE) -> anyhow::Result<()> {
+    /// Adds a method called as "obj.name(...)".
+    ///
+    /// Methods live in their own namespace, so a method and a function may share a name.
+    /// The receiver is passed by reference as the first argument, and is counted in `min_args` and `max_args`.
+    pub fn add_method<F:Fn(&[Rc<RefCell<B>>]) -> Result<Rc<RefCell<B>>>+'static>(
+        &mut self,
+        name: impl ToString,
+        func: F,
+        min_args: SIZE,
+        max_args: SIZE,
+    ) -> Result<()> {
+        return self.add_method_with_context(name, move |_, args| func(args), min_args, max_args);
+    }
+    /// Adds a method which can call back function pointers through its `NativeCallContext`, e.g. "arr.map(|x| x * 2)".
+    pub fn add_method_with_context<F:Fn(&NativeCallContext<'_, B>, &[Rc<RefCell<B>>]) -> Result<Rc<RefCell<B>>>+'static>(
+        &mut self,
+        name: impl ToString,
+        func: F,
+        min_args: SIZE,
+        max_args: SIZE,
+    ) -> Result<()> {
+        let name_string = name.to_string();
+        if self.method_names.contains(&name_string) {
+            return Err(ErrorKind::Registration(format!("Method \"{}\" already exists!", name_string)).into());
+        } else {
+            if min_args == 0 || min_args > max_args {
+                return Err(ErrorKind::Registration(format!(
+                    "Invalid argument range for method \"{}\"!",
+                    name_string
+                )).into());
+            }
+            self.methods.push(Box::new(func));
+            self.method_arg_ranges.push((min_args, max_args));
+            self.method_names.push(name_string);
+            return Ok(());
+        }
+    }
+    fn is_pure_fn(&self, index: SIZE) -> bool {
+        return self.fn_pure.get(index as usize).copied().unwrap_or(false);
+    }
+    fn check_fn_arg_count(&self, index: SIZE, arg_count: SIZE) -> Result<()> {
+        return check_arg_count(&self.fn_names, &self.fn_arg_ranges, "Function", index, arg_count);
+    }
+    fn check_method_arg_count(&self, index: SIZE, arg_count: SIZE) -> Result<()> {
+        return check_arg_count(&self.method_names, &self.method_arg_ranges, "Method", index, arg_count);
+    }
+    fn call_fn(&self, index: SIZE, context: &NativeCallContext<B>, args: &[Rc<RefCell<B>>]) -> Result<Rc<RefCell<B>>> {
         let ind = index as usize;
-        if ind >= self.fns.len() {
-            anyhow::bail!("Function \"{}\" does not exist!", self.fn_names[ind]);
+        return self.fns[ind](context, args);
+    }
+    fn call_method(&self, index: SIZE, context: &NativeCallContext<B>, args: &[Rc<RefCell<B>>]) -> Result<Rc<RefCell<B>>> {
+        let ind = index as usize;
+        return self.methods[ind](context, args);
+    }
+}
+
+fn check_arg_count(names: &[String], arg_ranges: &[(SIZE,SIZE)], type_str: &str, index: SIZE, arg_count: SIZE) -> Result<()> {
+    let ind = index as usize;
+    if ind >= names.len() {
+        return Err(ErrorKind::InvalidByteCode(format!("{} #{} does not exist!", type_str, ind)).into());
+    }
+    let (min_args, max_args) = &arg_ranges[ind];
+    if arg_count < *min_args {
+        return Err(ErrorKind::InvalidByteCode(format!(
+            "{} \"{}\" requires at least {} arguments, but {} given!",
+            type_str,
+            names[ind],
+            min_args,
+            arg_count
+        )).into());
+    }
+    if arg_count > *max_args {
+        return Err(ErrorKind::InvalidByteCode(format!(
+            "{} \"{}\" requires at most {} arguments, but {} given!",
+            type_str,
+            names[ind],
+            max_args,
+            arg_count
+        )).into());
+    }
+    return Ok(());
+}
+
+// Names of the host functions and methods, as seen by the compiler.
+struct FunctionNames<'a> {
+    fns: &'a Vec<String>,
+    methods: &'a Vec<String>,
+    // Names and parameter counts of script functions.
+    scripts: Vec<(String,SIZE)>,
+}
+
+// Appends a function call with its arguments.
+// Script functions are looked up first, by name and number of arguments, like Rhai does.
+// Calls to script functions hold the function index until the addresses are known.
+fn append_fn_call(
+    functions: &FunctionNames,
+    variables: &mut Vec<String>,
+    break_pos: &mut Vec<usize>,
+    continue_pos: &mut Vec<usize>,
+    byte_codes: &mut Vec<ByteCode>,
+    fn_call_expr: &rhai::FnCallExpr,
+) -> Result<()> {
+    let name = fn_call_expr.name.as_str();
+    let arg_count = fn_call_expr.args.len() as SIZE;
+    let script_id = functions.scripts.iter().position(|(n, c)| n == name && *c == arg_count);
+    // A variable holding a function pointer can be called like a function.
+    let fn_ptr_var_id = match script_id {
+        None if !functions.fns.iter().any(|x| x == name) => variables.iter().rposition(|x| x == name),
+        _ => None,
+    };
+    if let Some(var_id) = fn_ptr_var_id {
+        byte_codes.push(ByteCode::Variable(var_id as SIZE));
+    }
+    for sub_expr in &fn_call_expr.args {
+        append_expr(
+            functions,
+            variables,
+            break_pos,
+            continue_pos,
+            byte_codes,
+            sub_expr,
+        )?;
+    }
+    match (name, script_id, fn_ptr_var_id) {
+        ("call", _, _) if arg_count > 0 => {
+            byte_codes.push(ByteCode::CallPtr(arg_count - 1));
         }
-        let (min_args, max_args) = &self.fn_arg_ranges[ind];
-        if arg_count < *min_args {
-            anyhow::bail!("Function \"{}\" requires at least {} arguments, but {} given!",self.fn_names[ind], min_args, arg_count);
+        ("curry", _, _) if arg_count > 0 => {
+            byte_codes.push(ByteCode::Curry(arg_count - 1));
         }
-        if arg_count > *max_args {
-            anyhow::bail!("Function \"{}\" requires at most {} arguments, but {} given!", self.fn_names[ind], max_args, arg_count);
+        (_, Some(script_id), _) => {
+            byte_codes.push(ByteCode::Call(script_id as SIZE, arg_count));
+        }
+        (_, _, Some(_)) => {
+            byte_codes.push(ByteCode::CallPtr(arg_count));
+        }
+        ("Fn", _, _) if !functions.fns.iter().any(|x| x == name) => {
+            return Err(ErrorKind::Unsupported("Function pointer with a non-constant name".to_string()).into());
+        }
+        _ => {
+            let fn_id = find_index(functions.fns, name, ErrorKind::UndefinedFunction)?;
+            byte_codes.push(ByteCode::FnCall(fn_id, arg_count));
         }
-        return  Ok(());
-    }
-    fn call_fn(&self, index: SIZE, args: &[Rc<RefCell<B>>]) -> anyhow::Result<Rc<RefCell<B>>> {
-        let ind = index as usize;
-        return self.fns[ind](args);
     }
+    return Ok(());
 }
 
-fn find_index(vec: &Vec<String>, name: &str, type_str: &str) -> anyhow::Result<SIZE> {
+fn find_index(vec: &[String], name: &str, undefined: fn(String) -> ErrorKind) -> Result<SIZE> {
     match vec.iter().rposition(|x| x == name) {
         Some(i) => {
             return Ok(i as SIZE);
         }
         None => {
-            anyhow::bail!("Undefined {} \"{}\"!", type_str, name);
+            return Err(undefined(name.to_string()).into());
         }
     }
 }
@@ -278,14 +768,22 @@ fn append_return_index(vec: &mut Vec<String>, name: &str) -> SIZE {
 }
 
 fn append_expr(
-    functions: &Vec<String>,
+    functions: &FunctionNames,
     variables: &mut Vec<String>,
     break_pos: &mut Vec<usize>,
     continue_pos: &mut Vec<usize>,
     byte_codes: &mut Vec<ByteCode>,
     expr: &Expr,
-) -> anyhow::Result<()> {
+) -> Result<()> {
+    let start = byte_codes.len();
     match expr {
+        Expr::DynamicConstant(dynamic, _) if dynamic.is_fnptr() => {
+            let fn_ptr = dynamic.clone().cast::<rhai::FnPtr>();
+            if fn_ptr.is_curried() {
+                return Err(ErrorKind::Unsupported("Constant curried function pointer".to_string()).into());
+            }
+            byte_codes.push(ByteCode::FnPtr(fn_ptr.fn_name().to_string()));
+        }
         Expr::DynamicConstant(dynamic, _) => {
             byte_codes.push(ByteCode::DynamicConstant(DynamicConstant::from_dynamic(dynamic)?));
         }
@@ -330,75 +828,85 @@ fn append_expr(
             }
             byte_codes.push(ByteCode::ConstructArray(thin_vec.len() as SIZE));
         }
-        Expr::Map(..) => {
-            anyhow::bail!("Map not supported yet!");
+        Expr::Map(data, _) => {
+            let mut keys = VEC::with_capacity(data.0.len());
+            for (key, sub_expr) in &data.0 {
+                append_expr(
+                    functions,
+                    variables,
+                    break_pos,
+                    continue_pos,
+                    byte_codes,
+                    sub_expr,
+                )?;
+                keys.push(key.as_str().to_string());
+            }
+            byte_codes.push(ByteCode::ConstructMap(keys));
         }
         Expr::Unit(..) => {
             byte_codes.push(ByteCode::UnitConstant);
         }
         Expr::Variable(data, _, _) => {
-            let var_id = find_index(variables, data.1.as_str(), "variable")?;
+            let var_id = find_index(variables, data.1.as_str(), ErrorKind::UndefinedVariable)?;
             byte_codes.push(ByteCode::Variable(var_id));
         }
         Expr::ThisPtr(..) => {
-            anyhow::bail!("\"this\" pointer not supported yet!");
+            return Err(ErrorKind::Unsupported("\"this\" pointer".to_string()).into());
         }
         Expr::Property(..) => {
-            anyhow::bail!("Property not supported yet!");
+            return Err(ErrorKind::Unsupported("Property".to_string()).into());
         }
         Expr::MethodCall(..) => {
-            anyhow::bail!("Method not supported yet!");
+            return Err(ErrorKind::Compile("Method call without an object!".to_string()).into());
         }
-        Expr::Stmt(stmt_block) => {
-            for stmt in stmt_block.iter() {
-                append_stmt(
-                    functions,
-                    variables,
-                    break_pos,
-                    continue_pos,
-                    byte_codes,
-                    stmt,
-                )?;
+        Expr::Stmt(stmt_block) => match stmt_block.statements() {
+            // Rhai creates a closure by sharing the captured variables, then currying them.
+            #[cfg(not(feature = "no_function"))]
+            [Stmt::Share(..), Stmt::FnCall(fn_call_expr, _)] if fn_call_expr.name == "curry" && !fn_call_expr.args.is_empty() => {
+                for sub_expr in &fn_call_expr.args {
+                    append_expr(
+                        functions,
+                        variables,
+                        break_pos,
+                        continue_pos,
+                        byte_codes,
+                        sub_expr,
+                    )?;
+                }
+                byte_codes.push(ByteCode::Closure((fn_call_expr.args.len() - 1) as SIZE));
             }
-        }
-        Expr::FnCall(fn_call_expr, _) => {
-            for sub_expr in &fn_call_expr.args {
-                append_expr(
+            stmts => {
+                append_block(
                     functions,
                     variables,
                     break_pos,
                     continue_pos,
                     byte_codes,
-                    sub_expr,
+                    stmts,
+                    true,
                 )?;
             }
-            let fn_id = find_index(functions, fn_call_expr.name.as_str(), "function")?;
-            byte_codes.push(ByteCode::FnCall(fn_id, fn_call_expr.args.len() as SIZE));
-        }
-        Expr::Dot(..) => {
-            anyhow::bail!("Dot operator (.) not supported yet!");
-        }
-        Expr::Index(binary_expr, astflags, _) => {
-            if (*astflags & rhai::ASTFlags::NEGATED) == rhai::ASTFlags::NEGATED {
-                anyhow::bail!("Operator (?[]) not supported yet!");
-            }
-            append_expr(
+        },
+        Expr::FnCall(fn_call_expr, _) => {
+            append_fn_call(
                 functions,
                 variables,
                 break_pos,
                 continue_pos,
                 byte_codes,
-                &binary_expr.lhs,
+                fn_call_expr,
             )?;
-            append_expr(
+        }
+        Expr::Dot(..) | Expr::Index(..) => {
+            append_chain_root(
                 functions,
                 variables,
                 break_pos,
                 continue_pos,
                 byte_codes,
-                &binary_expr.rhs,
+                expr,
+                false,
             )?;
-            byte_codes.push(ByteCode::Index);
         }
         Expr::And(binary_expr, _) => {
             append_expr(
@@ -464,23 +972,242 @@ fn append_expr(
             byte_codes[pos] = ByteCode::JumpIfNotNull(byte_codes.len() as SIZE);
         }
         // Expr::Custom(..) => {
-        //     anyhow::bail!("Custom syntax not supported yet!");
+        //     return Err(ErrorKind::Unsupported("Custom syntax".to_string()).into());
         // }
         _ => {
-            anyhow::bail!("Unknown expression type for \"{:?}\"!", expr);
+            return Err(ErrorKind::Compile(format!("Unknown expression type for \"{:?}\"!", expr)).into());
+        }
+    }
+    record_position(start, byte_codes.len(), expr.position());
+    return Ok(());
+}
+
+fn check_chain_flags(astflags: rhai::ASTFlags, is_dot: bool) -> Result<()> {
+    if (astflags & rhai::ASTFlags::NEGATED) == rhai::ASTFlags::NEGATED {
+        if is_dot {
+            return Err(ErrorKind::Unsupported("Operator (?.)".to_string()).into());
+        } else {
+            return Err(ErrorKind::Unsupported("Operator (?[])".to_string()).into());
+        }
+    }
+    return Ok(());
+}
+
+// Appends the root of a dot/index chain such as "a.b[3].c".
+// With `keep_last`, the last link is not appended but returned, for assignments to handle it.
+fn append_chain_root<'a>(
+    functions: &FunctionNames,
+    variables: &mut Vec<String>,
+    break_pos: &mut Vec<usize>,
+    continue_pos: &mut Vec<usize>,
+    byte_codes: &mut Vec<ByteCode>,
+    expr: &'a Expr,
+    keep_last: bool,
+) -> Result<Option<(bool, &'a Expr)>> {
+    let (binary_expr, astflags, is_dot) = match expr {
+        Expr::Dot(binary_expr, astflags, _) => (binary_expr, *astflags, true),
+        Expr::Index(binary_expr, astflags, _) => (binary_expr, *astflags, false),
+        _ => {
+            return Err(ErrorKind::Compile(format!("Expression \"{:?}\" is not a dot or index chain!", expr)).into());
+        }
+    };
+    check_chain_flags(astflags, is_dot)?;
+    append_expr(
+        functions,
+        variables,
+        break_pos,
+        continue_pos,
+        byte_codes,
+        &binary_expr.lhs,
+    )?;
+    return append_chain(
+        functions,
+        variables,
+        break_pos,
+        continue_pos,
+        byte_codes,
+        is_dot,
+        astflags,
+        &binary_expr.rhs,
+        keep_last,
+    );
+}
+
+// Rhai nests chains to the right, the rhs of a link continues the chain unless the link has the BREAK flag.
+#[allow(clippy::too_many_arguments)] // The state threaded through every append function, plus the link and its parent.
+fn append_chain<'a>(
+    functions: &FunctionNames,
+    variables: &mut Vec<String>,
+    break_pos: &mut Vec<usize>,
+    continue_pos: &mut Vec<usize>,
+    byte_codes: &mut Vec<ByteCode>,
+    is_dot: bool,
+    parent_astflags: rhai::ASTFlags,
+    expr: &'a Expr,
+    keep_last: bool,
+) -> Result<Option<(bool, &'a Expr)>> {
+    let (binary_expr, astflags, sub_is_dot) = match expr {
+        Expr::Dot(binary_expr, astflags, _) if (parent_astflags & rhai::ASTFlags::BREAK) != rhai::ASTFlags::BREAK => {
+            (binary_expr, *astflags, true)
+        }
+        Expr::Index(binary_expr, astflags, _) if (parent_astflags & rhai::ASTFlags::BREAK) != rhai::ASTFlags::BREAK => {
+            (binary_expr, *astflags, false)
+        }
+        _ => {
+            if keep_last {
+                return Ok(Some((is_dot, expr)));
+            }
+            append_chain_link(
+                functions,
+                variables,
+                break_pos,
+                continue_pos,
+                byte_codes,
+                is_dot,
+                expr,
+            )?;
+            return Ok(None);
+        }
+    };
+    check_chain_flags(astflags, sub_is_dot)?;
+    append_chain_link(
+        functions,
+        variables,
+        break_pos,
+        continue_pos,
+        byte_codes,
+        is_dot,
+        &binary_expr.lhs,
+    )?;
+    return append_chain(
+        functions,
+        variables,
+        break_pos,
+        continue_pos,
+        byte_codes,
+        sub_is_dot,
+        astflags,
+        &binary_expr.rhs,
+        keep_last,
+    );
+}
+
+// Appends a single ".prop" or "[index]" link, the object is on the top of the stack.
+fn append_chain_link(
+    functions: &FunctionNames,
+    variables: &mut Vec<String>,
+    break_pos: &mut Vec<usize>,
+    continue_pos: &mut Vec<usize>,
+    byte_codes: &mut Vec<ByteCode>,
+    is_dot: bool,
+    expr: &Expr,
+) -> Result<()> {
+    if is_dot {
+        match expr {
+            Expr::Property(data, _) => {
+                // Host getters are registered like Rhai does, e.g. "get$name".
+                match functions.fns.iter().rposition(|x| x == data.0.0.as_str()) {
+                    Some(fn_id) => {
+                        byte_codes.push(ByteCode::FnCall(fn_id as SIZE, 1));
+                    }
+                    None => {
+                        byte_codes.push(ByteCode::GetProperty(data.2.to_string()));
+                    }
+                }
+            }
+            Expr::MethodCall(fn_call_expr, _) => {
+                for sub_expr in &fn_call_expr.args {
+                    append_expr(
+                        functions,
+                        variables,
+                        break_pos,
+                        continue_pos,
+                        byte_codes,
+                        sub_expr,
+                    )?;
+                }
+                let arg_count = (fn_call_expr.args.len() + 1) as SIZE;
+                let name = fn_call_expr.name.as_str();
+                if name == "call" {
+                    byte_codes.push(ByteCode::CallPtr(arg_count - 1));
+                } else if name == "curry" {
+                    byte_codes.push(ByteCode::Curry(arg_count - 1));
+                } else {
+                    // Like Rhai, a function can also be called in method style when there is no such method.
+                    match functions.methods.iter().rposition(|x| x == name) {
+                        Some(method_id) => {
+                            byte_codes.push(ByteCode::MethodCall(method_id as SIZE, arg_count));
+                        }
+                        None => match functions.fns.iter().rposition(|x| x == name) {
+                            Some(fn_id) => {
+                                byte_codes.push(ByteCode::FnCall(fn_id as SIZE, arg_count));
+                            }
+                            None => {
+                                return Err(ErrorKind::UndefinedMethod(name.to_string()).into());
+                            }
+                        },
+                    }
+                }
+            }
+            _ => {
+                return Err(ErrorKind::Compile(format!("Unsupported expression \"{:?}\" after the dot operator (.)!", expr)).into());
+            }
         }
+    } else {
+        append_expr(
+            functions,
+            variables,
+            break_pos,
+            continue_pos,
+            byte_codes,
+            expr,
+        )?;
+        byte_codes.push(ByteCode::Index);
+    }
+    return Ok(());
+}
+
+// Appends statements, whose value is the value of the last one.
+fn append_block(
+    functions: &FunctionNames,
+    variables: &mut Vec<String>,
+    break_pos: &mut Vec<usize>,
+    continue_pos: &mut Vec<usize>,
+    byte_codes: &mut Vec<ByteCode>,
+    stmts: &[Stmt],
+    keep_value: bool,
+) -> Result<()> {
+    if stmts.is_empty() && keep_value {
+        byte_codes.push(ByteCode::UnitConstant);
+    }
+    for (i, stmt) in stmts.iter().enumerate() {
+        append_stmt(
+            functions,
+            variables,
+            break_pos,
+            continue_pos,
+            byte_codes,
+            stmt,
+            keep_value && i + 1 == stmts.len(),
+        )?;
     }
     return Ok(());
 }
 
+// Appends a statement, which leaves its value on the stack only if `keep_value`,
+// so that every path through a loop or a branch leaves the stack as deep.
 fn append_stmt(
-    functions: &Vec<String>,
+    functions: &FunctionNames,
     variables: &mut Vec<String>,
     break_pos: &mut Vec<usize>,
     continue_pos: &mut Vec<usize>,
     byte_codes: &mut Vec<ByteCode>,
     stmt: &Stmt,
-) -> anyhow::Result<()> {
+    keep_value: bool,
+) -> Result<()> {
+    let start = byte_codes.len();
+    // Whether the statement has pushed its value.
+    let mut has_value = false;
     match stmt {
         Stmt::Noop(_) => {
             //Do nothing.
@@ -497,40 +1224,177 @@ fn append_stmt(
             let jz_pos = byte_codes.len();
             byte_codes.push(ByteCode::JumpIfFalse(0));
             let var_len=variables.len();
-            for sub_stmt in &flow_control.body {
-                append_stmt(
-                    functions,
-                    variables,
-                    break_pos,
-                    continue_pos,
-                    byte_codes,
-                    sub_stmt,
-                )?;
-            }
+            append_block(
+                functions,
+                variables,
+                break_pos,
+                continue_pos,
+                byte_codes,
+                flow_control.body.statements(),
+                keep_value,
+            )?;
             variables.truncate(var_len);
-            if flow_control.branch.is_empty() {
+            if flow_control.branch.is_empty() && !keep_value {
                 byte_codes[jz_pos] = ByteCode::JumpIfFalse(byte_codes.len() as SIZE);
             }else{
                 let jmp_pos = byte_codes.len();
                 byte_codes.push(ByteCode::Jump(0));
                 byte_codes[jz_pos] = ByteCode::JumpIfFalse(byte_codes.len() as SIZE);
                 let var_len=variables.len();
-                for sub_stmt in &flow_control.branch {
-                    append_stmt(
-                        functions,
-                        variables,
-                        break_pos,
-                        continue_pos,
-                        byte_codes,
-                        sub_stmt,
-                    )?;
-                }
-                variables.truncate(var_len);
+                append_block(
+                    functions,
+                    variables,
+                    break_pos,
+                    continue_pos,
+                    byte_codes,
+                    flow_control.branch.statements(),
+                    keep_value,
+                )?;
+                variables.truncate(var_len);
                 byte_codes[jmp_pos] = ByteCode::Jump(byte_codes.len() as SIZE);
             }
+            has_value = keep_value;
         }
-        Stmt::Switch(..) => {
-            anyhow::bail!("\"switch\" not supported yet!");
+        Stmt::Switch(data, _) => {
+            let (expr, cases) = data.as_ref();
+            append_expr(
+                functions,
+                variables,
+                break_pos,
+                continue_pos,
+                byte_codes,
+                expr,
+            )?;
+            let var_len = variables.len();
+            let range_indices = cases.ranges.iter().map(|range| match range {
+                rhai::RangeCase::ExclusiveInt(_, index) => *index,
+                rhai::RangeCase::InclusiveInt(_, index) => *index,
+            }).collect::<Vec<_>>();
+            // A range case whose condition fails falls through to the next matching range, so keep the value.
+            let has_range_condition = range_indices.iter().any(|index| {
+                !matches!(cases.expressions[*index].lhs, Expr::BoolConstant(true, _))
+            });
+            let switch_value_id = if has_range_condition {
+                let var_id = append_return_index(variables, "(switch_value)");
+                byte_codes.push(ByteCode::VarInit(var_id));
+                var_id
+            } else {
+                0
+            };
+            let switch_pos = byte_codes.len();
+            byte_codes.push(ByteCode::UnitConstant);
+            // Jumps to be patched: to a case body, to the default case, and to the end.
+            let mut body_jumps = Vec::<(usize, usize)>::new();
+            let mut default_jumps = Vec::<usize>::new();
+            let mut end_jumps = Vec::<usize>::new();
+            let mut append_conditions = |indices: &[usize], byte_codes: &mut Vec<ByteCode>, variables: &mut Vec<String>| -> Result<bool> {
+                for index in indices {
+                    match &cases.expressions[*index].lhs {
+                        Expr::BoolConstant(true, _) => {
+                            body_jumps.push((byte_codes.len(), *index));
+                            byte_codes.push(ByteCode::Jump(0));
+                            return Ok(true);
+                        }
+                        Expr::BoolConstant(false, _) => {}
+                        condition => {
+                            append_expr(
+                                functions,
+                                variables,
+                                break_pos,
+                                continue_pos,
+                                byte_codes,
+                                condition,
+                            )?;
+                            body_jumps.push((byte_codes.len(), *index));
+                            byte_codes.push(ByteCode::JumpIfTrue(0));
+                        }
+                    }
+                }
+                return Ok(false);
+            };
+            let mut table_cases = VEC::with_capacity(cases.cases.len());
+            // Keep the order of the cases in the script.
+            let mut literal_cases = cases.cases.iter().collect::<Vec<_>>();
+            literal_cases.sort_by_key(|(_, indices)| indices[0]);
+            for (hash, indices) in literal_cases {
+                // Rhai also hashes the range of a range case, which only matches range values.
+                if indices.iter().all(|index| range_indices.contains(index)) {
+                    continue;
+                }
+                check_switch_case_hash()?;
+                table_cases.push((*hash, byte_codes.len() as SIZE));
+                if !append_conditions(indices, byte_codes, variables)? {
+                    default_jumps.push(byte_codes.len());
+                    byte_codes.push(ByteCode::Jump(0));
+                }
+            }
+            let mut table_ranges = VEC::with_capacity(cases.ranges.len());
+            let mut fall_through_pos = Vec::<usize>::new();
+            for range in cases.ranges.iter() {
+                let (start, len, index) = match range {
+                    rhai::RangeCase::ExclusiveInt(r, index) => (r.start, r.end - r.start, *index),
+                    rhai::RangeCase::InclusiveInt(r, index) => (*r.start(), *r.end() - *r.start() + 1, *index),
+                };
+                table_ranges.push((start, len, byte_codes.len() as SIZE));
+                if !append_conditions(&[index], byte_codes, variables)? {
+                    byte_codes.push(ByteCode::Variable(switch_value_id));
+                    fall_through_pos.push(byte_codes.len());
+                    byte_codes.push(ByteCode::UnitConstant);
+                } else {
+                    fall_through_pos.push(usize::MAX);
+                }
+            }
+            let mut body_addresses = vec![usize::MAX; cases.expressions.len()];
+            for (index, case) in cases.expressions.iter().enumerate() {
+                body_addresses[index] = byte_codes.len();
+                append_expr(
+                    functions,
+                    variables,
+                    break_pos,
+                    continue_pos,
+                    byte_codes,
+                    &case.rhs,
+                )?;
+                variables.truncate(var_len + has_range_condition as usize);
+                end_jumps.push(byte_codes.len());
+                byte_codes.push(ByteCode::Jump(0));
+            }
+            let default_pos = match cases.def_case {
+                Some(index) => body_addresses[index],
+                None => {
+                    byte_codes.push(ByteCode::UnitConstant);
+                    byte_codes.len() - 1
+                }
+            } as SIZE;
+            variables.truncate(var_len);
+            let end_pos = byte_codes.len() as SIZE;
+            for (pos, index) in body_jumps {
+                byte_codes[pos] = match byte_codes[pos] {
+                    ByteCode::JumpIfTrue(_) => ByteCode::JumpIfTrue(body_addresses[index] as SIZE),
+                    _ => ByteCode::Jump(body_addresses[index] as SIZE),
+                };
+            }
+            for pos in default_jumps {
+                byte_codes[pos] = ByteCode::Jump(default_pos);
+            }
+            for pos in end_jumps {
+                byte_codes[pos] = ByteCode::Jump(end_pos);
+            }
+            for (i, pos) in fall_through_pos.iter().enumerate() {
+                if *pos != usize::MAX {
+                    byte_codes[*pos] = ByteCode::Switch(Box::new(SwitchTable {
+                        cases: VEC::new(),
+                        ranges: table_ranges.iter().skip(i + 1).cloned().collect(),
+                        default: default_pos,
+                    }));
+                }
+            }
+            byte_codes[switch_pos] = ByteCode::Switch(Box::new(SwitchTable {
+                cases: table_cases,
+                ranges: table_ranges,
+                default: default_pos,
+            }));
+            has_value = true;
         }
         Stmt::While(flow_control, _) => {
             let start_pos = byte_codes.len();
@@ -552,16 +1416,15 @@ fn append_stmt(
             let mut new_break_pos = Vec::<usize>::new();
             let mut new_continue_pos = Vec::<usize>::new();
             let var_len=variables.len();
-            for sub_stmt in &flow_control.body {
-                append_stmt(
-                    functions,
-                    variables,
-                    &mut new_break_pos,
-                    &mut new_continue_pos,
-                    byte_codes,
-                    sub_stmt,
-                )?;
-            }
+            append_block(
+                functions,
+                variables,
+                &mut new_break_pos,
+                &mut new_continue_pos,
+                byte_codes,
+                flow_control.body.statements(),
+                false,
+            )?;
             variables.truncate(var_len);
             byte_codes.push(ByteCode::Jump(start_pos as SIZE));
             let end_pos = byte_codes.len();
@@ -580,16 +1443,15 @@ fn append_stmt(
             let mut new_break_pos = Vec::<usize>::new();
             let mut new_continue_pos = Vec::<usize>::new();
             let var_len=variables.len();
-            for sub_stmt in &flow_control.body {
-                append_stmt(
-                    functions,
-                    variables,
-                    &mut new_break_pos,
-                    &mut new_continue_pos,
-                    byte_codes,
-                    sub_stmt,
-                )?;
-            }
+            append_block(
+                functions,
+                variables,
+                &mut new_break_pos,
+                &mut new_continue_pos,
+                byte_codes,
+                flow_control.body.statements(),
+                false,
+            )?;
             let compare_pos = byte_codes.len();
             append_expr(
                 functions,
@@ -638,16 +1500,15 @@ fn append_stmt(
             let mut new_break_pos = Vec::<usize>::new();
             let mut new_continue_pos = Vec::<usize>::new();
             let var_len=variables.len();
-            for sub_stmt in &data.2.body {
-                append_stmt(
-                    functions,
-                    variables,
-                    &mut new_break_pos,
-                    &mut new_continue_pos,
-                    byte_codes,
-                    sub_stmt,
-                )?;
-            }
+            append_block(
+                functions,
+                variables,
+                &mut new_break_pos,
+                &mut new_continue_pos,
+                byte_codes,
+                data.2.body.statements(),
+                false,
+            )?;
             variables.truncate(var_len);
             byte_codes.push(ByteCode::Jump(start_pos as SIZE));
             let end_pos = byte_codes.len();
@@ -673,61 +1534,165 @@ fn append_stmt(
             byte_codes.push(ByteCode::PopStack);
         }
         Stmt::Assignment(data) => {
-            append_expr(
-                functions,
-                variables,
-                break_pos,
-                continue_pos,
-                byte_codes,
-                &data.1.lhs,
-            )?;
-            append_expr(
-                functions,
-                variables,
-                break_pos,
-                continue_pos,
-                byte_codes,
-                &data.1.rhs,
-            )?;
             let op_str = match data.0.get_op_assignment_info() {
                 Some(info) => info.3,
                 None => "=",
             };
-            let op_id = find_index(functions, op_str, "assignment operator")?;
-            byte_codes.push(ByteCode::FnCall(op_id, 2));
-            byte_codes.push(ByteCode::PopStack);
-        }
-        Stmt::FnCall(fn_call_expr, _) => {
-            for sub_expr in &fn_call_expr.args {
-                append_expr(
+            let last_link = match &data.1.lhs {
+                Expr::Dot(..) | Expr::Index(..) => append_chain_root(
                     functions,
                     variables,
                     break_pos,
                     continue_pos,
                     byte_codes,
-                    sub_expr,
-                )?;
-            }
-            let fn_id = find_index(functions, fn_call_expr.name.as_str(), "function")?;
-            byte_codes.push(ByteCode::FnCall(fn_id, fn_call_expr.args.len() as SIZE));
-            byte_codes.push(ByteCode::PopStack);
-        }
-        Stmt::Block(stmt_block) => {
-            let var_len=variables.len();
-            for stmt in stmt_block.iter() {
-                append_stmt(
+                    &data.1.lhs,
+                    true,
+                )?,
+                lhs => {
+                    append_expr(
+                        functions,
+                        variables,
+                        break_pos,
+                        continue_pos,
+                        byte_codes,
+                        lhs,
+                    )?;
+                    None
+                }
+            };
+            // Setting a property may add it, so it cannot go through an assignable reference.
+            let set_property = match last_link {
+                Some((true, Expr::Property(prop, _))) => {
+                    match functions.fns.iter().rposition(|x| x == prop.1.0.as_str()) {
+                        Some(_) if op_str != "=" => {
+                            return Err(ErrorKind::Unsupported(format!("Operator \"{}\" on property \"{}\" with a host setter", op_str, prop.2)).into());
+                        }
+                        Some(fn_id) => Some(ByteCode::FnCall(fn_id as SIZE, 2)),
+                        None if op_str == "=" => Some(ByteCode::SetProperty(prop.2.to_string())),
+                        None => None,
+                    }
+                }
+                Some((false, Expr::StringConstant(key, _))) if op_str == "=" => {
+                    Some(ByteCode::SetProperty(key.to_string()))
+                }
+                _ => None,
+            };
+            if let (Some((is_dot, link)), None) = (last_link, &set_property) {
+                append_chain_link(
                     functions,
                     variables,
                     break_pos,
                     continue_pos,
                     byte_codes,
-                    stmt,
+                    is_dot,
+                    link,
                 )?;
             }
+            append_expr(
+                functions,
+                variables,
+                break_pos,
+                continue_pos,
+                byte_codes,
+                &data.1.rhs,
+            )?;
+            match set_property {
+                Some(byte_code @ ByteCode::SetProperty(_)) => {
+                    byte_codes.push(byte_code);
+                }
+                Some(byte_code) => {
+                    byte_codes.push(byte_code);
+                    byte_codes.push(ByteCode::PopStack);
+                }
+                None => {
+                    let op_id = find_index(functions.fns, op_str, ErrorKind::UndefinedFunction)?;
+                    byte_codes.push(ByteCode::FnCall(op_id, 2));
+                    byte_codes.push(ByteCode::PopStack);
+                }
+            }
+        }
+        Stmt::FnCall(fn_call_expr, _) => {
+            append_fn_call(
+                functions,
+                variables,
+                break_pos,
+                continue_pos,
+                byte_codes,
+                fn_call_expr,
+            )?;
+            has_value = true;
+        }
+        Stmt::Block(stmt_block) => {
+            let var_len=variables.len();
+            append_block(
+                functions,
+                variables,
+                break_pos,
+                continue_pos,
+                byte_codes,
+                stmt_block.statements(),
+                keep_value,
+            )?;
             variables.truncate(var_len);
+            has_value = keep_value;
         }
-        Stmt::TryCatch(..) => {
-            anyhow::bail!("\"try\" not supported yet!");
+        Stmt::TryCatch(flow_control, _) => {
+            let handler_pos = byte_codes.len();
+            byte_codes.push(ByteCode::PushHandler(0));
+            let mut new_break_pos = Vec::<usize>::new();
+            let mut new_continue_pos = Vec::<usize>::new();
+            let var_len=variables.len();
+            append_block(
+                functions,
+                variables,
+                &mut new_break_pos,
+                &mut new_continue_pos,
+                byte_codes,
+                flow_control.body.statements(),
+                keep_value,
+            )?;
+            variables.truncate(var_len);
+            byte_codes.push(ByteCode::PopHandler);
+            let jmp_pos = byte_codes.len();
+            byte_codes.push(ByteCode::Jump(0));
+            // Leaving the "try" block by "break" or "continue" must drop its handler as well.
+            if !new_break_pos.is_empty() {
+                let stub_pos = byte_codes.len();
+                byte_codes.push(ByteCode::PopHandler);
+                break_pos.push(byte_codes.len());
+                byte_codes.push(ByteCode::Jump(0));
+                for pos_break in &new_break_pos {
+                    byte_codes[*pos_break] = ByteCode::Jump(stub_pos as SIZE);
+                }
+            }
+            if !new_continue_pos.is_empty() {
+                let stub_pos = byte_codes.len();
+                byte_codes.push(ByteCode::PopHandler);
+                continue_pos.push(byte_codes.len());
+                byte_codes.push(ByteCode::Jump(0));
+                for pos_continue in &new_continue_pos {
+                    byte_codes[*pos_continue] = ByteCode::Jump(stub_pos as SIZE);
+                }
+            }
+            byte_codes[handler_pos] = ByteCode::PushHandler(byte_codes.len() as SIZE);
+            // The thrown value is on the stack when entering the "catch" block.
+            if let Expr::Variable(data, _, _) = &flow_control.expr {
+                let var_id = append_return_index(variables, data.1.as_str());
+                byte_codes.push(ByteCode::VarInit(var_id));
+            }
+            byte_codes.push(ByteCode::PopStack);
+            append_block(
+                functions,
+                variables,
+                break_pos,
+                continue_pos,
+                byte_codes,
+                flow_control.branch.statements(),
+                keep_value,
+            )?;
+            variables.truncate(var_len);
+            byte_codes[jmp_pos] = ByteCode::Jump(byte_codes.len() as SIZE);
+            has_value = keep_value;
         }
         Stmt::Expr(expr) => {
             append_expr(
@@ -738,6 +1703,7 @@ fn append_stmt(
                 byte_codes,
                 expr,
             )?;
+            has_value = true;
         }
         Stmt::BreakLoop(_, astflags, _) => {
             if (*astflags & rhai::ASTFlags::BREAK) == rhai::ASTFlags::BREAK {
@@ -750,171 +1716,1060 @@ fn append_stmt(
             byte_codes.push(ByteCode::Jump(0));
         }
         Stmt::Return(expr, astflags, _) => {
+            match expr {
+                Some(exp) => {
+                    append_expr(
+                        functions,
+                        variables,
+                        break_pos,
+                        continue_pos,
+                        byte_codes,
+                        exp,
+                    )?;
+                }
+                None => {
+                    byte_codes.push(ByteCode::UnitConstant);
+                }
+            }
             if (*astflags & rhai::ASTFlags::BREAK) == rhai::ASTFlags::BREAK {
-                anyhow::bail!("\"throw\" not supported yet!");
+                //throw
+                byte_codes.push(ByteCode::Throw);
             } else {
-                match expr {
-                    Some(exp) => {
-                        append_expr(
-                            functions,
-                            variables,
-                            break_pos,
-                            continue_pos,
-                            byte_codes,
-                            exp,
-                        )?;
-                    }
-                    None => {
-                        byte_codes.push(ByteCode::UnitConstant);
-                    }
-                }
                 byte_codes.push(ByteCode::Return);
             }
         }
+        #[cfg(not(feature = "no_function"))]
+        Stmt::Share(..) => {
+            // Variables are already shared cells.
+        }
         // Stmt::Import(..) => todo!(),
         // Stmt::Export(..) => todo!(),
-        // Stmt::Share(..) => todo!(),
         _ => {
-            anyhow::bail!("Unknown statement type for \"{:?}\"!", stmt);
+            return Err(ErrorKind::Compile(format!("Unknown statement type for \"{:?}\"!", stmt)).into());
         }
     }
+    if keep_value && !has_value {
+        byte_codes.push(ByteCode::UnitConstant);
+    } else if !keep_value && has_value {
+        byte_codes.push(ByteCode::PopStack);
+    }
+    record_position(start, byte_codes.len(), stmt.position());
     return Ok(());
 }
 
-fn trace_jump(init_pos:SIZE,byte_codes: &Vec<ByteCode>)->SIZE {
-    let init_pos_sz= init_pos as usize;
-    if init_pos_sz < byte_codes.len() {
-        match byte_codes[init_pos_sz] {
-            ByteCode::Jump(pos) => {
-                return trace_jump(pos, byte_codes);
+// Sets the position of the byte codes from `start` to `end` not set by inner expressions.
+fn record_position(start: usize, end: usize, position: rhai::Position) {
+    let (Some(line), Some(column)) = (position.line(), position.position()) else {
+        return;
+    };
+    SOURCE_POSITIONS.with_borrow_mut(|positions| {
+        if positions.len() < end {
+            positions.resize(end, None);
+        }
+        for slot in positions[start..end].iter_mut() {
+            slot.get_or_insert(SourcePosition {
+                line: line as u32,
+                column: column as u32,
+            });
+        }
+    });
+}
+
+// Calls `f` on every jump target of the byte code, not including function addresses of `ByteCode::Call`.
+fn for_each_jump_target(byte_code: &mut ByteCode, mut f: impl FnMut(&mut SIZE)) {
+    match byte_code {
+        ByteCode::Jump(pos)
+        | ByteCode::JumpIfTrue(pos)
+        | ByteCode::JumpIfFalse(pos)
+        | ByteCode::JumpIfNotNull(pos)
+        | ByteCode::PushHandler(pos)
+        | ByteCode::Iter(_, _, _, pos)
+        | ByteCode::JumpIfFalseVarInt(_, _, _, pos) => {
+            f(pos);
+        }
+        ByteCode::Switch(table) => {
+            for (_, pos) in table.cases.iter_mut() {
+                f(pos);
             }
-            _=>{}
+            for (_, _, pos) in table.ranges.iter_mut() {
+                f(pos);
+            }
+            f(&mut table.default);
+        }
+        _ => {}
+    }
+}
+
+fn trace_jump(init_pos:SIZE,byte_codes: &[ByteCode])->SIZE {
+    let mut pos=init_pos;
+    // Jumps can form a cycle, e.g. "loop {}", which is left as it is.
+    for _i in 0..byte_codes.len() {
+        match byte_codes.get(pos as usize) {
+            Some(ByteCode::Jump(target)) => pos = *target,
+            _=> return pos,
         }
     }
     return init_pos;
 }
 
+// Number of variable slots used by the byte codes.
+fn count_variables(byte_codes: &[ByteCode]) -> Result<SIZE> {
+    let mut count=0 as SIZE;
+    let mut add = |var_id: &SIZE| -> Result<()> {
+        match var_id.checked_add(1) {
+            Some(slots) => {
+                count=count.max(slots);
+                return Ok(());
+            }
+            None => {
+                return Err(ErrorKind::InvalidByteCode(format!("Variable #{} is out of range!", var_id)).into());
+            }
+        }
+    };
+    for byte_code in byte_codes {
+        match byte_code {
+            ByteCode::Variable(var_id)
+            | ByteCode::VarInit(var_id)
+            | ByteCode::VarStore(var_id)
+            | ByteCode::FnCallVarInt(_, var_id, _)
+            | ByteCode::JumpIfFalseVarInt(_, var_id, _, _) => {
+                add(var_id)?;
+            }
+            ByteCode::FnCallVarVar(_, var_id_a, var_id_b) => {
+                add(var_id_a)?;
+                add(var_id_b)?;
+            }
+            ByteCode::Iter(loop_range_id,loop_index_id,loop_var_id,_) => {
+                add(loop_range_id)?;
+                add(loop_index_id)?;
+                add(loop_var_id)?;
+            }
+            _=>{}
+        }
+    }
+    return Ok(count);
+}
+
+// Number of byte codes of the main script, which script functions follow.
+fn main_script_len(byte_codes: &[ByteCode]) -> usize {
+    return byte_codes.iter().position(|byte_code| matches!(byte_code, ByteCode::Enter(..))).unwrap_or(byte_codes.len());
+}
+
+// Appends the statements of the main script or a function, leaving the value of the last one.
+fn append_stmts(
+    functions: &FunctionNames,
+    variables: &mut Vec<String>,
+    byte_codes: &mut Vec<ByteCode>,
+    stmts: &[Stmt],
+) -> Result<()> {
+    let mut break_pos = Vec::<usize>::new();
+    let mut continue_pos = Vec::<usize>::new();
+    append_block(
+        functions,
+        variables,
+        &mut break_pos,
+        &mut continue_pos,
+        byte_codes,
+        stmts,
+        true,
+    )?;
+    if !break_pos.is_empty() || !continue_pos.is_empty() {
+        return Err(ErrorKind::BreakOutsideLoop.into());
+    }
+    return Ok(());
+}
+
 pub fn ast_to_byte_codes<B: DynamicValue+std::fmt::Debug>(
     executer: &Executer<B>,
     initial_variables: &mut Vec<String>,
     ast: &rhai::AST,
-) -> anyhow::Result<Vec<ByteCode>> {
-    let functions = executer.function_names();
-    let mut byte_codes = Vec::<ByteCode>::new();
-    let mut break_pos = Vec::<usize>::new();
-    let mut continue_pos = Vec::<usize>::new();
-    for stmt in ast.statements() {
-        append_stmt(
-            functions,
-            initial_variables,
-            &mut break_pos,
-            &mut continue_pos,
-            &mut byte_codes,
-            stmt,
-        )?;
-    }
-    if !break_pos.is_empty() || !continue_pos.is_empty() {
-        anyhow::bail!("Invalid \"break\" or \"continue\" statements without a loop!");
+) -> Result<Vec<ByteCode>> {
+    let (byte_codes, _) = compile(executer, initial_variables, ast)?;
+    return Ok(byte_codes);
+}
+
+// Addresses where the source position of the byte codes changes, like `CompiledProgram::positions`.
+type PositionTable = Vec<(SIZE,SourcePosition)>;
+
+// Compiles to byte codes, along with the table of their source positions.
+fn compile<B: DynamicValue+std::fmt::Debug>(
+    executer: &Executer<B>,
+    initial_variables: &mut Vec<String>,
+    ast: &rhai::AST,
+) -> Result<(Vec<ByteCode>, PositionTable)> {
+    SOURCE_POSITIONS.with_borrow_mut(|positions| positions.clear());
+    #[allow(unused_mut)] // Not mutated with "no_function".
+    let mut functions = executer.function_names();
+    #[cfg(not(feature = "no_function"))]
+    let fn_defs = {
+        let mut fn_defs = ast.iter_fn_def().collect::<Vec<_>>();
+        fn_defs.sort_by(|a, b| (a.name.as_str(), a.params.len()).cmp(&(b.name.as_str(), b.params.len())));
+        fn_defs
+    };
+    #[cfg(not(feature = "no_function"))]
+    for fn_def in &fn_defs {
+        functions.scripts.push((fn_def.name.to_string(), fn_def.params.len() as SIZE));
     }
-    match byte_codes.last() {
-        Some(code) => match code {
-            ByteCode::PopStack => {
-                byte_codes.pop();
+    let mut byte_codes = Vec::<ByteCode>::new();
+    append_stmts(&functions, initial_variables, &mut byte_codes, ast.statements())?;
+    #[cfg(not(feature = "no_function"))]
+    if !fn_defs.is_empty() {
+        // Script functions are placed after the main script, which has to jump over them.
+        let end_jump_pos = byte_codes.len();
+        byte_codes.push(ByteCode::Jump(0));
+        let mut fn_pos = Vec::<SIZE>::with_capacity(fn_defs.len());
+        for fn_def in &fn_defs {
+            let start = byte_codes.len();
+            fn_pos.push(start as SIZE);
+            byte_codes.push(ByteCode::Enter(String::new(), 0, 0));
+            let mut variables = fn_def.params.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+            append_stmts(&functions, &mut variables, &mut byte_codes, fn_def.body.statements())?;
+            byte_codes.push(ByteCode::Return);
+            let var_count = count_variables(&byte_codes[start..])?.max(fn_def.params.len() as SIZE);
+            byte_codes[start] = ByteCode::Enter(fn_def.name.to_string(), fn_def.params.len() as SIZE, var_count);
+        }
+        byte_codes[end_jump_pos] = ByteCode::Jump(byte_codes.len() as SIZE);
+        for byte_code in byte_codes.iter_mut() {
+            if let ByteCode::Call(script_id, arg_count) = byte_code {
+                *byte_code = ByteCode::Call(fn_pos[*script_id as usize], *arg_count);
             }
-            _ => {}
-        },
-        None => {}
+        }
     }
     for i in 0..byte_codes.len() {
-        match &byte_codes[i] {
-            ByteCode::Jump(pos) => {
-                byte_codes[i]=ByteCode::Jump(trace_jump(*pos,&byte_codes));
-            }
-            ByteCode::JumpIfTrue(pos) => {
-                byte_codes[i]=ByteCode::JumpIfTrue(trace_jump(*pos,&byte_codes));
-            }
-            ByteCode::JumpIfFalse(pos) => {
-                byte_codes[i]=ByteCode::JumpIfFalse(trace_jump(*pos,&byte_codes));
-            }
-            ByteCode::JumpIfNotNull(pos) => {
-                byte_codes[i]=ByteCode::JumpIfNotNull(trace_jump(*pos,&byte_codes));
-            }
-            ByteCode::Iter(loop_range_id,loop_index_id,loop_var_id,pos) => {
-                byte_codes[i]=ByteCode::Iter(*loop_range_id,*loop_index_id,*loop_var_id,trace_jump(*pos,&byte_codes));
+        let mut byte_code = byte_codes[i].clone();
+        for_each_jump_target(&mut byte_code, |pos| {
+            *pos = trace_jump(*pos, &byte_codes);
+        });
+        byte_codes[i] = byte_code;
+    }
+    // Address of the byte code each one was compiled as, whose source position it keeps.
+    let origins = match executer.fuse_instructions {
+        true => optimize::fuse(executer, &mut byte_codes),
+        false => (0..byte_codes.len()).collect(),
+    };
+    let positions = SOURCE_POSITIONS.with_borrow_mut(|positions| {
+        let mut table = Vec::<(SIZE,SourcePosition)>::new();
+        for (pos, origin) in origins.into_iter().enumerate() {
+            if let Some(Some(position)) = positions.get(origin).copied() {
+                if table.last().map(|(_, last)| *last) != Some(position) {
+                    table.push((pos as SIZE, position));
+                }
             }
-            _=>{}
         }
-    }
-    return Ok(byte_codes);
+        positions.clear();
+        table
+    });
+    return Ok((byte_codes, positions));
 }
 
 pub fn script_to_byte_codes<B: DynamicValue+std::fmt::Debug>(
     executer: &Executer<B>,
     initial_variables: &mut Vec<String>,
     script: &str,
-) -> anyhow::Result<Vec<ByteCode>,> {
+) -> Result<Vec<ByteCode>,> {
     let ast=COMPILE_ENGINE.with_borrow(|engine|engine.compile(script))?;
-    return ast_to_byte_codes(executer, initial_variables, &ast);
+    let (byte_codes, _) = compile(executer, initial_variables, &ast)?;
+    return Ok(byte_codes);
 }
 
 pub fn script_to_byte_codes_expression<B: DynamicValue+std::fmt::Debug>(
     executer: &Executer<B>,
     initial_variables: &mut Vec<String>,
     script: &str,
-) -> anyhow::Result<Vec<ByteCode>> {
+) -> Result<Vec<ByteCode>> {
     let ast=COMPILE_ENGINE.with_borrow(|engine|engine.compile_expression(script))?;
-    return ast_to_byte_codes(executer, initial_variables, &ast);
+    let (byte_codes, _) = compile(executer, initial_variables, &ast)?;
+    return Ok(byte_codes);
 }
 
 pub fn script_to_byte_codes_expression_no_new_variables<B: DynamicValue+std::fmt::Debug>(
     executer: &Executer<B>,
     initial_variables: &mut Vec<String>,
     script: &str,
-) -> anyhow::Result<Vec<ByteCode>> {
+) -> Result<Vec<ByteCode>> {
     let ast=COMPILE_ENGINE.with_borrow(|engine|engine.compile_expression(script))?;
     let init_len = initial_variables.len();
-    let res = ast_to_byte_codes(executer, initial_variables, &ast)?;
+    let (res, _) = compile(executer, initial_variables, &ast)?;
     if initial_variables.len() != init_len {
         initial_variables.truncate(init_len);
-        anyhow::bail!("The script should not declare new variables!");
+        return Err(ErrorKind::Compile("The script should not declare new variables!".to_string()).into());
     } else {
         return Ok(res);
     }
 }
 
+/// Version of `CompiledProgram`, checked when a program is loaded or linked.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// A host function or method used by a `CompiledProgram`.
+#[derive(Clone,Debug,serde::Serialize, serde::Deserialize)]
+pub struct Import {
+    pub name: String,
+    /// Least and most arguments it is called with.
+    pub arg_range: (SIZE,SIZE),
+}
+
+/// Compiled code bundled with everything needed to run it safely on another machine.
+///
+/// `ByteCode::FnCall` and `ByteCode::MethodCall` index the import tables rather than the `Executer`,
+/// and are resolved by name when the program is linked.
+#[derive(Clone,Debug,serde::Serialize, serde::Deserialize)]
+pub struct CompiledProgram {
+    pub version: u32,
+    pub byte_codes: Vec<ByteCode>,
+    /// Host functions called.
+    pub functions: Vec<Import>,
+    /// Host methods called.
+    pub methods: Vec<Import>,
+    /// Names of the variables of the main script, the first `initial_variable_count` of which are set by `run`.
+    pub variable_names: Vec<String>,
+    pub initial_variable_count: SIZE,
+    /// Variable slots of the main script, which `verify` checks its byte codes against.
+    pub variable_count: SIZE,
+    /// Strings, arrays and maps used by `ByteCode::Constant`.
+    pub constants: Vec<DynamicConstant>,
+    /// Source position of the byte codes from each address until the next one listed.
+    ///
+    /// Empty when built with the "no_position" feature.
+    pub positions: Vec<(SIZE,SourcePosition)>,
+}
+
+impl CompiledProgram {
+    pub fn from_ast<B: DynamicValue+std::fmt::Debug>(
+        executer: &Executer<B>,
+        initial_variables: &[String],
+        ast: &rhai::AST,
+    ) -> Result<Self> {
+        let mut variable_names = initial_variables.to_vec();
+        let (mut byte_codes, positions) = compile(executer, &mut variable_names, ast)?;
+        let variable_count = count_variables(&byte_codes[..main_script_len(&byte_codes)])?.max(initial_variables.len() as SIZE);
+        let mut functions = Vec::<Import>::new();
+        let mut methods = Vec::<Import>::new();
+        let mut constants = Vec::<DynamicConstant>::new();
+        let mut constant_ids = HashMap::<DynamicConstant,SIZE>::new();
+        for byte_code in byte_codes.iter_mut() {
+            let constant = match byte_code {
+                ByteCode::FnCall(fn_id, arg_count) => {
+                    *fn_id = add_import(&mut functions, &executer.fn_names[*fn_id as usize], *arg_count);
+                    continue;
+                }
+                ByteCode::MethodCall(method_id, arg_count) => {
+                    *method_id = add_import(&mut methods, &executer.method_names[*method_id as usize], *arg_count);
+                    continue;
+                }
+                ByteCode::FnCallVarInt(fn_id, ..) | ByteCode::FnCallVarVar(fn_id, ..) | ByteCode::JumpIfFalseVarInt(fn_id, ..) => {
+                    *fn_id = add_import(&mut functions, &executer.fn_names[*fn_id as usize], 2);
+                    continue;
+                }
+                ByteCode::StringConstant(v) => DynamicConstant::String(std::mem::take(v)),
+                ByteCode::DynamicConstant(
+                    dynamic @ (DynamicConstant::String(_) | DynamicConstant::Array(_) | DynamicConstant::Map(_)),
+                ) => std::mem::replace(dynamic, DynamicConstant::Unit),
+                _ => continue,
+            };
+            let index = *constant_ids.entry(constant).or_insert_with_key(|constant| {
+                constants.push(constant.clone());
+                (constants.len() - 1) as SIZE
+            });
+            *byte_code = ByteCode::Constant(index);
+        }
+        return Ok(Self {
+            version: FORMAT_VERSION,
+            byte_codes,
+            functions,
+            methods,
+            variable_names,
+            initial_variable_count: initial_variables.len() as SIZE,
+            variable_count,
+            constants,
+            positions,
+        });
+    }
+    pub fn from_script<B: DynamicValue+std::fmt::Debug>(
+        executer: &Executer<B>,
+        initial_variables: &[String],
+        script: &str,
+    ) -> Result<Self> {
+        let ast=COMPILE_ENGINE.with_borrow(|engine|engine.compile(script))?;
+        return Self::from_ast(executer, initial_variables, &ast);
+    }
+    /// Resolves the imports by name against an `Executer`, which may register its functions in any order.
+    ///
+    /// Every missing or incompatible import is reported at once in a `LinkError`.
+    /// The linked byte codes are then checked by `verify`, failing with an `ErrorKind::Verify`,
+    /// so that programs from untrusted sources cannot index variables or the stack out of bounds.
+    pub fn link<'a, B: DynamicValue+std::fmt::Debug>(&'a self, executer: &'a Executer<B>) -> Result<LinkedProgram<'a, B>> {
+        if self.version != FORMAT_VERSION {
+            return Err(ErrorKind::Format(format!("Program format version {} is not supported, expected {}!", self.version, FORMAT_VERSION)).into());
+        }
+        let mut errors = Vec::<String>::new();
+        let fn_ids = resolve_imports(&self.functions, &executer.fn_names, &executer.fn_arg_ranges, "Function", &mut errors);
+        let method_ids = resolve_imports(&self.methods, &executer.method_names, &executer.method_arg_ranges, "Method", &mut errors);
+        let mut byte_codes = self.byte_codes.clone();
+        for byte_code in byte_codes.iter_mut() {
+            match byte_code {
+                ByteCode::FnCall(fn_id, _)
+                | ByteCode::FnCallVarInt(fn_id, ..)
+                | ByteCode::FnCallVarVar(fn_id, ..)
+                | ByteCode::JumpIfFalseVarInt(fn_id, ..) => match fn_ids.get(*fn_id as usize) {
+                    Some(Some(id)) => *fn_id = *id,
+                    Some(None) => {}
+                    None => errors.push(format!("Function import #{} does not exist!", fn_id)),
+                },
+                ByteCode::MethodCall(method_id, _) => match method_ids.get(*method_id as usize) {
+                    Some(Some(id)) => *method_id = *id,
+                    Some(None) => {}
+                    None => errors.push(format!("Method import #{} does not exist!", method_id)),
+                },
+                _ => {}
+            }
+        }
+        if !errors.is_empty() {
+            return Err(ErrorKind::Link(LinkError(errors)).into());
+        }
+        let linked_program = LinkedProgram {
+            executer,
+            byte_codes,
+            program: self,
+        };
+        linked_program.verify().check()?;
+        return Ok(linked_program);
+    }
+    /// Links and runs the program.
+    pub fn run<B: DynamicValue+std::fmt::Debug>(&self, executer: &Executer<B>, init_vars: &[B]) -> Result<B> {
+        return self.link(executer)?.run(init_vars);
+    }
+    /// Serializes into the compact binary format.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        return binary::encode(self);
+    }
+    /// Deserializes from the compact binary format, failing if written by a different format version.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        return binary::decode(bytes);
+    }
+}
+
+// Returns the index of the import, adding it or widening its argument range as needed.
+fn add_import(imports: &mut Vec<Import>, name: &str, arg_count: SIZE) -> SIZE {
+    match imports.iter().position(|import| import.name == name) {
+        Some(index) => {
+            let (min_args, max_args) = &mut imports[index].arg_range;
+            *min_args = (*min_args).min(arg_count);
+            *max_args = (*max_args).max(arg_count);
+            return index as SIZE;
+        }
+        None => {
+            imports.push(Import {
+                name: name.to_string(),
+                arg_range: (arg_count, arg_count),
+            });
+            return (imports.len() - 1) as SIZE;
+        }
+    }
+}
+
+// Finds the `Executer` index of each import, or `None` after recording why it cannot be used.
+fn resolve_imports(
+    imports: &[Import],
+    names: &[String],
+    arg_ranges: &[(SIZE,SIZE)],
+    type_str: &str,
+    errors: &mut Vec<String>,
+) -> Vec<Option<SIZE>> {
+    let mut ids = Vec::<Option<SIZE>>::with_capacity(imports.len());
+    for import in imports {
+        match names.iter().position(|name| *name == import.name) {
+            Some(index) => {
+                let (min_args, max_args) = arg_ranges[index];
+                let (used_min, used_max) = import.arg_range;
+                if used_min < min_args || used_max > max_args {
+                    errors.push(format!(
+                        "{} \"{}\" is called with {} to {} arguments, but accepts {} to {}!",
+                        type_str, import.name, used_min, used_max, min_args, max_args
+                    ));
+                    ids.push(None);
+                } else {
+                    ids.push(Some(index as SIZE));
+                }
+            }
+            None => {
+                errors.push(format!("{} \"{}\" does not exist!", type_str, import.name));
+                ids.push(None);
+            }
+        }
+    }
+    return ids;
+}
+
+/// A `CompiledProgram` with its imports resolved, ready to run any number of times.
+pub struct LinkedProgram<'a, B: DynamicValue+std::fmt::Debug> {
+    executer: &'a Executer<B>,
+    byte_codes: Vec<ByteCode>,
+    program: &'a CompiledProgram,
+}
+
+impl<B: DynamicValue+std::fmt::Debug> LinkedProgram<'_, B> {
+    /// Checks the linked byte codes, as `CompiledProgram::link` already did.
+    pub fn verify(&self) -> VerifyReport {
+        let program = self.program;
+        return verify::verify_with_constants(self.executer, &self.byte_codes, program.constants.len(), program.variable_count as usize);
+    }
+    pub fn run(&self, init_vars: &[B]) -> Result<B> {
+        return self.run_with_limits(init_vars, &RunLimits::default());
+    }
+    pub fn run_with_limits(&self, init_vars: &[B], limits: &RunLimits) -> Result<B> {
+        self.check_initial_variables(init_vars)?;
+        let program = self.program;
+        return run(self.executer, &self.byte_codes, &program.constants, &program.positions, program.variable_count as usize, init_vars, limits);
+    }
+    /// Runs like `run`, returning a `Profile` of the byte codes executed and the host functions called.
+    pub fn run_with_profile(&self, init_vars: &[B]) -> Result<(B, Profile)> {
+        self.check_initial_variables(init_vars)?;
+        let program = self.program;
+        return run_with_profile(self.executer, &self.byte_codes, &program.constants, &program.positions, program.variable_count as usize, init_vars);
+    }
+    fn check_initial_variables(&self, init_vars: &[B]) -> Result<()> {
+        let initial_variable_count = self.program.initial_variable_count as usize;
+        if init_vars.len() != initial_variable_count {
+            return Err(ErrorKind::InitialVariables {
+                expected: initial_variable_count,
+                given: init_vars.len(),
+            }.into());
+        }
+        return Ok(());
+    }
+}
+
+// A value still referenced elsewhere (e.g. by a variable) is copied with its items, so that none of it is shared.
+fn detach_value<B:DynamicValue>(value: Rc<RefCell<B>>) -> Rc<RefCell<B>> {
+    if Rc::strong_count(&value) == 1 {
+        return value;
+    }
+    let copy=value.borrow().deep_clone(); // Never panics when single-threaded.
+    return Rc::new(RefCell::new(copy));
+}
+
+#[derive(Clone,Debug,serde::Serialize, serde::Deserialize)]
+struct CallFrame {
+    return_pos: usize,
+    // Variable base of the caller.
+    var_base: usize,
+    stack_base: usize,
+    handler_count: usize,
+}
+
+#[derive(Clone,Debug,serde::Serialize, serde::Deserialize)]
+struct Handler {
+    catch_pos: usize,
+    stack_len: usize,
+    frame_count: usize,
+}
+
+// Script function calls and exception handlers of a run.
+struct CallStack {
+    frames: Vec<CallFrame>,
+    handlers: Vec<Handler>,
+    // Index of the first variable of the current function.
+    var_base: usize,
+    // Call levels of the runs calling back into this one.
+    base_levels: usize,
+}
+
+impl CallStack {
+    fn call_levels(&self) -> usize {
+        return self.base_levels + self.frames.len();
+    }
+    // Enters a script function, whose variables start at `var_len`.
+    fn push_frame(&mut self, max_call_levels: usize, return_pos: usize, stack_base: usize, var_len: usize) -> Result<()> {
+        if self.call_levels() >= max_call_levels {
+            return Err(Limit::CallLevels.into());
+        }
+        self.frames.push(CallFrame {
+            return_pos,
+            var_base: self.var_base,
+            stack_base,
+            handler_count: self.handlers.len(),
+        });
+        self.var_base = var_len;
+        return Ok(());
+    }
+}
+
+fn throw<B:DynamicValue+std::fmt::Debug>(
+    call_stack: &mut CallStack,
+    variables: &mut Vec<Rc<RefCell<B>>>,
+    variable_stack: &mut Vec<Rc<RefCell<B>>>,
+    value: Rc<RefCell<B>>,
+) -> Result<usize> {
+    match call_stack.handlers.pop() {
+        Some(handler) => {
+            if call_stack.frames.len() > handler.frame_count {
+                let callee_base = match call_stack.frames.get(handler.frame_count + 1) {
+                    Some(frame) => frame.var_base,
+                    None => call_stack.var_base,
+                };
+                call_stack.var_base = call_stack.frames[handler.frame_count].var_base;
+                call_stack.frames.truncate(handler.frame_count);
+                variables.truncate(callee_base);
+            }
+            variable_stack.truncate(handler.stack_len);
+            variable_stack.push(value);
+            return Ok(handler.catch_pos);
+        }
+        None => {
+            // Keeps the value, so that the run calling back into this one can catch it.
+            match value.borrow().to_constant() { // Never panics when single-threaded.
+                Some(constant) => {
+                    return Err(ErrorKind::Exception(constant).into());
+                }
+                None => {
+                    return Err(ErrorKind::UncaughtException(format!("{:?}", value.borrow())).into()); // Never panics when single-threaded.
+                }
+            }
+        }
+    }
+}
+
+// Replaces the arguments with the result of a host call.
+// Returns the catch position if the host function threw an `ErrorKind::Exception`,
+// and removes the arguments if it yielded, for a `Vm` to push the result when resumed.
+fn push_call_result<B:DynamicValue+std::fmt::Debug>(
+    call_stack: &mut CallStack,
+    variables: &mut Vec<Rc<RefCell<B>>>,
+    variable_stack: &mut Vec<Rc<RefCell<B>>>,
+    start_pos: usize,
+    res: Result<Rc<RefCell<B>>>,
+) -> Result<Option<usize>> {
+    match res {
+        Ok(res) => {
+            variable_stack.truncate(start_pos);
+            variable_stack.push(res);
+            return Ok(None);
+        }
+        Err(err) => match err.kind() {
+            ErrorKind::Exception(constant) => {
+                let value=Rc::new(RefCell::new(B::from_constant(constant.clone())?));
+                return Ok(Some(throw(call_stack, variables, variable_stack, value)?));
+            }
+            ErrorKind::Yield(_) => {
+                variable_stack.truncate(start_pos);
+                return Err(err);
+            }
+            _ => {
+                return Err(err);
+            }
+        },
+    }
+}
+
+// Kept out of line, so that the error paths do not slow down the loop of `execute_from`.
+#[cold]
+fn invalid_byte_code(message: &str) -> Error {
+    return ErrorKind::InvalidByteCode(message.to_string()).into();
+}
+
+// A fused conditional jump has no result for a `Vm` to push when resumed, so its function must not yield.
+#[cold]
+fn cannot_yield(err: Error) -> Error {
+    if matches!(err.kind(), ErrorKind::Yield(_)) {
+        return invalid_byte_code("Functions called by fused conditional jumps cannot yield!");
+    }
+    return err;
+}
+
+// A program prepared for running.
+struct Program<'a, B: DynamicValue+std::fmt::Debug> {
+    executer: &'a Executer<B>,
+    byte_codes: &'a Vec<ByteCode>,
+    constants: &'a [DynamicConstant],
+    positions: &'a [(SIZE,SourcePosition)],
+    switch_cases: HashMap<usize,HashMap<u64,SIZE>>,
+    // Addresses of script functions by name and number of parameters.
+    script_fns: HashMap<(String,SIZE),usize>,
+    limits: RunLimits,
+    // Instructions executed before the last check of the limits, including those of callbacks.
+    instruction_count: std::cell::Cell<u64>,
+    // Breakpoints and steps of a `Vm`.
+    debugger: vm::Debugger,
+    // Set for runs with a profile.
+    profiler: Option<&'a RefCell<profile::Profiler>>,
+}
+
+enum FnTarget {
+    Script(usize),
+    Host(SIZE),
+}
+
+impl<B: DynamicValue+std::fmt::Debug> Program<'_, B> {
+    // Returns how many instructions to execute before checking the limits or reporting progress.
+    fn instructions_until_check(&self) -> u64 {
+        if self.debugger.is_active() || self.profiler.is_some() {
+            return 1;
+        }
+        let limits = &self.limits;
+        let count = self.instruction_count.get();
+        let mut left = u64::MAX;
+        if limits.max_stack_depth.is_some() || limits.deadline.is_some() {
+            left = LIMIT_CHECK_INTERVAL;
+        }
+        if let Some(max) = limits.max_instructions {
+            left = left.min(max.saturating_sub(count));
+        }
+        if let Some(progress) = &self.executer.progress {
+            left = left.min(progress.interval - count % progress.interval);
+        }
+        return left;
+    }
+    // Counts the instructions executed by a run which finished before the next check.
+    fn count_instructions(&self, instructions: u64) {
+        self.instruction_count.set(self.instruction_count.get() + instructions);
+    }
+    // Counts the instructions executed before a host function yielded, since a `Vm` starts over when resumed.
+    #[cold]
+    fn count_before_yield(&self, err: Error, instructions: u64) -> Error {
+        if matches!(err.kind(), ErrorKind::Yield(_)) {
+            self.count_instructions(instructions);
+        }
+        return err;
+    }
+    // Counts the instructions executed since the last check, then fails if a limit is exceeded.
+    fn check_limits(&self, instructions: u64, stack_depth: usize) -> Result<()> {
+        let count = self.instruction_count.get() + instructions;
+        self.instruction_count.set(count);
+        if let Some(progress) = &self.executer.progress {
+            // Callbacks may have counted past a multiple of the interval.
+            if count / progress.interval > (count - instructions) / progress.interval {
+                if let Some(value) = (progress.callback)(count) {
+                    return Err(ErrorKind::Terminated(value).into());
+                }
+            }
+        }
+        let limits = &self.limits;
+        if limits.max_instructions.is_some_and(|max| count >= max) {
+            return Err(Limit::Instructions.into());
+        }
+        if limits.max_stack_depth.is_some_and(|max| stack_depth > max) {
+            return Err(Limit::StackDepth.into());
+        }
+        if limits.deadline.is_some_and(|deadline| std::time::Instant::now() >= deadline) {
+            return Err(Limit::Deadline.into());
+        }
+        return Ok(());
+    }
+    // Pauses a `Vm` before the byte code at `pos`, but not the runs called back by host functions.
+    fn check_stop(&self, pos: usize, call_stack: &CallStack) -> Result<()> {
+        if self.debugger.is_active() && call_stack.base_levels == 0 && self.debugger.should_stop(pos, call_stack.frames.len()) {
+            return Err(ErrorKind::Paused.into());
+        }
+        return Ok(());
+    }
+    fn record_profile(&self, pos: usize) {
+        if let Some(profiler) = self.profiler {
+            profiler.borrow_mut().record(pos); // Never panics when single-threaded.
+        }
+    }
+    // Calls a host function or method, timing it for a profile.
+    fn call_host(&self, is_method: bool, index: SIZE, context: &NativeCallContext<B>, args: &[Rc<RefCell<B>>]) -> Result<Rc<RefCell<B>>> {
+        let call = |context| match is_method {
+            true => self.executer.call_method(index, context, args),
+            false => self.executer.call_fn(index, context, args),
+        };
+        let Some(profiler) = self.profiler else {
+            return call(context);
+        };
+        let start = std::time::Instant::now();
+        let res = call(context);
+        profiler.borrow_mut().record_call(is_method, index as usize, start.elapsed()); // Never panics when single-threaded.
+        return res;
+    }
+    fn source_position(&self, address: usize) -> Option<SourcePosition> {
+        return match self.positions.partition_point(|(pos, _)| *pos as usize <= address) {
+            0 => None,
+            index => Some(self.positions[index - 1].1),
+        };
+    }
+    fn find_fn(&self, name: &str, arg_count: SIZE) -> Result<FnTarget> {
+        match self.script_fns.get(&(name.to_string(), arg_count)) {
+            Some(p) => {
+                return Ok(FnTarget::Script(*p));
+            }
+            None => {
+                let fn_id = find_index(&self.executer.fn_names, name, ErrorKind::UndefinedFunction)?;
+                self.executer.check_fn_arg_count(fn_id, arg_count)?;
+                return Ok(FnTarget::Host(fn_id));
+            }
+        }
+    }
+    // Adds the location of the failing byte code to an error.
+    //
+    // Exceptions inside a callback are left for the run calling back to catch,
+    // and errors already located are kept from the innermost call.
+    fn locate_error(&self, err: Error, address: usize, call_levels: usize) -> Error {
+        if matches!(err.kind(), ErrorKind::Runtime(_)) || (call_levels > 0 && matches!(err.kind(), ErrorKind::Exception(_))) {
+            return err;
+        }
+        let position = self.source_position(address);
+        let script_function = self.byte_codes[..(address + 1).min(self.byte_codes.len())].iter().rev().find_map(|byte_code| match byte_code {
+            ByteCode::Enter(name, _, _) => Some(name.to_owned()),
+            _ => None,
+        });
+        let host_function = match self.byte_codes.get(address) {
+            Some(
+                ByteCode::FnCall(fn_id, _)
+                | ByteCode::FnCallVarInt(fn_id, ..)
+                | ByteCode::FnCallVarVar(fn_id, ..)
+                | ByteCode::JumpIfFalseVarInt(fn_id, ..),
+            ) => self.executer.fn_names.get(*fn_id as usize).cloned(),
+            Some(ByteCode::MethodCall(method_id, _)) => self.executer.method_names.get(*method_id as usize).cloned(),
+            _ => None,
+        };
+        return ErrorKind::Runtime(RuntimeError {
+            error: err,
+            address,
+            position,
+            script_function,
+            host_function,
+        })
+        .into();
+    }
+}
+
+/// Context of a host function call, for calling back function pointers.
+pub struct NativeCallContext<'a, B: DynamicValue+std::fmt::Debug> {
+    program: &'a Program<'a, B>,
+    call_levels: usize,
+}
+
+impl<B: DynamicValue+std::fmt::Debug> NativeCallContext<'_, B> {
+    /// Calls a function pointer, e.g. a closure passed to the host function.
+    pub fn call_fn_ptr(&self, fn_ptr: &FnPtr<B>, args: &[Rc<RefCell<B>>]) -> Result<Rc<RefCell<B>>> {
+        let arg_count = (fn_ptr.curry.len() + args.len()) as SIZE;
+        match self.program.find_fn(&fn_ptr.name, arg_count)? {
+            FnTarget::Script(p) => {
+                if self.call_levels >= self.program.executer.max_call_levels {
+                    return Err(Limit::CallLevels.into());
+                }
+                let variables = fn_ptr.curry.iter().cloned().chain(args.iter().cloned().map(detach_value)).collect();
+                let res = execute(self.program, variables, p, self.call_levels + 1)?;
+                return Ok(Rc::new(RefCell::new(res)));
+            }
+            FnTarget::Host(fn_id) => {
+                let all_args = fn_ptr.curry.iter().chain(args.iter()).cloned().collect::<Vec<_>>();
+                return self.program.call_host(false, fn_id, self, &all_args);
+            }
+        }
+    }
+}
+
+// Instructions executed between checks of the stack depth and the deadline.
+const LIMIT_CHECK_INTERVAL: u64 = 1024;
+
+/// Limits of a single run, for running untrusted scripts. None is set by default.
+///
+/// Exceeding one fails the run with an `ErrorKind::Limit`.
+/// The stack depth and the deadline are checked every 1024 instructions,
+/// and callbacks from host functions count towards the limits of the run calling them.
+#[derive(Clone,Copy,Debug,Default)]
+pub struct RunLimits {
+    /// Like Rhai's `Engine::set_max_operations`.
+    pub max_instructions: Option<u64>,
+    /// Values on the stack of the run, not counting variables.
+    pub max_stack_depth: Option<usize>,
+    /// Elements of an array constructed by `ByteCode::ConstructArray`.
+    pub max_array_length: Option<usize>,
+    pub deadline: Option<std::time::Instant>,
+}
+
+/// Runs byte codes without checking them, so byte codes from untrusted sources must pass `verify` first.
 pub fn run_byte_codes<B:DynamicValue+std::fmt::Debug>(
     executer: &Executer<B>,
     byte_codes: &Vec<ByteCode>,
-    init_vars: &Vec<B>,
-) -> anyhow::Result<B> {
-    let mut max_var_id=0 as SIZE;
-    for byte_code in byte_codes {
-        match byte_code {
-            ByteCode::Variable(var_id) => {
-                if *var_id > max_var_id {
-                    max_var_id=*var_id;
+    init_vars: &[B],
+) -> Result<B> {
+    return run(executer, byte_codes, &[], &[], count_variables(byte_codes)? as usize, init_vars, &RunLimits::default());
+}
+
+pub fn run_byte_codes_with_limits<B:DynamicValue+std::fmt::Debug>(
+    executer: &Executer<B>,
+    byte_codes: &Vec<ByteCode>,
+    init_vars: &[B],
+    limits: &RunLimits,
+) -> Result<B> {
+    return run(executer, byte_codes, &[], &[], count_variables(byte_codes)? as usize, init_vars, limits);
+}
+
+/// Runs like `run_byte_codes`, counting every byte code executed and timing host functions, which is slower.
+pub fn run_byte_codes_with_profile<B:DynamicValue+std::fmt::Debug>(
+    executer: &Executer<B>,
+    byte_codes: &Vec<ByteCode>,
+    init_vars: &[B],
+) -> Result<(B, Profile)> {
+    return run_with_profile(executer, byte_codes, &[], &[], count_variables(byte_codes)? as usize, init_vars);
+}
+
+fn run<B:DynamicValue+std::fmt::Debug>(
+    executer: &Executer<B>,
+    byte_codes: &Vec<ByteCode>,
+    constants: &[DynamicConstant],
+    positions: &[(SIZE,SourcePosition)],
+    variable_count: usize,
+    init_vars: &[B],
+    limits: &RunLimits,
+) -> Result<B> {
+    let program = Program::new(executer, byte_codes, constants, positions, limits)?;
+    let variables = initial_variables(variable_count, init_vars)?;
+    return execute(&program, variables, 0, 0);
+}
+
+fn run_with_profile<B:DynamicValue+std::fmt::Debug>(
+    executer: &Executer<B>,
+    byte_codes: &Vec<ByteCode>,
+    constants: &[DynamicConstant],
+    positions: &[(SIZE,SourcePosition)],
+    variable_count: usize,
+    init_vars: &[B],
+) -> Result<(B, Profile)> {
+    let profiler = RefCell::new(profile::Profiler::new(executer, byte_codes.len()));
+    let mut program = Program::new(executer, byte_codes, constants, positions, &RunLimits::default())?;
+    program.profiler = Some(&profiler);
+    let variables = initial_variables(variable_count, init_vars)?;
+    let value = execute(&program, variables, 0, 0)?;
+    return Ok((value, profiler.into_inner().into_profile(executer, byte_codes)));
+}
+
+impl<'a, B: DynamicValue+std::fmt::Debug> Program<'a, B> {
+    fn new(
+        executer: &'a Executer<B>,
+        byte_codes: &'a Vec<ByteCode>,
+        constants: &'a [DynamicConstant],
+        positions: &'a [(SIZE,SourcePosition)],
+        limits: &RunLimits,
+    ) -> Result<Self> {
+        let mut script_fns=HashMap::<(String,SIZE),usize>::new();
+        for (pos, byte_code) in byte_codes.iter().enumerate() {
+            match byte_code {
+                ByteCode::FnCall(fn_id, arg_count) => {
+                    executer.check_fn_arg_count(*fn_id, *arg_count)?;
                 }
+                ByteCode::FnCallVarInt(fn_id, ..) | ByteCode::FnCallVarVar(fn_id, ..) | ByteCode::JumpIfFalseVarInt(fn_id, ..) => {
+                    executer.check_fn_arg_count(*fn_id, 2)?;
+                }
+                ByteCode::MethodCall(method_id, arg_count) => {
+                    executer.check_method_arg_count(*method_id, *arg_count)?;
+                }
+                ByteCode::Enter(name, param_count, _) => {
+                    script_fns.insert((name.to_owned(), *param_count), pos);
+                }
+                _=>{}
             }
-            ByteCode::FnCall(fn_id, arg_count) => {
-                executer.check_fn_arg_count(*fn_id, *arg_count)?;
+        }
+        let mut switch_cases=HashMap::<usize,HashMap<u64,SIZE>>::new();
+        for (pos, byte_code) in byte_codes.iter().enumerate() {
+            if let ByteCode::Switch(table) = byte_code {
+                if !table.cases.is_empty() {
+                    switch_cases.insert(pos, table.cases.iter().cloned().collect());
+                }
             }
-            _=>{}
         }
+        return Ok(Self {
+            executer,
+            byte_codes,
+            constants,
+            positions,
+            switch_cases,
+            script_fns,
+            limits: *limits,
+            instruction_count: std::cell::Cell::new(0),
+            debugger: vm::Debugger::default(),
+            profiler: None,
+        });
     }
-    let var_count=max_var_id as usize+1;
+}
+
+// Variables of the main script, starting with the initial ones.
+fn initial_variables<B:DynamicValue>(var_count: usize, init_vars: &[B]) -> Result<Vec<Rc<RefCell<B>>>> {
     let mut variables=Vec::<Rc<RefCell<B>>>::with_capacity(var_count);
     let init_len=usize::min(var_count, init_vars.len());
-    for i in 0..init_len {
-        variables.push(Rc::new(RefCell::new(init_vars[i].clone())));
+    for init_var in &init_vars[..init_len] {
+        variables.push(Rc::new(RefCell::new(init_var.clone())));
     }
     for _i in init_len..var_count {
         variables.push(Rc::new(RefCell::new(B::from_unit()?)));
     }
-    let mut variable_stack = Vec::<Rc<RefCell<B>>>::new();
-    let mut pos = 0usize;
+    return Ok(variables);
+}
+
+// Variables and stacks of a run, kept by a `Vm` while suspended.
+struct RunState<B> {
+    variables: Vec<Rc<RefCell<B>>>,
+    variable_stack: Vec<Rc<RefCell<B>>>,
+    call_stack: CallStack,
+}
+
+impl<B> RunState<B> {
+    fn new(variables: Vec<Rc<RefCell<B>>>, call_levels: usize) -> Self {
+        return Self {
+            variables,
+            variable_stack: vec![],
+            call_stack: CallStack {
+                frames: vec![],
+                handlers: vec![],
+                var_base: 0,
+                base_levels: call_levels,
+            },
+        };
+    }
+}
+
+// Runs from `pos` until the end, or until the function started at `pos` returns.
+fn execute<B:DynamicValue+std::fmt::Debug>(
+    program: &Program<B>,
+    variables: Vec<Rc<RefCell<B>>>,
+    pos: usize,
+    call_levels: usize,
+) -> Result<B> {
+    let mut state = RunState::new(variables, call_levels);
+    let mut failed_pos = pos;
+    return execute_from(program, &mut state, pos, &mut failed_pos)
+        .map_err(|err| program.locate_error(err, failed_pos, call_levels));
+}
+
+// Same as `execute` with the state of the run, setting `failed_pos` to the byte code which fails.
+fn execute_from<B:DynamicValue+std::fmt::Debug>(
+    program: &Program<B>,
+    state: &mut RunState<B>,
+    mut pos: usize,
+    failed_pos: &mut usize,
+) -> Result<B> {
+    let executer = program.executer;
+    let byte_codes = program.byte_codes;
+    let RunState { variables, variable_stack, call_stack } = state;
+    // Instructions to execute until the next check of the limits, out of `instructions`.
+    let mut instructions = program.instructions_until_check();
+    // A profile counts every byte code, starting with the first one.
+    if program.profiler.is_some() {
+        instructions = 0;
+    }
+    let mut countdown = instructions;
     while pos < byte_codes.len() {
-        //println!("{}: {:?}", pos, byte_codes[pos]);
+        *failed_pos = pos;
+        if countdown == 0 {
+            program.check_limits(instructions, variable_stack.len())?;
+            program.check_stop(pos, call_stack)?;
+            program.record_profile(pos);
+            instructions = program.instructions_until_check();
+            countdown = instructions;
+        }
+        countdown -= 1;
         match &byte_codes[pos] {
+            ByteCode::Constant(index) => match program.constants.get(*index as usize) {
+                Some(constant) => {
+                    variable_stack.push(Rc::new(RefCell::new(B::from_constant(constant.to_owned())?)));
+                }
+                None => {
+                    return Err(invalid_byte_code(&format!("Constant #{} does not exist!", index)));
+                }
+            },
             ByteCode::DynamicConstant(dynamic) => {
                 variable_stack.push(Rc::new(RefCell::new(B::from_constant(dynamic.to_owned())?)));
             }
@@ -936,31 +2791,104 @@ pub fn run_byte_codes<B:DynamicValue+std::fmt::Debug>(
             ByteCode::StringConstant(v) => {
                 variable_stack.push(Rc::new(RefCell::new(B::from_string(v.to_owned())?)));
             }
-            ByteCode::InterpolatedString(_) => {
-                anyhow::bail!("InterpolatedString not supported yet!");
+            ByteCode::InterpolatedString(l) => {
+                let len=*l as usize;
+                if variable_stack.len() < len {
+                    return Err(invalid_byte_code("Not enough elements to construct interpolated string"));
+                }
+                let mut res=String::new();
+                for v in variable_stack.split_off(variable_stack.len() - len) {
+                    res.push_str(&v.borrow().to_display_string()?); // Never panics when single-threaded.
+                }
+                variable_stack.push(Rc::new(RefCell::new(B::from_string(res)?)));
             }
             ByteCode::ConstructArray(l) => {
                 let len=*l as usize;
                 if variable_stack.len() < len {
-                    anyhow::bail!("Not enough elements to construct array");
+                    return Err(invalid_byte_code("Not enough elements to construct array"));
                 }
-                let ary=variable_stack.split_off(variable_stack.len() - len);
+                if program.limits.max_array_length.is_some_and(|max| len > max) {
+                    return Err(Limit::ArrayLength.into());
+                }
+                let ary=variable_stack.split_off(variable_stack.len() - len).into_iter().map(detach_value).collect();
                 variable_stack.push(Rc::new(RefCell::new(B::from_array(ary)?)));
             }
+            ByteCode::ConstructMap(keys) => {
+                if variable_stack.len() < keys.len() {
+                    return Err(invalid_byte_code("Not enough elements to construct map"));
+                }
+                let values=variable_stack.split_off(variable_stack.len() - keys.len());
+                let map=keys.iter().cloned().zip(values.into_iter().map(detach_value)).collect();
+                variable_stack.push(Rc::new(RefCell::new(B::from_map(map)?)));
+            }
             ByteCode::Variable(var_id) => {
-                variable_stack.push(variables[*var_id as usize].clone());
+                variable_stack.push(variables[call_stack.var_base + *var_id as usize].clone());
             }
             ByteCode::FnCall(fn_index, fn_arg_count) => {
                 let fn_arg_count_sz = *fn_arg_count as usize;
                 if variable_stack.len() < fn_arg_count_sz {
-                    anyhow::bail!("Not enough arguments for function call!");
+                    return Err(invalid_byte_code("Not enough arguments for function call!"));
                 }
                 let start_pos=variable_stack.len() - fn_arg_count_sz;
-                let res=executer.call_fn(*fn_index,&variable_stack[start_pos..])?;
-                variable_stack.truncate(start_pos);
-                variable_stack.push(res);
-                // variable_stack.truncate(start_pos+1);
-                // variable_stack[start_pos]=res;
+                let context=NativeCallContext { program, call_levels: call_stack.call_levels() };
+                let res=program.call_host(false,*fn_index,&context,&variable_stack[start_pos..]);
+                if let Some(catch_pos)=push_call_result(call_stack, variables, variable_stack, start_pos, res)
+                    .map_err(move |err| program.count_before_yield(err, instructions - countdown))? {
+                    pos=catch_pos;
+                    continue;
+                }
+            }
+            ByteCode::MethodCall(method_index, arg_count) => {
+                let arg_count_sz = *arg_count as usize;
+                if variable_stack.len() < arg_count_sz {
+                    return Err(invalid_byte_code("Not enough arguments for method call!"));
+                }
+                let start_pos=variable_stack.len() - arg_count_sz;
+                let context=NativeCallContext { program, call_levels: call_stack.call_levels() };
+                let res=program.call_host(true,*method_index,&context,&variable_stack[start_pos..]);
+                if let Some(catch_pos)=push_call_result(call_stack, variables, variable_stack, start_pos, res)
+                    .map_err(move |err| program.count_before_yield(err, instructions - countdown))? {
+                    pos=catch_pos;
+                    continue;
+                }
+            }
+            ByteCode::FnCallVarInt(fn_index, var_id, v) => {
+                let args=[variables[call_stack.var_base + *var_id as usize].clone(), Rc::new(RefCell::new(B::from_integer(*v)?))];
+                let context=NativeCallContext { program, call_levels: call_stack.call_levels() };
+                let res=program.call_host(false,*fn_index,&context,&args);
+                let start_pos=variable_stack.len();
+                if let Some(catch_pos)=push_call_result(call_stack, variables, variable_stack, start_pos, res)
+                    .map_err(move |err| program.count_before_yield(err, instructions - countdown))? {
+                    pos=catch_pos;
+                    continue;
+                }
+            }
+            ByteCode::FnCallVarVar(fn_index, var_id_a, var_id_b) => {
+                let base=call_stack.var_base;
+                let args=[variables[base + *var_id_a as usize].clone(), variables[base + *var_id_b as usize].clone()];
+                let context=NativeCallContext { program, call_levels: call_stack.call_levels() };
+                let res=program.call_host(false,*fn_index,&context,&args);
+                let start_pos=variable_stack.len();
+                if let Some(catch_pos)=push_call_result(call_stack, variables, variable_stack, start_pos, res)
+                    .map_err(move |err| program.count_before_yield(err, instructions - countdown))? {
+                    pos=catch_pos;
+                    continue;
+                }
+            }
+            ByteCode::JumpIfFalseVarInt(fn_index, var_id, v, p) => {
+                let args=[variables[call_stack.var_base + *var_id as usize].clone(), Rc::new(RefCell::new(B::from_integer(*v)?))];
+                let context=NativeCallContext { program, call_levels: call_stack.call_levels() };
+                let res=program.call_host(false,*fn_index,&context,&args).map_err(cannot_yield);
+                let start_pos=variable_stack.len();
+                if let Some(catch_pos)=push_call_result(call_stack, variables, variable_stack, start_pos, res)? {
+                    pos=catch_pos;
+                    continue;
+                }
+                let val=variable_stack.pop().unwrap(); // Never panics since the result was just pushed.
+                if !val.borrow().to_bool()? { // Never panics when single-threaded.
+                    pos = *p as usize;
+                    continue;
+                }
             }
             ByteCode::Jump(p) => {
                 pos = *p as usize;
@@ -974,7 +2902,7 @@ pub fn run_byte_codes<B:DynamicValue+std::fmt::Debug>(
                     }
                 }
                 None => {
-                    anyhow::bail!("Not enough arguments for conditional jump!");
+                    return Err(invalid_byte_code("Not enough arguments for conditional jump!"));
                 }
             },
             ByteCode::JumpIfFalse(p) => match variable_stack.pop() {
@@ -985,7 +2913,7 @@ pub fn run_byte_codes<B:DynamicValue+std::fmt::Debug>(
                     }
                 }
                 None => {
-                    anyhow::bail!("Not enough arguments for conditional jump!");
+                    return Err(invalid_byte_code("Not enough arguments for conditional jump!"));
                 }
             },
             ByteCode::JumpIfNotNull(p) => match variable_stack.pop() {
@@ -996,51 +2924,139 @@ pub fn run_byte_codes<B:DynamicValue+std::fmt::Debug>(
                     }
                 }
                 None => {
-                    anyhow::bail!("Not enough arguments for conditional jump!");
+                    return Err(invalid_byte_code("Not enough arguments for conditional jump!"));
+                }
+            },
+            ByteCode::VarInit(var_id) => match variable_stack.pop() {
+                Some(val) => {
+                    let val=detach_value(val);
+                    variables[call_stack.var_base + *var_id as usize]=val.clone();
+                    variable_stack.push(val);
+                }
+                None => {
+                    return Err(invalid_byte_code("Not enough arguments for variable declare!"));
                 }
             },
-            ByteCode::VarInit(var_id) => match variable_stack.last() {
+            ByteCode::VarStore(var_id) => match variable_stack.pop() {
                 Some(val) => {
-                    variables[*var_id as usize]=val.clone();
+                    variables[call_stack.var_base + *var_id as usize]=detach_value(val);
                 }
                 None => {
-                    anyhow::bail!("Not enough arguments for variable declare!");
+                    return Err(invalid_byte_code("Not enough arguments for variable declare!"));
                 }
             },
             ByteCode::Index => match variable_stack.pop() {
                 Some(ind) => match variable_stack.last_mut() {
                     Some(r) => {
-                        let index=ind.borrow().to_size()?; // Never panics when single-threaded.
-                        let res=r.borrow().index_into(index)?; // Never panics when single-threaded.
+                        let res=match ind.borrow().to_size() { // Never panics when single-threaded.
+                            Ok(index) => r.borrow().index_into(index)?, // Never panics when single-threaded.
+                            Err(err) => match ind.borrow().to_constant() { // Never panics when single-threaded.
+                                Some(DynamicConstant::String(name)) => r.borrow().get_property(&name)?, // Never panics when single-threaded.
+                                _ => {
+                                    return Err(err);
+                                }
+                            },
+                        };
                         *r=res;
                     }
                     None => {
-                        anyhow::bail!("Not enough arguments for index!");
+                        return Err(invalid_byte_code("Not enough arguments for index!"));
                     }
                 },
                 None => {
-                    anyhow::bail!("Not enough arguments for index!");
+                    return Err(invalid_byte_code("Not enough arguments for index!"));
                 }
             },
-            ByteCode::Return => match variable_stack.pop() {
-                Some(value) => {
-                    return Ok(value.borrow().to_owned()); // Never panics when single-threaded.
+            ByteCode::GetProperty(name) => match variable_stack.last_mut() {
+                Some(r) => {
+                    let res=r.borrow().get_property(name)?; // Never panics when single-threaded.
+                    *r=res;
                 }
                 None => {
-                    anyhow::bail!("Missing return value!");
+                    return Err(invalid_byte_code("Not enough arguments for property!"));
+                }
+            },
+            ByteCode::SetProperty(name) => match (variable_stack.pop(), variable_stack.pop()) {
+                (Some(value), Some(r)) => {
+                    let value=detach_value(value);
+                    r.borrow_mut().set_property(name, value)?; // Never panics when single-threaded.
+                }
+                _ => {
+                    return Err(invalid_byte_code("Not enough arguments for property!"));
+                }
+            },
+            ByteCode::Return => match call_stack.frames.pop() {
+                Some(frame) => {
+                    let value = if variable_stack.len() > frame.stack_base {
+                        variable_stack.pop().unwrap() // Never panics since the stack is not empty.
+                    } else {
+                        Rc::new(RefCell::new(B::from_unit()?))
+                    };
+                    variables.truncate(call_stack.var_base);
+                    variable_stack.truncate(frame.stack_base);
+                    variable_stack.push(value);
+                    call_stack.handlers.truncate(frame.handler_count);
+                    call_stack.var_base = frame.var_base;
+                    pos = frame.return_pos;
+                    continue;
                 }
+                None => match variable_stack.pop() {
+                    Some(value) => {
+                        program.count_instructions(instructions - countdown);
+                        return Ok(value.borrow().to_owned()); // Never panics when single-threaded.
+                    }
+                    None => {
+                        return Err(invalid_byte_code("Missing return value!"));
+                    }
+                },
             },
             ByteCode::PopStack => {
                 variable_stack.pop();
             }
+            ByteCode::Switch(table) => match variable_stack.pop() {
+                Some(val) => {
+                    let target = match val.borrow().to_constant() { // Never panics when single-threaded.
+                        Some(value) => match program.switch_cases.get(&pos).and_then(|cases| cases.get(&switch_case_hash(&value))) {
+                            Some(p) => *p,
+                            None => table.range_target(&value),
+                        },
+                        None => table.default,
+                    };
+                    pos = target as usize;
+                    continue;
+                }
+                None => {
+                    return Err(invalid_byte_code("Not enough arguments for switch!"));
+                }
+            },
+            ByteCode::PushHandler(p) => {
+                call_stack.handlers.push(Handler {
+                    catch_pos: *p as usize,
+                    stack_len: variable_stack.len(),
+                    frame_count: call_stack.frames.len(),
+                });
+            }
+            ByteCode::PopHandler => {
+                call_stack.handlers.pop();
+            }
+            ByteCode::Throw => match variable_stack.pop() {
+                Some(value) => {
+                    pos=throw(call_stack, variables, variable_stack, value)?;
+                    continue;
+                }
+                None => {
+                    return Err(invalid_byte_code("Not enough arguments for throw!"));
+                }
+            },
             ByteCode::Iter(loop_range_id,loop_index_id,loop_var_id,p) => {
-                let index=variables[*loop_index_id as usize].borrow().to_size()?; // Never panics when single-threaded.
-                let index_res=variables[*loop_range_id as usize].borrow().iter(index)?; // Never panics when single-threaded.
+                let base=call_stack.var_base;
+                let index=variables[base + *loop_index_id as usize].borrow().to_size()?; // Never panics when single-threaded.
+                let index_res=variables[base + *loop_range_id as usize].borrow().iter(index)?; // Never panics when single-threaded.
                 match index_res {
                     Some(v) => {
-                        variables[*loop_var_id as usize]=v;
+                        variables[base + *loop_var_id as usize]=v;
                         let new_index=index+1;
-                        variables[*loop_index_id as usize]=Rc::new(RefCell::new(B::from_integer(new_index as INT)?));
+                        variables[base + *loop_index_id as usize]=Rc::new(RefCell::new(B::from_integer(new_index as INT)?));
                     }
                     None => {
                         pos = *p as usize;
@@ -1048,16 +3064,110 @@ pub fn run_byte_codes<B:DynamicValue+std::fmt::Debug>(
                     }
                 }
             }
+            ByteCode::Call(p, arg_count) => {
+                let arg_count_sz = *arg_count as usize;
+                if variable_stack.len() < arg_count_sz {
+                    return Err(invalid_byte_code("Not enough arguments for function call!"));
+                }
+                let start_pos=variable_stack.len() - arg_count_sz;
+                call_stack.push_frame(executer.max_call_levels, pos + 1, start_pos, variables.len())?;
+                // Arguments are passed by value.
+                variables.extend(variable_stack.drain(start_pos..).map(detach_value));
+                pos = *p as usize;
+                continue;
+            }
+            ByteCode::CallPtr(arg_count) => {
+                let arg_count_sz = *arg_count as usize;
+                if variable_stack.len() <= arg_count_sz {
+                    return Err(invalid_byte_code("Not enough arguments for function pointer call!"));
+                }
+                let start_pos=variable_stack.len() - arg_count_sz;
+                let fn_ptr=variable_stack[start_pos - 1].borrow().to_fn_ptr()?; // Never panics when single-threaded.
+                match program.find_fn(&fn_ptr.name, (fn_ptr.curry.len() + arg_count_sz) as SIZE)? {
+                    FnTarget::Script(p) => {
+                        call_stack.push_frame(executer.max_call_levels, pos + 1, start_pos - 1, variables.len())?;
+                        variables.extend(fn_ptr.curry);
+                        variables.extend(variable_stack.drain(start_pos..).map(detach_value));
+                        variable_stack.pop();
+                        pos = p;
+                        continue;
+                    }
+                    FnTarget::Host(fn_id) => {
+                        let args=fn_ptr.curry.into_iter().chain(variable_stack[start_pos..].iter().cloned()).collect::<Vec<_>>();
+                        let context=NativeCallContext { program, call_levels: call_stack.call_levels() };
+                        let res=program.call_host(false,fn_id,&context,&args);
+                        if let Some(catch_pos)=push_call_result(call_stack, variables, variable_stack, start_pos - 1, res)
+                    .map_err(move |err| program.count_before_yield(err, instructions - countdown))? {
+                            pos=catch_pos;
+                            continue;
+                        }
+                    }
+                }
+            }
+            ByteCode::FnPtr(name) => {
+                let fn_ptr=FnPtr {
+                    name: name.to_owned(),
+                    curry: vec![],
+                };
+                variable_stack.push(Rc::new(RefCell::new(B::from_fn_ptr(fn_ptr)?)));
+            }
+            ByteCode::Curry(arg_count) | ByteCode::Closure(arg_count) => {
+                let arg_count_sz = *arg_count as usize;
+                if variable_stack.len() <= arg_count_sz {
+                    return Err(invalid_byte_code("Not enough arguments for currying!"));
+                }
+                let start_pos=variable_stack.len() - arg_count_sz;
+                let mut fn_ptr=variable_stack[start_pos - 1].borrow().to_fn_ptr()?; // Never panics when single-threaded.
+                if let ByteCode::Closure(_) = &byte_codes[pos] {
+                    // Captured variables are shared with the closure.
+                    fn_ptr.curry.extend(variable_stack.drain(start_pos..));
+                } else {
+                    fn_ptr.curry.extend(variable_stack.drain(start_pos..).map(detach_value));
+                }
+                variable_stack[start_pos - 1]=Rc::new(RefCell::new(B::from_fn_ptr(fn_ptr)?));
+            }
+            ByteCode::Enter(_, _, var_count) => {
+                for _i in variables.len()..call_stack.var_base + *var_count as usize {
+                    variables.push(Rc::new(RefCell::new(B::from_unit()?)));
+                }
+            }
         }
         pos += 1;
     }
-    //println!("Stack size: {}",variable_stack.len());
+    program.count_instructions(instructions - countdown);
     match variable_stack.pop() {
         Some(value) =>{
             return Ok(value.borrow().to_owned()); // Never panics when single-threaded.
         }
         None => {
-            return Ok(B::from_unit()?);
+            return B::from_unit();
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_value::{compile, new_executer, Value};
+
+    #[test]
+    fn switch_cases_from_scripts() {
+        let executer = new_executer();
+        let script = "let s = 0; for i in 0..5 { s += switch i { 1 => 10, 2..4 => 100, _ => 1000 }; } s";
+        let byte_codes = compile(&executer, script);
+        assert_eq!(run_byte_codes(&executer, &byte_codes, &[]).unwrap(), Value::Integer(2210));
+        let program = CompiledProgram::from_script(&executer, &[], script).unwrap();
+        assert_eq!(program.run(&executer, &[]).unwrap(), Value::Integer(2210));
+    }
+
+    #[test]
+    fn switch_cases_from_asts() {
+        let executer = new_executer();
+        let ast = rhai::Engine::new_raw().compile("let s = 0; for i in 0..5 { s += switch i { 1 => 10, 2..4 => 100, _ => 1000 }; } s").unwrap();
+        let byte_codes = ast_to_byte_codes(&executer, &mut Vec::new(), &ast).unwrap();
+        assert_eq!(run_byte_codes(&executer, &byte_codes, &[]).unwrap(), Value::Integer(2210));
+        let program = CompiledProgram::from_ast(&executer, &[], &ast).unwrap();
+        assert_eq!(program.run(&executer, &[]).unwrap(), Value::Integer(2210));
+        check_switch_case_hash().unwrap();
+    }
+}
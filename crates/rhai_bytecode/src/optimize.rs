@@ -0,0 +1,534 @@
+//! Optimization passes over compiled byte codes.
+//!
+//! Passes remove byte codes, so every jump target and script function address is remapped,
+//! and byte codes which other ones jump into are kept.
+//!
+//! `optimize` rewrites byte codes without knowing the host functions, so it can be run on any `CompiledProgram`,
+//! while `fold_constants` calls the pure host functions of an `Executer`.
+//! The compiler itself fuses common sequences of byte codes by `fuse`, before anything else runs.
+
+use std::{cell::RefCell, rc::Rc};
+
+use crate::{
+    ByteCode, CompiledProgram, DynamicConstant, DynamicValue, Executer, NativeCallContext, Program, RunLimits, SourcePosition,
+    SIZE,
+};
+
+/// How much `optimize` changes the byte codes.
+#[derive(Clone,Copy,Debug,PartialEq,Eq,PartialOrd,Ord)]
+pub enum OptimizationLevel {
+    /// Byte codes are left as compiled, to compare against.
+    None,
+    /// A single pass over neighbouring byte codes, e.g. merging `VarInit` and `PopStack` into `VarStore`,
+    /// removing values pushed then popped, and jumps to the next byte code or over constant conditions.
+    Simple,
+    /// Repeats the passes of `Simple` until nothing changes, also removing byte codes which cannot be reached.
+    Full,
+}
+
+/// Optimizes a program, returning the number of byte codes removed.
+///
+/// Source positions are moved with the byte codes, so runtime errors are still located.
+pub fn optimize(program: &mut CompiledProgram, level: OptimizationLevel) -> usize {
+    let len = program.byte_codes.len();
+    let new_address = optimize_with_addresses(&mut program.byte_codes, level);
+    let mut positions = Vec::<(SIZE,SourcePosition)>::with_capacity(program.positions.len());
+    for (pos, position) in program.positions.drain(..) {
+        let address = new_address[(pos as usize).min(len)] as SIZE;
+        // The byte codes of an earlier position are all removed if the next one moves to the same address.
+        match positions.last_mut() {
+            Some(last) if last.0 == address => *last = (address, position),
+            _ => positions.push((address, position)),
+        }
+    }
+    positions.retain(|(address, _)| (*address as usize) < program.byte_codes.len());
+    positions.dedup_by(|next, last| next.1 == last.1);
+    program.positions = positions;
+    return len - program.byte_codes.len();
+}
+
+/// Optimizes byte codes from `ast_to_byte_codes` like `optimize`, returning the number of byte codes removed.
+pub fn optimize_byte_codes(byte_codes: &mut Vec<ByteCode>, level: OptimizationLevel) -> usize {
+    let len = byte_codes.len();
+    optimize_with_addresses(byte_codes, level);
+    return len - byte_codes.len();
+}
+
+// Runs the passes of the level, returning the new address of every byte code, and of the end.
+fn optimize_with_addresses(byte_codes: &mut Vec<ByteCode>, level: OptimizationLevel) -> Vec<usize> {
+    let mut new_address = (0..=byte_codes.len()).collect::<Vec<_>>();
+    if level == OptimizationLevel::None {
+        return new_address;
+    }
+    loop {
+        let mut removed = vec![false; byte_codes.len()];
+        let mut changed = rewrite_neighbours(byte_codes, &mut removed);
+        changed |= thread_jumps(byte_codes);
+        if level == OptimizationLevel::Full {
+            changed |= remove_unreachable(byte_codes, &mut removed);
+        }
+        let step = remove(byte_codes, &removed);
+        for address in new_address.iter_mut() {
+            *address = step[*address];
+        }
+        if !changed || level == OptimizationLevel::Simple {
+            return new_address;
+        }
+    }
+}
+
+// Marks byte codes removed or rewrites them, looking at each one with the next.
+fn rewrite_neighbours(byte_codes: &mut [ByteCode], removed: &mut [bool]) -> bool {
+    let targets = jump_targets(byte_codes);
+    let mut changed = false;
+    for pos in 0..byte_codes.len() {
+        if removed[pos] {
+            continue;
+        }
+        if let ByteCode::Jump(target) = byte_codes[pos] {
+            match byte_codes.get(target as usize) {
+                _ if target as usize == pos + 1 => {
+                    removed[pos] = true;
+                    changed = true;
+                }
+                // Returns the same way from anywhere in a function.
+                Some(ByteCode::Return) => {
+                    byte_codes[pos] = ByteCode::Return;
+                    changed = true;
+                }
+                _ => {}
+            }
+            continue;
+        }
+        // The second byte code must only be reached from the first one.
+        if pos + 1 >= byte_codes.len() || targets[pos + 1] || removed[pos + 1] {
+            continue;
+        }
+        match (&byte_codes[pos], &byte_codes[pos + 1]) {
+            (ByteCode::VarInit(var_id), ByteCode::PopStack) => {
+                byte_codes[pos] = ByteCode::VarStore(*var_id);
+                removed[pos + 1] = true;
+            }
+            (push, ByteCode::PopStack) if is_pure_push(push) => {
+                removed[pos] = true;
+                removed[pos + 1] = true;
+            }
+            (ByteCode::BoolConstant(v), ByteCode::JumpIfTrue(target) | ByteCode::JumpIfFalse(target)) => {
+                let jumps = *v == matches!(byte_codes[pos + 1], ByteCode::JumpIfTrue(_));
+                if jumps {
+                    byte_codes[pos + 1] = ByteCode::Jump(*target);
+                } else {
+                    removed[pos + 1] = true;
+                }
+                removed[pos] = true;
+            }
+            // Jumping over a jump is the opposite conditional jump.
+            (ByteCode::JumpIfTrue(over) | ByteCode::JumpIfFalse(over), ByteCode::Jump(target)) if *over as usize == pos + 2 => {
+                byte_codes[pos] = match byte_codes[pos] {
+                    ByteCode::JumpIfTrue(_) => ByteCode::JumpIfFalse(*target),
+                    _ => ByteCode::JumpIfTrue(*target),
+                };
+                removed[pos + 1] = true;
+            }
+            _ => {
+                continue;
+            }
+        }
+        changed = true;
+    }
+    return changed;
+}
+
+// Whether the byte code only pushes a value, without any other effect.
+fn is_pure_push(byte_code: &ByteCode) -> bool {
+    return matches!(byte_code, ByteCode::Constant(_) | ByteCode::Variable(_) | ByteCode::FnPtr(_)) || to_constant(byte_code).is_some();
+}
+
+// Makes jumps to other jumps go to their final targets.
+fn thread_jumps(byte_codes: &mut [ByteCode]) -> bool {
+    let mut changed = false;
+    for pos in 0..byte_codes.len() {
+        let mut byte_code = byte_codes[pos].clone();
+        crate::for_each_jump_target(&mut byte_code, |target| {
+            let final_target = crate::trace_jump(*target, byte_codes);
+            if final_target != *target {
+                *target = final_target;
+                changed = true;
+            }
+        });
+        byte_codes[pos] = byte_code;
+    }
+    return changed;
+}
+
+// Marks the byte codes which no path reaches from the start of the main script or of a script function.
+fn remove_unreachable(byte_codes: &[ByteCode], removed: &mut [bool]) -> bool {
+    let mut reachable = vec![false; byte_codes.len()];
+    // Script functions can also be called by name through function pointers.
+    let mut pending = byte_codes.iter().enumerate().filter(|(_, byte_code)| matches!(byte_code, ByteCode::Enter(..))).map(|(pos, _)| pos).collect::<Vec<_>>();
+    pending.push(0);
+    while let Some(pos) = pending.pop() {
+        if pos >= byte_codes.len() || reachable[pos] {
+            continue;
+        }
+        reachable[pos] = true;
+        // Byte codes removed by other passes lead to the next one kept.
+        if removed[pos] {
+            pending.push(pos + 1);
+            continue;
+        }
+        let mut byte_code = byte_codes[pos].clone();
+        match &byte_code {
+            ByteCode::Jump(_) | ByteCode::Switch(_) | ByteCode::Throw | ByteCode::Return => {}
+            ByteCode::Call(target, _) => {
+                pending.push(*target as usize);
+                pending.push(pos + 1);
+            }
+            _ => {
+                pending.push(pos + 1);
+            }
+        }
+        crate::for_each_jump_target(&mut byte_code, |target| pending.push(*target as usize));
+    }
+    let mut changed = false;
+    for (pos, is_reachable) in reachable.into_iter().enumerate() {
+        if !is_reachable && !removed[pos] {
+            removed[pos] = true;
+            changed = true;
+        }
+    }
+    return changed;
+}
+
+// Removes the marked byte codes, returning the new address of every byte code, and of the end.
+//
+// A removed byte code moves to the address of the next one kept, where jumps to it now go.
+fn remove(byte_codes: &mut Vec<ByteCode>, removed: &[bool]) -> Vec<usize> {
+    let mut new_address = Vec::<usize>::with_capacity(byte_codes.len() + 1);
+    let mut kept = 0;
+    for is_removed in removed {
+        new_address.push(kept);
+        if !is_removed {
+            kept += 1;
+        }
+    }
+    new_address.push(kept);
+    let mut pos = 0;
+    byte_codes.retain(|_| {
+        pos += 1;
+        return !removed[pos - 1];
+    });
+    remap_targets(byte_codes, &new_address);
+    return new_address;
+}
+
+/// Replaces calls to pure host functions whose arguments are all constants by their results,
+/// e.g. "2 * 3" by "6", returning the number of calls folded.
+///
+/// Functions are pure when added by `Executer::add_pure_fn`. Calls which fail, or whose result
+/// cannot be kept as a constant, are left to fail or run as usual.
+pub fn fold_constants<B: DynamicValue+std::fmt::Debug>(executer: &Executer<B>, byte_codes: &mut Vec<ByteCode>) -> usize {
+    let no_byte_codes = Vec::<ByteCode>::new();
+    let program = match Program::new(executer, &no_byte_codes, &[], &[], &RunLimits::default()) {
+        Ok(program) => program,
+        Err(_) => {
+            return 0;
+        }
+    };
+    let context = NativeCallContext { program: &program, call_levels: 0 };
+    let targets = jump_targets(byte_codes);
+    let mut folded = 0;
+    let mut new_address = Vec::<usize>::with_capacity(byte_codes.len() + 1);
+    // Byte codes kept, with the addresses they were at.
+    let mut out = Vec::<(usize, ByteCode)>::with_capacity(byte_codes.len());
+    for (pos, byte_code) in std::mem::take(byte_codes).into_iter().enumerate() {
+        new_address.push(out.len());
+        if let ByteCode::FnCall(fn_id, arg_count) = &byte_code {
+            let arg_count = *arg_count as usize;
+            if executer.is_pure_fn(*fn_id) && !targets[pos] && out.len() >= arg_count {
+                let args_start = out.len() - arg_count;
+                // Jumping to the first argument still pushes all of them, which the result does as well.
+                let args = &out[args_start..];
+                let foldable = args.iter().skip(1).all(|(old_pos, _)| !targets[*old_pos]);
+                if let (true, Some(args)) = (foldable, args.iter().map(|(_, arg)| to_constant(arg)).collect::<Option<Vec<_>>>()) {
+                    if let Some(constant) = call_with_constants(executer, &context, *fn_id, args) {
+                        let old_pos = match out.get(args_start) {
+                            Some((old_pos, _)) => *old_pos,
+                            None => pos,
+                        };
+                        out.truncate(args_start);
+                        // Nothing jumps to the arguments left out, nor to the call.
+                        *new_address.last_mut().unwrap() = out.len(); // Never panics since an address was just pushed.
+                        out.push((old_pos, from_constant(constant)));
+                        folded += 1;
+                        continue;
+                    }
+                }
+            }
+        }
+        out.push((pos, byte_code));
+    }
+    new_address.push(out.len());
+    *byte_codes = out.into_iter().map(|(_, byte_code)| byte_code).collect();
+    remap_targets(byte_codes, &new_address);
+    return folded;
+}
+
+fn call_with_constants<B: DynamicValue+std::fmt::Debug>(
+    executer: &Executer<B>,
+    context: &NativeCallContext<B>,
+    fn_id: SIZE,
+    args: Vec<DynamicConstant>,
+) -> Option<DynamicConstant> {
+    let args = args.into_iter().map(|arg| B::from_constant(arg).map(|value| Rc::new(RefCell::new(value)))).collect::<crate::Result<Vec<_>>>().ok()?;
+    let res = executer.call_fn(fn_id, context, &args).ok()?;
+    return res.borrow().to_constant(); // Never panics when single-threaded.
+}
+
+// Fuses the byte codes of calls to binary functions with variables and integer constants, e.g. "x -= 1",
+// and of conditions like "x > 0" with their conditional jumps, returning the address each byte code comes from.
+//
+// Only the first byte code of a sequence may be jumped to. Fused byte codes keep the source position of the call.
+pub(crate) fn fuse<B: DynamicValue+std::fmt::Debug>(executer: &Executer<B>, byte_codes: &mut Vec<ByteCode>) -> Vec<usize> {
+    let targets = jump_targets(byte_codes);
+    let mut new_address = Vec::<usize>::with_capacity(byte_codes.len() + 1);
+    let mut origins = Vec::<usize>::with_capacity(byte_codes.len());
+    let mut out = Vec::<ByteCode>::with_capacity(byte_codes.len());
+    let mut pos = 0;
+    while pos < byte_codes.len() {
+        let fusable = |len: usize| pos + len <= byte_codes.len() && (pos + 1..pos + len).all(|next| !targets[next]);
+        let (byte_code, len) = match &byte_codes[pos..] {
+            [ByteCode::Variable(var_id), ByteCode::IntegerConstant(v), ByteCode::FnCall(fn_id, 2), ByteCode::JumpIfFalse(target), ..]
+                if executer.is_pure_fn(*fn_id) && fusable(4) =>
+            {
+                (ByteCode::JumpIfFalseVarInt(*fn_id, *var_id, *v, *target), 4)
+            }
+            [ByteCode::Variable(var_id), ByteCode::IntegerConstant(v), ByteCode::FnCall(fn_id, 2), ..] if fusable(3) => {
+                (ByteCode::FnCallVarInt(*fn_id, *var_id, *v), 3)
+            }
+            [ByteCode::Variable(var_id_a), ByteCode::Variable(var_id_b), ByteCode::FnCall(fn_id, 2), ..] if fusable(3) => {
+                (ByteCode::FnCallVarVar(*fn_id, *var_id_a, *var_id_b), 3)
+            }
+            _ => (byte_codes[pos].clone(), 1),
+        };
+        new_address.extend(std::iter::repeat_n(out.len(), len));
+        // The call of a fused sequence is its third byte code.
+        origins.push(if len > 1 { pos + 2 } else { pos });
+        out.push(byte_code);
+        pos += len;
+    }
+    new_address.push(out.len());
+    *byte_codes = out;
+    remap_targets(byte_codes, &new_address);
+    return origins;
+}
+
+// Whether the byte code at each address is jumped to, including the end of the byte codes.
+pub(crate) fn jump_targets(byte_codes: &[ByteCode]) -> Vec<bool> {
+    let mut targets = vec![false; byte_codes.len() + 1];
+    let mut mark = |target: &mut SIZE| {
+        if let Some(is_target) = targets.get_mut(*target as usize) {
+            *is_target = true;
+        }
+    };
+    for byte_code in byte_codes {
+        let mut byte_code = byte_code.clone();
+        if let ByteCode::Call(target, _) = &mut byte_code {
+            mark(target);
+        }
+        crate::for_each_jump_target(&mut byte_code, &mut mark);
+    }
+    return targets;
+}
+
+// Moves jump targets and script function addresses to the new addresses of the byte codes.
+pub(crate) fn remap_targets(byte_codes: &mut [ByteCode], new_address: &[usize]) {
+    let remap = |target: &mut SIZE| {
+        if let Some(address) = new_address.get(*target as usize) {
+            *target = *address as SIZE;
+        }
+    };
+    for byte_code in byte_codes.iter_mut() {
+        if let ByteCode::Call(target, _) = byte_code {
+            remap(target);
+        }
+        crate::for_each_jump_target(byte_code, remap);
+    }
+}
+
+// Value pushed by a constant byte code, not including `ByteCode::Constant`, whose table is not known.
+pub(crate) fn to_constant(byte_code: &ByteCode) -> Option<DynamicConstant> {
+    return match byte_code {
+        ByteCode::DynamicConstant(v) => Some(v.clone()),
+        ByteCode::UnitConstant => Some(DynamicConstant::Unit),
+        ByteCode::BoolConstant(v) => Some(DynamicConstant::Bool(*v)),
+        ByteCode::IntegerConstant(v) => Some(DynamicConstant::Integer(*v)),
+        ByteCode::FloatConstant(v) => Some(DynamicConstant::Float(*v)),
+        ByteCode::CharConstant(v) => Some(DynamicConstant::Char(*v)),
+        ByteCode::StringConstant(v) => Some(DynamicConstant::String(v.clone())),
+        _ => None,
+    };
+}
+
+pub(crate) fn from_constant(constant: DynamicConstant) -> ByteCode {
+    return match constant {
+        DynamicConstant::Unit => ByteCode::UnitConstant,
+        DynamicConstant::Bool(v) => ByteCode::BoolConstant(v),
+        DynamicConstant::Integer(v) => ByteCode::IntegerConstant(v),
+        DynamicConstant::Float(v) => ByteCode::FloatConstant(v),
+        DynamicConstant::Char(v) => ByteCode::CharConstant(v),
+        DynamicConstant::String(v) => ByteCode::StringConstant(v),
+        constant => ByteCode::DynamicConstant(constant),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_value::{compile, new_executer, Value};
+    use crate::run_byte_codes;
+
+    fn fn_id(executer: &crate::Executer<Value>, name: &str) -> SIZE {
+        return executer.fn_names.iter().position(|fn_name| fn_name == name).unwrap() as SIZE;
+    }
+
+    #[test]
+    fn jump_targets_are_remapped() {
+        let executer = new_executer();
+        let less = fn_id(&executer, "<");
+        let add_assign = fn_id(&executer, "+=");
+        let mut byte_codes = vec![
+            ByteCode::IntegerConstant(0),
+            ByteCode::VarInit(0),
+            ByteCode::PopStack,
+            ByteCode::Jump(4),
+            ByteCode::Variable(0),
+            ByteCode::IntegerConstant(3),
+            ByteCode::FnCall(less, 2),
+            ByteCode::JumpIfFalse(13),
+            ByteCode::Variable(0),
+            ByteCode::IntegerConstant(1),
+            ByteCode::FnCall(add_assign, 2),
+            ByteCode::PopStack,
+            ByteCode::Jump(4),
+            ByteCode::Variable(0),
+        ];
+        assert_eq!(optimize_byte_codes(&mut byte_codes, OptimizationLevel::Full), 2);
+        let expected = vec![
+            ByteCode::IntegerConstant(0),
+            ByteCode::VarStore(0),
+            ByteCode::Variable(0),
+            ByteCode::IntegerConstant(3),
+            ByteCode::FnCall(less, 2),
+            ByteCode::JumpIfFalse(11),
+            ByteCode::Variable(0),
+            ByteCode::IntegerConstant(1),
+            ByteCode::FnCall(add_assign, 2),
+            ByteCode::PopStack,
+            ByteCode::Jump(2),
+            ByteCode::Variable(0),
+        ];
+        assert_eq!(format!("{:?}", byte_codes), format!("{:?}", expected));
+        assert_eq!(run_byte_codes(&executer, &byte_codes, &[]).unwrap(), Value::Integer(3));
+    }
+
+    #[test]
+    fn dead_code_is_removed() {
+        let byte_codes = vec![
+            ByteCode::BoolConstant(true),
+            ByteCode::JumpIfFalse(4),
+            ByteCode::IntegerConstant(1),
+            ByteCode::Jump(5),
+            ByteCode::IntegerConstant(2),
+        ];
+        let mut simple = byte_codes.clone();
+        optimize_byte_codes(&mut simple, OptimizationLevel::Simple);
+        assert!(simple.len() < byte_codes.len());
+        let mut full = byte_codes.clone();
+        optimize_byte_codes(&mut full, OptimizationLevel::Full);
+        assert_eq!(format!("{:?}", full), format!("{:?}", vec![ByteCode::IntegerConstant(1)]));
+        let mut none = byte_codes.clone();
+        assert_eq!(optimize_byte_codes(&mut none, OptimizationLevel::None), 0);
+    }
+
+    #[test]
+    fn fusion() {
+        let mut executer = new_executer();
+        let script = "let x = 0; let y = 2; while x < 5 { x += 1; } x + y";
+        let mut variable_names = Vec::new();
+        let fused = crate::script_to_byte_codes(&executer, &mut variable_names, script).unwrap();
+        assert!(fused.iter().any(|byte_code| matches!(byte_code, ByteCode::JumpIfFalseVarInt(..))));
+        assert!(fused.iter().any(|byte_code| matches!(byte_code, ByteCode::FnCallVarInt(..))));
+        assert!(fused.iter().any(|byte_code| matches!(byte_code, ByteCode::FnCallVarVar(..))));
+        let text = crate::disassemble(&executer, &fused, &variable_names);
+        let assembled = crate::assemble(&executer, &mut Vec::new(), &text).unwrap();
+        assert_eq!(format!("{:?}", assembled), format!("{:?}", fused));
+        executer.set_fuse_instructions(false);
+        let unfused = compile(&executer, script);
+        let is_fused = |byte_code: &ByteCode| {
+            matches!(byte_code, ByteCode::JumpIfFalseVarInt(..) | ByteCode::FnCallVarInt(..) | ByteCode::FnCallVarVar(..))
+        };
+        assert!(!unfused.iter().any(is_fused));
+        assert!(fused.len() < unfused.len());
+        assert_eq!(run_byte_codes(&executer, &fused, &[]).unwrap(), Value::Integer(7));
+        assert_eq!(run_byte_codes(&executer, &unfused, &[]).unwrap(), Value::Integer(7));
+    }
+
+    #[test]
+    fn fusion_keeps_jump_targets_and_impure_conditions() {
+        let executer = new_executer();
+        let add = fn_id(&executer, "+");
+        let assign = fn_id(&executer, "=");
+        // The constant is jumped to, so the sequence cannot be fused.
+        let mut byte_codes = vec![
+            ByteCode::Variable(0),
+            ByteCode::IntegerConstant(1),
+            ByteCode::FnCall(add, 2),
+            ByteCode::Jump(1),
+        ];
+        let origins = fuse(&executer, &mut byte_codes);
+        assert_eq!(origins, vec![0, 1, 2, 3]);
+        assert!(matches!(byte_codes[3], ByteCode::Jump(1)));
+        // A function which is not pure is only fused with its operands, not with the jump.
+        let mut byte_codes = vec![
+            ByteCode::UnitConstant,
+            ByteCode::Variable(0),
+            ByteCode::IntegerConstant(1),
+            ByteCode::FnCall(assign, 2),
+            ByteCode::JumpIfFalse(0),
+        ];
+        let origins = fuse(&executer, &mut byte_codes);
+        assert_eq!(origins, vec![0, 3, 4]);
+        assert!(matches!(byte_codes[1], ByteCode::FnCallVarInt(id, 0, 1) if id == assign));
+        assert!(matches!(byte_codes[2], ByteCode::JumpIfFalse(0)));
+    }
+
+    #[test]
+    fn fused_calls_locate_errors() {
+        let executer = new_executer();
+        let byte_codes = compile(&executer, "let a = [1]; a + 1");
+        assert!(byte_codes.iter().any(|byte_code| matches!(byte_code, ByteCode::FnCallVarInt(..))));
+        match run_byte_codes(&executer, &byte_codes, &[]).map_err(|err| err.into_kind()) {
+            Err(crate::ErrorKind::Runtime(runtime_error)) => assert_eq!(runtime_error.host_function.as_deref(), Some("+")),
+            res => panic!("Unexpected result {:?}", res),
+        }
+        let byte_codes = compile(&executer, "let a = [1]; let b = 2; a + b");
+        assert!(byte_codes.iter().any(|byte_code| matches!(byte_code, ByteCode::FnCallVarVar(..))));
+        match run_byte_codes(&executer, &byte_codes, &[]).map_err(|err| err.into_kind()) {
+            Err(crate::ErrorKind::Runtime(runtime_error)) => assert_eq!(runtime_error.host_function.as_deref(), Some("+")),
+            res => panic!("Unexpected result {:?}", res),
+        }
+    }
+
+    #[cfg(not(feature = "no_function"))]
+    #[test]
+    fn optimized_programs_give_the_same_results() {
+        let executer = new_executer();
+        let script = "fn f(n) { if n > 2 { return n; } let m = n + 1; m } let s = 0; for i in 0..5 { if false { s += 100; } s += f(i); } s";
+        let mut program = CompiledProgram::from_script(&executer, &[], script).unwrap();
+        let expected = program.run(&executer, &[]).unwrap();
+        assert!(optimize(&mut program, OptimizationLevel::Full) > 0);
+        assert!(program.positions.iter().all(|(address, _)| (*address as usize) < program.byte_codes.len()));
+        assert_eq!(program.run(&executer, &[]).unwrap(), expected);
+    }
+}
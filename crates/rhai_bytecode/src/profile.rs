@@ -0,0 +1,131 @@
+//! Execution statistics of a run, for finding out where a script spends its time.
+//!
+//! A profiled run counts every byte code executed by address, and the calls and time of every host function.
+//! Byte codes are then counted one by one, like when debugging a `Vm`, so runs without profiling are not affected.
+
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+use crate::{ByteCode, DynamicValue, Executer};
+
+/// Calls of a host function or method.
+#[derive(Clone,Copy,Debug,Default)]
+pub struct HostCalls {
+    pub calls: u64,
+    /// Total time spent inside the function, including the script functions it calls back.
+    pub time: Duration,
+}
+
+/// Statistics of a run returned by `run_byte_codes_with_profile` or `LinkedProgram::run_with_profile`.
+#[derive(Clone,Debug,Default)]
+pub struct Profile {
+    /// Times each kind of byte code was executed, by name.
+    pub opcodes: BTreeMap<&'static str, u64>,
+    /// Host functions called, by name.
+    pub functions: BTreeMap<String, HostCalls>,
+    /// Host methods called, by name.
+    pub methods: BTreeMap<String, HostCalls>,
+    /// Times the byte code at each address was executed, including by callbacks from host functions.
+    pub addresses: Vec<u64>,
+    pub instructions: u64,
+    /// Time of the whole run.
+    pub time: Duration,
+}
+
+impl Profile {
+    /// Addresses executed most with their counts, at most `count` of them.
+    pub fn hot_addresses(&self, count: usize) -> Vec<(usize, u64)> {
+        let mut hot = self.addresses.iter().copied().enumerate().filter(|(_, n)| *n > 0).collect::<Vec<_>>();
+        hot.sort_by(|(pa, na), (pb, nb)| nb.cmp(na).then(pa.cmp(pb)));
+        hot.truncate(count);
+        return hot;
+    }
+    /// Time spent outside host functions, i.e. dispatching byte codes and allocating values.
+    ///
+    /// Host functions called back by other ones are counted twice, so this is less accurate for callbacks.
+    pub fn dispatch_time(&self) -> Duration {
+        let host_time = self.functions.values().chain(self.methods.values()).map(|calls| calls.time).sum::<Duration>();
+        return self.time.saturating_sub(host_time);
+    }
+}
+
+impl std::fmt::Display for Profile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{} instructions in {:?}, {:?} outside of host functions", self.instructions, self.time, self.dispatch_time())?;
+        let mut opcodes = self.opcodes.iter().collect::<Vec<_>>();
+        opcodes.sort_by(|(_, na), (_, nb)| nb.cmp(na));
+        writeln!(f, "Byte codes:")?;
+        for (name, count) in opcodes {
+            writeln!(f, "{:>12}  {}", count, name)?;
+        }
+        for (type_str, calls) in [("Functions", &self.functions), ("Methods", &self.methods)] {
+            if calls.is_empty() {
+                continue;
+            }
+            writeln!(f, "{}:", type_str)?;
+            let mut calls = calls.iter().collect::<Vec<_>>();
+            calls.sort_by_key(|(_, calls)| std::cmp::Reverse(calls.time));
+            for (name, calls) in calls {
+                writeln!(f, "{:>12}  {:?}  {:?}", calls.calls, name, calls.time)?;
+            }
+        }
+        writeln!(f, "Hot addresses:")?;
+        for (address, count) in self.hot_addresses(10) {
+            writeln!(f, "{:>12}  {}", count, address)?;
+        }
+        return Ok(());
+    }
+}
+
+// Counts recorded while running, indexed like the byte codes and the `Executer`.
+pub(crate) struct Profiler {
+    addresses: Vec<u64>,
+    functions: Vec<HostCalls>,
+    methods: Vec<HostCalls>,
+    start: Instant,
+}
+
+impl Profiler {
+    pub(crate) fn new<B: DynamicValue+std::fmt::Debug>(executer: &Executer<B>, byte_code_count: usize) -> Self {
+        return Self {
+            addresses: vec![0; byte_code_count],
+            functions: vec![HostCalls::default(); executer.fn_names.len()],
+            methods: vec![HostCalls::default(); executer.method_names.len()],
+            start: Instant::now(),
+        };
+    }
+    pub(crate) fn record(&mut self, pos: usize) {
+        if let Some(count) = self.addresses.get_mut(pos) {
+            *count += 1;
+        }
+    }
+    pub(crate) fn record_call(&mut self, is_method: bool, index: usize, time: Duration) {
+        let calls = if is_method { &mut self.methods } else { &mut self.functions };
+        if let Some(calls) = calls.get_mut(index) {
+            calls.calls += 1;
+            calls.time += time;
+        }
+    }
+    pub(crate) fn into_profile<B: DynamicValue+std::fmt::Debug>(self, executer: &Executer<B>, byte_codes: &[ByteCode]) -> Profile {
+        let time = self.start.elapsed();
+        let mut opcodes = BTreeMap::<&'static str, u64>::new();
+        for (byte_code, count) in byte_codes.iter().zip(self.addresses.iter()) {
+            if *count > 0 {
+                *opcodes.entry(byte_code.name()).or_default() += *count;
+            }
+        }
+        let named = |names: &[String], calls: Vec<HostCalls>| {
+            return names.iter().cloned().zip(calls).filter(|(_, calls)| calls.calls > 0).collect::<BTreeMap<_, _>>();
+        };
+        return Profile {
+            opcodes,
+            functions: named(&executer.fn_names, self.functions),
+            methods: named(&executer.method_names, self.methods),
+            instructions: self.addresses.iter().sum(),
+            addresses: self.addresses,
+            time,
+        };
+    }
+}
@@ -0,0 +1,206 @@
+//! Small `DynamicValue` implementation and `Executer` for the unit tests, supporting integers, booleans and arrays.
+
+use std::{cell::RefCell, collections::BTreeMap, rc::Rc};
+
+use crate::{
+    script_to_byte_codes, ByteCode, DynamicConstant, DynamicValue, Error, ErrorKind, Executer, FnPtr, Result, FLOAT, INT,
+    SIZE, VEC,
+};
+
+#[derive(Clone,Debug,PartialEq)]
+pub(crate) enum Value {
+    Unit,
+    Bool(bool),
+    Integer(INT),
+    Array(Vec<Rc<RefCell<Value>>>),
+    Range(INT,INT),
+}
+
+impl DynamicValue for Value {
+    fn from_constant(v:DynamicConstant) -> Result<Self> {
+        match v {
+            DynamicConstant::Unit => {
+                return Ok(Self::Unit);
+            }
+            DynamicConstant::Bool(v) => {
+                return Ok(Self::Bool(v));
+            }
+            DynamicConstant::Integer(v) => {
+                return Ok(Self::Integer(v));
+            }
+            DynamicConstant::Array(ary) => {
+                let mut items = Vec::with_capacity(ary.len());
+                for v in ary.iter() {
+                    items.push(Rc::new(RefCell::new(Self::from_constant(v.clone())?)));
+                }
+                return Ok(Self::Array(items));
+            }
+            DynamicConstant::Range(start, len) => {
+                return Ok(Self::Range(start, len));
+            }
+            _ => {
+                return Err(Error::type_error(format_args!("Unsupported constant \"{:?}\"!", v)));
+            }
+        }
+    }
+    fn from_unit() -> Result<Self> {
+        return Ok(Self::Unit);
+    }
+    fn from_bool(v:bool) -> Result<Self> {
+        return Ok(Self::Bool(v));
+    }
+    fn from_integer(v:INT) -> Result<Self> {
+        return Ok(Self::Integer(v));
+    }
+    fn from_float(v:FLOAT) -> Result<Self> {
+        return Err(Error::type_error(format_args!("Unsupported float \"{}\"!", v)));
+    }
+    fn from_char(v:char) -> Result<Self> {
+        return Err(Error::type_error(format_args!("Unsupported char \"{}\"!", v)));
+    }
+    fn from_string(v:String) -> Result<Self> {
+        return Err(Error::type_error(format_args!("Unsupported string \"{}\"!", v)));
+    }
+    fn from_array(v:VEC<Rc<RefCell<Self>>>) -> Result<Self> {
+        return Ok(Self::Array(v.into_iter().collect()));
+    }
+    fn from_map(_v:BTreeMap<String,Rc<RefCell<Self>>>) -> Result<Self> {
+        return Err(Error::type_error("Unsupported map!"));
+    }
+    fn from_fn_ptr(v:FnPtr<Self>) -> Result<Self> {
+        return Err(Error::type_error(format_args!("Unsupported function pointer \"{}\"!", v.name)));
+    }
+    fn is_unit(&self) -> bool {
+        return *self == Self::Unit;
+    }
+    fn to_constant(&self) -> Option<DynamicConstant> {
+        match self {
+            Self::Unit => {
+                return Some(DynamicConstant::Unit);
+            }
+            Self::Bool(v) => {
+                return Some(DynamicConstant::Bool(*v));
+            }
+            Self::Integer(v) => {
+                return Some(DynamicConstant::Integer(*v));
+            }
+            _ => {
+                return None;
+            }
+        }
+    }
+    fn to_bool(&self) -> Result<bool> {
+        match self {
+            Self::Bool(v) => {
+                return Ok(*v);
+            }
+            _ => {
+                return Err(Error::type_error(format_args!("Cannot convert \"{:?}\" to bool!", self)));
+            }
+        }
+    }
+    fn to_size(&self) -> Result<SIZE> {
+        match self {
+            Self::Integer(v) => {
+                return Ok(*v as SIZE);
+            }
+            _ => {
+                return Err(Error::type_error(format_args!("Cannot convert \"{:?}\" to size!", self)));
+            }
+        }
+    }
+    fn to_fn_ptr(&self) -> Result<FnPtr<Self>> {
+        return Err(Error::type_error(format_args!("Cannot convert \"{:?}\" to function pointer!", self)));
+    }
+    fn to_display_string(&self) -> Result<String> {
+        return Ok(format!("{:?}", self));
+    }
+    fn index_into(&self,ind:SIZE) -> Result<Rc<RefCell<Self>>> {
+        match self {
+            Self::Array(items) if (ind as usize) < items.len() => {
+                return Ok(items[ind as usize].clone());
+            }
+            _ => {
+                return Err(Error::type_error(format_args!("Cannot index into \"{:?}\"!", self)));
+            }
+        }
+    }
+    fn get_property(&self,name:&str) -> Result<Rc<RefCell<Self>>> {
+        return Err(Error::type_error(format_args!("Cannot get property \"{}\"!", name)));
+    }
+    fn set_property(&mut self,name:&str,_value:Rc<RefCell<Self>>) -> Result<()> {
+        return Err(Error::type_error(format_args!("Cannot set property \"{}\"!", name)));
+    }
+    fn iter(&self,index:SIZE) -> Result<Option<Rc<RefCell<Self>>>> {
+        match self {
+            Self::Array(items) => {
+                return Ok(items.get(index as usize).cloned());
+            }
+            Self::Range(start, len) if (index as INT) < *len => {
+                return Ok(Some(Rc::new(RefCell::new(Self::Integer(*start + index as INT)))));
+            }
+            Self::Range(..) => {
+                return Ok(None);
+            }
+            _ => {
+                return Err(Error::type_error(format_args!("Cannot iterate over \"{:?}\"!", self)));
+            }
+        }
+    }
+    fn deep_clone(&self) -> Self {
+        match self {
+            Self::Array(items) => {
+                return Self::Array(items.iter().map(|v| Rc::new(RefCell::new(v.borrow().deep_clone()))).collect()); // Never panics when single-threaded.
+            }
+            _ => {
+                return self.clone();
+            }
+        }
+    }
+}
+
+fn integers(args: &[Rc<RefCell<Value>>]) -> Result<(INT,INT)> {
+    match (&*args[0].borrow(), &*args[1].borrow()) { // Never panics when single-threaded.
+        (Value::Integer(a), Value::Integer(b)) => {
+            return Ok((*a, *b));
+        }
+        (a, b) => {
+            return Err(Error::type_error(format_args!("Expected integers, not \"{:?}\" and \"{:?}\"!", a, b)));
+        }
+    }
+}
+
+fn new_value(value: Value) -> Result<Rc<RefCell<Value>>> {
+    return Ok(Rc::new(RefCell::new(value)));
+}
+
+/// Executer with integer arithmetic, comparisons, assignments, and "wait_for(v)" which suspends a `Vm` with `v`.
+pub(crate) fn new_executer() -> Executer<Value> {
+    let mut executer = Executer::<Value>::new();
+    executer.add_pure_fn("+", |args| integers(args).and_then(|(a, b)| new_value(Value::Integer(a + b))), 2, 2).unwrap();
+    executer.add_pure_fn("-", |args| integers(args).and_then(|(a, b)| new_value(Value::Integer(a - b))), 2, 2).unwrap();
+    executer.add_pure_fn("<", |args| integers(args).and_then(|(a, b)| new_value(Value::Bool(a < b))), 2, 2).unwrap();
+    executer.add_pure_fn(">", |args| integers(args).and_then(|(a, b)| new_value(Value::Bool(a > b))), 2, 2).unwrap();
+    executer.add_pure_fn("==", |args| integers(args).and_then(|(a, b)| new_value(Value::Bool(a == b))), 2, 2).unwrap();
+    executer.add_pure_fn("..", |args| integers(args).and_then(|(a, b)| new_value(Value::Range(a, b - a))), 2, 2).unwrap();
+    executer.add_fn("=", |args| {
+        let value = args[1].borrow().clone(); // Never panics when single-threaded.
+        *args[0].borrow_mut() = value; // Never panics when single-threaded.
+        return Ok(args[0].clone());
+    }, 2, 2).unwrap();
+    executer.add_fn("+=", |args| {
+        let (a, b) = integers(args)?;
+        *args[0].borrow_mut() = Value::Integer(a + b); // Never panics when single-threaded.
+        return Ok(args[0].clone());
+    }, 2, 2).unwrap();
+    executer.add_fn("wait_for", |args| {
+        let value = args[0].borrow().to_constant().unwrap_or(DynamicConstant::Unit); // Never panics when single-threaded.
+        return Err(ErrorKind::Yield(value).into());
+    }, 1, 1).unwrap();
+    return executer;
+}
+
+/// Compiles a script with `new_executer`, panicking on errors.
+pub(crate) fn compile(executer: &Executer<Value>, script: &str) -> Vec<ByteCode> {
+    return script_to_byte_codes(executer, &mut Vec::new(), script).unwrap();
+}
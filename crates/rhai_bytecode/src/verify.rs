@@ -0,0 +1,498 @@
+//! Static checks of byte codes, so that programs from untrusted sources can be run safely.
+//!
+//! The code is split into the main script, and script functions each starting with `ByteCode::Enter`.
+//! Jumps must stay within their part, and every path into an instruction must reach it with the same stack depth.
+
+use crate::{ByteCode, Executer, DynamicValue, SIZE};
+
+/// Problem found by `verify`.
+#[derive(Clone,Debug,PartialEq)]
+pub enum VerifyErrorKind {
+    /// Jump target outside of the main script or function containing the jump.
+    InvalidJumpTarget(SIZE),
+    /// `ByteCode::Call` target which is not the `ByteCode::Enter` of a function with this many parameters.
+    InvalidCallTarget(SIZE),
+    /// Variable index beyond those allocated.
+    InvalidVariable(SIZE),
+    /// More variables allocated by the main script or a `ByteCode::Enter` than `MAX_VARIABLE_COUNT`.
+    TooManyVariables(usize),
+    /// `ByteCode::Iter` using the same variable for different purposes.
+    InvalidIterOperands,
+    InvalidFunction(SIZE),
+    InvalidMethod(SIZE),
+    InvalidArgCount(String, SIZE),
+    InvalidConstant(SIZE),
+    /// `ByteCode::JumpIfFalseVarInt` calling a host function which is not pure, and so might yield inside the jump.
+    ImpureCondition(String),
+    /// More values popped than on the stack.
+    StackUnderflow { needed: usize, depth: usize },
+    /// Paths reaching the same instruction with different stack depths.
+    StackDepthMismatch { expected: usize, found: usize },
+    /// Execution runs past the end of a function, or from the main script into a function.
+    FallThrough,
+}
+
+#[derive(Clone,Debug,PartialEq)]
+pub struct VerifyError {
+    pub pos: usize,
+    pub kind: VerifyErrorKind,
+}
+
+impl std::fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: ", self.pos)?;
+        match &self.kind {
+            VerifyErrorKind::InvalidJumpTarget(target) => write!(f, "Invalid jump target {}!", target),
+            VerifyErrorKind::InvalidCallTarget(target) => write!(f, "Invalid call target {}!", target),
+            VerifyErrorKind::InvalidVariable(var_id) => write!(f, "Variable #{} is not allocated!", var_id),
+            VerifyErrorKind::TooManyVariables(count) => write!(f, "{} variables exceed the limit!", count),
+            VerifyErrorKind::InvalidIterOperands => write!(f, "Loop variables are not distinct!"),
+            VerifyErrorKind::InvalidFunction(fn_id) => write!(f, "Function #{} does not exist!", fn_id),
+            VerifyErrorKind::InvalidMethod(method_id) => write!(f, "Method #{} does not exist!", method_id),
+            VerifyErrorKind::InvalidArgCount(name, arg_count) => {
+                write!(f, "\"{}\" does not accept {} arguments!", name, arg_count)
+            }
+            VerifyErrorKind::InvalidConstant(index) => write!(f, "Constant #{} does not exist!", index),
+            VerifyErrorKind::ImpureCondition(name) => write!(f, "\"{}\" is not pure, so it cannot be fused into a jump!", name),
+            VerifyErrorKind::StackUnderflow { needed, depth } => {
+                write!(f, "Stack underflow, {} values needed but {} available!", needed, depth)
+            }
+            VerifyErrorKind::StackDepthMismatch { expected, found } => {
+                write!(f, "Stack depth {} differs from {} on another path!", found, expected)
+            }
+            VerifyErrorKind::FallThrough => write!(f, "Execution falls through the end of the code!"),
+        }
+    }
+}
+
+/// Result of `verify`.
+#[derive(Clone,Debug,Default)]
+pub struct VerifyReport {
+    pub errors: Vec<VerifyError>,
+    /// Deepest stack reached by the main script or a single function call.
+    pub max_stack_depth: usize,
+}
+
+impl VerifyReport {
+    pub fn is_valid(&self) -> bool {
+        return self.errors.is_empty();
+    }
+    /// Fails with `ErrorKind::Verify` unless valid, for use with `?`.
+    pub fn check(self) -> crate::Result<()> {
+        if self.is_valid() {
+            return Ok(());
+        }
+        return Err(self.into());
+    }
+}
+
+impl std::fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.errors.is_empty() {
+            return write!(f, "Byte codes are valid.");
+        }
+        write!(f, "Invalid byte codes:")?;
+        for error in self.errors.iter() {
+            write!(f, "\n{}", error)?;
+        }
+        return Ok(());
+    }
+}
+
+impl std::error::Error for VerifyReport {}
+
+/// Most variables the main script or a single function call may allocate.
+pub const MAX_VARIABLE_COUNT: usize = 1 << 16;
+
+/// Checks byte codes for `run_byte_codes`, which makes any `ByteCode::Constant` invalid.
+pub fn verify<B: DynamicValue+std::fmt::Debug>(executer: &Executer<B>, byte_codes: &[ByteCode]) -> VerifyReport {
+    return verify_with_constants(executer, byte_codes, 0, MAX_VARIABLE_COUNT);
+}
+
+// Start and end of the main script or a function.
+#[derive(Clone,Copy)]
+struct Part {
+    start: usize,
+    end: usize,
+    in_function: bool,
+    // Variables allocated.
+    var_count: usize,
+}
+
+pub(crate) fn verify_with_constants<B: DynamicValue+std::fmt::Debug>(
+    executer: &Executer<B>,
+    byte_codes: &[ByteCode],
+    constant_count: usize,
+    variable_count: usize,
+) -> VerifyReport {
+    let mut report = VerifyReport::default();
+    let len = byte_codes.len();
+    let mut parts = Vec::<Part>::new();
+    let mut start = 0;
+    let mut in_function = false;
+    let mut var_count = variable_count;
+    if var_count > MAX_VARIABLE_COUNT {
+        report.errors.push(VerifyError { pos: 0, kind: VerifyErrorKind::TooManyVariables(var_count) });
+    }
+    for (pos, byte_code) in byte_codes.iter().enumerate() {
+        if let ByteCode::Enter(_, param_count, count) = byte_code {
+            parts.push(Part { start, end: pos, in_function, var_count });
+            start = pos;
+            in_function = true;
+            var_count = (*param_count).max(*count) as usize;
+            if var_count > MAX_VARIABLE_COUNT {
+                report.errors.push(VerifyError { pos, kind: VerifyErrorKind::TooManyVariables(var_count) });
+            }
+        }
+    }
+    parts.push(Part { start, end: len, in_function, var_count });
+    for part in parts.iter() {
+        for pos in part.start..part.end {
+            check_operands(executer, byte_codes, constant_count, part, pos, &mut report.errors);
+        }
+    }
+    for part in parts.iter() {
+        check_stack(byte_codes, part, &mut report);
+    }
+    report.errors.sort_by_key(|error| error.pos);
+    return report;
+}
+
+fn is_valid_target(part: &Part, len: usize, target: SIZE) -> bool {
+    let target = target as usize;
+    if part.in_function {
+        return target > part.start && target < part.end;
+    }
+    return target < part.end || target == len;
+}
+
+// Checks indices and jump targets of a single instruction.
+fn check_operands<B: DynamicValue+std::fmt::Debug>(
+    executer: &Executer<B>,
+    byte_codes: &[ByteCode],
+    constant_count: usize,
+    part: &Part,
+    pos: usize,
+    errors: &mut Vec<VerifyError>,
+) {
+    let mut error = |kind| errors.push(VerifyError { pos, kind });
+    let check_variable = |var_id: SIZE| (var_id as usize) < part.var_count;
+    let mut byte_code = byte_codes[pos].clone();
+    let mut targets = Vec::<SIZE>::new();
+    crate::for_each_jump_target(&mut byte_code, |target| targets.push(*target));
+    for target in targets {
+        if !is_valid_target(part, byte_codes.len(), target) {
+            error(VerifyErrorKind::InvalidJumpTarget(target));
+        }
+    }
+    match &byte_code {
+        ByteCode::Constant(index) if *index as usize >= constant_count => {
+            error(VerifyErrorKind::InvalidConstant(*index));
+        }
+        ByteCode::Variable(var_id) | ByteCode::VarInit(var_id) | ByteCode::VarStore(var_id) if !check_variable(*var_id) => {
+            error(VerifyErrorKind::InvalidVariable(*var_id));
+        }
+        ByteCode::Iter(loop_range_id, loop_index_id, loop_var_id, _) => {
+            for var_id in [loop_range_id, loop_index_id, loop_var_id] {
+                if !check_variable(*var_id) {
+                    error(VerifyErrorKind::InvalidVariable(*var_id));
+                }
+            }
+            if loop_range_id == loop_index_id || loop_range_id == loop_var_id || loop_index_id == loop_var_id {
+                error(VerifyErrorKind::InvalidIterOperands);
+            }
+        }
+        ByteCode::FnCall(fn_id, arg_count) if executer.check_fn_arg_count(*fn_id, *arg_count).is_err() => {
+            match executer.fn_names.get(*fn_id as usize) {
+                Some(name) => error(VerifyErrorKind::InvalidArgCount(name.to_owned(), *arg_count)),
+                None => error(VerifyErrorKind::InvalidFunction(*fn_id)),
+            }
+        }
+        ByteCode::FnCallVarInt(fn_id, var_id, _) | ByteCode::JumpIfFalseVarInt(fn_id, var_id, _, _) => {
+            check_binary_fn(executer, *fn_id, &mut error);
+            if !check_variable(*var_id) {
+                error(VerifyErrorKind::InvalidVariable(*var_id));
+            }
+            // The compiler only fuses pure functions, but the program may be linked against another `Executer`.
+            if matches!(byte_code, ByteCode::JumpIfFalseVarInt(..)) && !executer.is_pure_fn(*fn_id) {
+                if let Some(name) = executer.fn_names.get(*fn_id as usize) {
+                    error(VerifyErrorKind::ImpureCondition(name.to_owned()));
+                }
+            }
+        }
+        ByteCode::FnCallVarVar(fn_id, var_id_a, var_id_b) => {
+            check_binary_fn(executer, *fn_id, &mut error);
+            for var_id in [var_id_a, var_id_b] {
+                if !check_variable(*var_id) {
+                    error(VerifyErrorKind::InvalidVariable(*var_id));
+                }
+            }
+        }
+        ByteCode::MethodCall(method_id, arg_count) if executer.check_method_arg_count(*method_id, *arg_count).is_err() => {
+            match executer.method_names.get(*method_id as usize) {
+                Some(name) => error(VerifyErrorKind::InvalidArgCount(name.to_owned(), *arg_count)),
+                None => error(VerifyErrorKind::InvalidMethod(*method_id)),
+            }
+        }
+        ByteCode::Call(target, arg_count) => match byte_codes.get(*target as usize) {
+            Some(ByteCode::Enter(_, param_count, _)) if param_count == arg_count => {}
+            _ => error(VerifyErrorKind::InvalidCallTarget(*target)),
+        },
+        _ => {}
+    }
+}
+
+// Checks the function of a fused byte code, which is called with 2 arguments.
+fn check_binary_fn<B: DynamicValue+std::fmt::Debug>(executer: &Executer<B>, fn_id: SIZE, error: &mut impl FnMut(VerifyErrorKind)) {
+    if executer.check_fn_arg_count(fn_id, 2).is_err() {
+        match executer.fn_names.get(fn_id as usize) {
+            Some(name) => error(VerifyErrorKind::InvalidArgCount(name.to_owned(), 2)),
+            None => error(VerifyErrorKind::InvalidFunction(fn_id)),
+        }
+    }
+}
+
+// Values popped and pushed by an instruction.
+fn stack_effect(byte_code: &ByteCode, in_function: bool) -> (usize, usize) {
+    return match byte_code {
+        ByteCode::Constant(_)
+        | ByteCode::DynamicConstant(_)
+        | ByteCode::UnitConstant
+        | ByteCode::BoolConstant(_)
+        | ByteCode::IntegerConstant(_)
+        | ByteCode::FloatConstant(_)
+        | ByteCode::CharConstant(_)
+        | ByteCode::StringConstant(_)
+        | ByteCode::Variable(_)
+        | ByteCode::FnPtr(_)
+        | ByteCode::FnCallVarInt(..)
+        | ByteCode::FnCallVarVar(..) => (0, 1),
+        ByteCode::InterpolatedString(count)
+        | ByteCode::ConstructArray(count)
+        | ByteCode::FnCall(_, count)
+        | ByteCode::MethodCall(_, count)
+        | ByteCode::Call(_, count) => (*count as usize, 1),
+        ByteCode::ConstructMap(keys) => (keys.len(), 1),
+        ByteCode::CallPtr(count) | ByteCode::Curry(count) | ByteCode::Closure(count) => (*count as usize + 1, 1),
+        ByteCode::JumpIfTrue(_) | ByteCode::JumpIfFalse(_) | ByteCode::JumpIfNotNull(_) => (1, 0),
+        ByteCode::VarInit(_) | ByteCode::GetProperty(_) => (1, 1),
+        ByteCode::Index => (2, 1),
+        ByteCode::SetProperty(_) => (2, 0),
+        ByteCode::Switch(_) | ByteCode::Throw | ByteCode::PopStack | ByteCode::VarStore(_) => (1, 0),
+        // The main script returns the top of the stack, a function returns unit if there is none.
+        ByteCode::Return => (if in_function { 0 } else { 1 }, 0),
+        ByteCode::Jump(_)
+        | ByteCode::Iter(..)
+        | ByteCode::PushHandler(_)
+        | ByteCode::PopHandler
+        | ByteCode::Enter(..)
+        | ByteCode::JumpIfFalseVarInt(..) => (0, 0),
+    };
+}
+
+// Follows every path through a part, tracking the stack depth.
+fn check_stack(byte_codes: &[ByteCode], part: &Part, report: &mut VerifyReport) {
+    if part.start == part.end {
+        return;
+    }
+    let len = byte_codes.len();
+    let in_function = part.in_function;
+    let mut depths = vec![None::<usize>; part.end - part.start];
+    let mut pending = vec![(part.start, 0)];
+    while let Some((pos, depth)) = pending.pop() {
+        match depths[pos - part.start] {
+            Some(expected) => {
+                if expected != depth {
+                    report.errors.push(VerifyError {
+                        pos,
+                        kind: VerifyErrorKind::StackDepthMismatch { expected, found: depth },
+                    });
+                }
+                continue;
+            }
+            None => {
+                depths[pos - part.start] = Some(depth);
+            }
+        }
+        let byte_code = &byte_codes[pos];
+        let (pops, pushes) = stack_effect(byte_code, in_function);
+        if depth < pops {
+            report.errors.push(VerifyError {
+                pos,
+                kind: VerifyErrorKind::StackUnderflow { needed: pops, depth },
+            });
+            continue;
+        }
+        let new_depth = depth - pops + pushes;
+        report.max_stack_depth = report.max_stack_depth.max(new_depth);
+        let mut next = vec![];
+        let mut falls_through = true;
+        match byte_code {
+            ByteCode::Jump(_) | ByteCode::Switch(_) | ByteCode::Throw | ByteCode::Return => {
+                falls_through = false;
+            }
+            ByteCode::PushHandler(target) => {
+                // A caught exception restores the stack and pushes the thrown value.
+                next.push((*target as usize, depth + 1));
+            }
+            _ => {}
+        }
+        let mut byte_code = byte_code.clone();
+        if !matches!(byte_code, ByteCode::PushHandler(_)) {
+            crate::for_each_jump_target(&mut byte_code, |target| next.push((*target as usize, new_depth)));
+        }
+        if falls_through {
+            if pos + 1 == part.end && (in_function || part.end < len) {
+                report.errors.push(VerifyError { pos, kind: VerifyErrorKind::FallThrough });
+            } else {
+                next.push((pos + 1, new_depth));
+            }
+        }
+        for (target, depth) in next {
+            // Invalid targets are reported by `check_operands`, and the end of the script needs no checks.
+            if target < part.end && is_valid_target(part, len, target as SIZE) {
+                pending.push((target, depth));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_value::new_executer;
+    use crate::{CompiledProgram, ErrorKind};
+
+    fn error_kinds(byte_codes: &[ByteCode]) -> Vec<VerifyErrorKind> {
+        return verify(&new_executer(), byte_codes).errors.into_iter().map(|error| error.kind).collect();
+    }
+
+    #[cfg(not(feature = "no_function"))]
+    #[test]
+    fn compiled_scripts_are_valid() {
+        let executer = new_executer();
+        let byte_codes = crate::test_value::compile(&executer, "fn f(a, b) { let c = a + b; c } let x = 0; for i in 0..3 { x += f(i, 1); } x");
+        let report = verify(&executer, &byte_codes);
+        assert!(report.is_valid(), "{}", report);
+        assert!(report.max_stack_depth > 0);
+    }
+
+    #[test]
+    fn invalid_jump_targets() {
+        assert_eq!(error_kinds(&[ByteCode::Jump(5)]), vec![VerifyErrorKind::InvalidJumpTarget(5)]);
+        // A function may not jump into the main script.
+        let byte_codes = [
+            ByteCode::UnitConstant,
+            ByteCode::Return,
+            ByteCode::Enter("f".to_string(), 0, 0),
+            ByteCode::Jump(0),
+        ];
+        assert!(error_kinds(&byte_codes).contains(&VerifyErrorKind::InvalidJumpTarget(0)));
+    }
+
+    #[test]
+    fn invalid_operands() {
+        let byte_codes = [
+            ByteCode::UnitConstant,
+            ByteCode::Return,
+            ByteCode::Enter("f".to_string(), 1, 1),
+            ByteCode::Variable(1),
+            ByteCode::Return,
+        ];
+        assert_eq!(error_kinds(&byte_codes), vec![VerifyErrorKind::InvalidVariable(1)]);
+        let byte_codes = [
+            ByteCode::UnitConstant,
+            ByteCode::Return,
+            ByteCode::Enter("f".to_string(), 1, 2),
+            ByteCode::FnCallVarVar(0, 1, 2),
+            ByteCode::Return,
+        ];
+        assert_eq!(error_kinds(&byte_codes), vec![VerifyErrorKind::InvalidVariable(2)]);
+        let byte_codes = [ByteCode::IntegerConstant(1), ByteCode::FnCall(99, 1)];
+        assert_eq!(error_kinds(&byte_codes), vec![VerifyErrorKind::InvalidFunction(99)]);
+        assert_eq!(error_kinds(&[ByteCode::Constant(0)]), vec![VerifyErrorKind::InvalidConstant(0)]);
+    }
+
+    #[test]
+    fn stack_depth_errors() {
+        assert_eq!(error_kinds(&[ByteCode::PopStack]), vec![VerifyErrorKind::StackUnderflow { needed: 1, depth: 0 }]);
+        // One path pushes a value before reaching the end, the other does not.
+        let byte_codes = [
+            ByteCode::BoolConstant(true),
+            ByteCode::JumpIfFalse(3),
+            ByteCode::IntegerConstant(1),
+            ByteCode::IntegerConstant(2),
+        ];
+        assert_eq!(error_kinds(&byte_codes), vec![VerifyErrorKind::StackDepthMismatch { expected: 1, found: 0 }]);
+    }
+
+    #[test]
+    fn link_rejects_invalid_programs() {
+        let executer = new_executer();
+        let mut program = CompiledProgram::from_script(&executer, &[], "let x = 1; x").unwrap();
+        assert!(program.link(&executer).is_ok());
+        program.byte_codes.insert(0, ByteCode::Jump(100));
+        match program.link(&executer).map(|_| ()).map_err(|err| err.into_kind()) {
+            Err(ErrorKind::Verify(report)) => assert_eq!(report.errors[0].kind, VerifyErrorKind::InvalidJumpTarget(100)),
+            res => panic!("Unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn link_checks_main_script_variables() {
+        let executer = new_executer();
+        let program = CompiledProgram::from_script(&executer, &[], "let x = 1; x").unwrap();
+        assert_eq!(program.variable_count, 1);
+        for var_id in [1, 1_000_000, SIZE::MAX] {
+            let mut program = program.clone();
+            program.byte_codes.insert(0, ByteCode::Variable(var_id));
+            program.byte_codes.insert(1, ByteCode::PopStack);
+            match program.link(&executer).map(|_| ()).map_err(|err| err.into_kind()) {
+                Err(ErrorKind::Verify(report)) => assert_eq!(report.errors[0].kind, VerifyErrorKind::InvalidVariable(var_id)),
+                res => panic!("Unexpected result {:?}", res),
+            }
+        }
+        let mut program = program.clone();
+        program.variable_count = SIZE::MAX;
+        match program.link(&executer).map(|_| ()).map_err(|err| err.into_kind()) {
+            Err(ErrorKind::Verify(report)) => {
+                assert_eq!(report.errors[0].kind, VerifyErrorKind::TooManyVariables(SIZE::MAX as usize));
+            }
+            res => panic!("Unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn too_many_variables() {
+        let byte_codes = [
+            ByteCode::UnitConstant,
+            ByteCode::Return,
+            ByteCode::Enter("f".to_string(), 0, SIZE::MAX),
+            ByteCode::UnitConstant,
+            ByteCode::Return,
+        ];
+        assert_eq!(error_kinds(&byte_codes), vec![VerifyErrorKind::TooManyVariables(SIZE::MAX as usize)]);
+        // Unverified byte codes fail instead of overflowing the variable count.
+        let byte_codes = [ByteCode::Variable(SIZE::MAX), ByteCode::Return];
+        assert_eq!(error_kinds(&byte_codes), vec![VerifyErrorKind::InvalidVariable(SIZE::MAX)]);
+        let res = crate::run_byte_codes(&new_executer(), &byte_codes.to_vec(), &[]).map_err(|err| err.into_kind());
+        assert!(matches!(res, Err(ErrorKind::InvalidByteCode(_))), "{:?}", res);
+    }
+
+    #[test]
+    fn fused_conditions_need_pure_functions() {
+        let executer = new_executer();
+        let program = CompiledProgram::from_script(&executer, &[], "let x = 0; while x < 5 { x += 1; } x").unwrap();
+        assert!(program.byte_codes.iter().any(|byte_code| matches!(byte_code, ByteCode::JumpIfFalseVarInt(..))));
+        // The same functions, but "<" is not registered as pure.
+        let mut impure_executer = Executer::<crate::test_value::Value>::new();
+        impure_executer.add_fn("<", |args| Ok(args[0].clone()), 2, 2).unwrap();
+        impure_executer.add_fn("+=", |args| Ok(args[0].clone()), 2, 2).unwrap();
+        match program.link(&impure_executer).map(|_| ()).map_err(|err| err.into_kind()) {
+            Err(ErrorKind::Verify(report)) => {
+                assert_eq!(report.errors[0].kind, VerifyErrorKind::ImpureCondition("<".to_string()));
+            }
+            res => panic!("Unexpected result {:?}", res),
+        }
+    }
+}
@@ -0,0 +1,589 @@
+//! Runs which host functions can suspend, e.g. for a script waiting for game frames or I/O without blocking a thread.
+//!
+//! A host function suspends the run by returning an `ErrorKind::Yield` with a value telling the host what it waits for.
+//! The `Vm` keeps the position, stacks and variables of the run, and `resume` continues it with the result of the call.
+//! Its state can be saved as a `VmSnapshot`, e.g. to continue a long-running script after a restart of the process.
+//!
+//! For debuggers, a `Vm` also pauses at breakpoints and after steps, where its stack and variables can be inspected.
+//! Byte codes are then checked one by one, which is slower, so runs without breakpoints or steps are not affected.
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeSet, HashMap},
+    rc::Rc,
+};
+
+use crate::{
+    count_variables, execute_from, initial_variables, ByteCode, CallFrame, CallStack, DynamicConstant, DynamicValue, ErrorKind, Executer,
+    Handler, LinkedProgram, Program, Result, RunLimits, RunState, SourcePosition, FORMAT_VERSION,
+};
+
+/// Outcome of running a `Vm` until the script finishes, a host function yields or the debugger pauses it.
+#[derive(Debug)]
+pub enum VmStatus<B> {
+    Finished(B),
+    /// Value of the `ErrorKind::Yield` returned by the host function, to be resumed with the result of the call.
+    Yielded(DynamicConstant),
+    /// Paused at a breakpoint or after a step, before the byte code at this address.
+    Paused(usize),
+}
+
+#[derive(Clone,Copy,Debug,PartialEq,serde::Serialize, serde::Deserialize)]
+enum Status {
+    NotStarted,
+    Suspended,
+    Paused,
+    Finished,
+}
+
+#[derive(Clone,Copy)]
+enum Step {
+    Into,
+    // Pauses once no deeper than this many script function calls.
+    Over(usize),
+}
+
+// Where a `Vm` pauses.
+#[derive(Default)]
+pub(crate) struct Debugger {
+    breakpoints: BTreeSet<usize>,
+    step: Option<Step>,
+}
+
+impl Debugger {
+    pub(crate) fn is_active(&self) -> bool {
+        return self.step.is_some() || !self.breakpoints.is_empty();
+    }
+    pub(crate) fn should_stop(&self, pos: usize, call_depth: usize) -> bool {
+        return match self.step {
+            Some(Step::Into) => true,
+            Some(Step::Over(depth)) if call_depth <= depth => true,
+            _ => self.breakpoints.contains(&pos),
+        };
+    }
+}
+
+/// State of a `Vm` saved by `Vm::snapshot`, which can be serialized when the `DynamicValue` implementation supports serde.
+///
+/// Values shared by variables and the stack (e.g. a variable being assigned) are saved once, so that they stay shared,
+/// but those shared inside other values (e.g. variables captured by closures) are copied.
+/// Limits are not saved, except for the number of instructions executed.
+#[derive(Clone,Debug,serde::Serialize, serde::Deserialize)]
+pub struct VmSnapshot<B> {
+    version: u32,
+    byte_code_count: usize,
+    pos: usize,
+    status: Status,
+    values: Vec<B>,
+    // Indices of `values`.
+    variables: Vec<usize>,
+    stack: Vec<usize>,
+    frames: Vec<CallFrame>,
+    handlers: Vec<Handler>,
+    var_base: usize,
+    instruction_count: u64,
+}
+
+impl<B> VmSnapshot<B> {
+    // Checks that the snapshot is consistent and was saved for byte codes of the same length,
+    // with the variables the main script and each function call being run allocate.
+    fn check(&self, byte_codes: &[ByteCode], variable_count: usize) -> Result<()> {
+        let byte_code_count = byte_codes.len();
+        if self.version != FORMAT_VERSION {
+            return Err(ErrorKind::Format(format!("Snapshot format version {} is not supported, expected {}!", self.version, FORMAT_VERSION)).into());
+        }
+        if self.byte_code_count != byte_code_count {
+            return Err(ErrorKind::Format("Snapshot of another program!".to_string()).into());
+        }
+        let valid = self.pos <= byte_code_count
+            && self.variables.iter().chain(self.stack.iter()).all(|id| *id < self.values.len())
+            && self.var_base <= self.variables.len()
+            && self.frames.iter().all(|frame| {
+                frame.return_pos <= byte_code_count
+                    && frame.var_base <= self.variables.len()
+                    && frame.stack_base <= self.stack.len()
+                    && frame.handler_count <= self.handlers.len()
+            })
+            && self.handlers.iter().all(|handler| {
+                handler.catch_pos <= byte_code_count
+                    && handler.stack_len <= self.stack.len()
+                    && handler.frame_count <= self.frames.len()
+            });
+        if !valid {
+            return Err(ErrorKind::Format("Invalid snapshot!".to_string()).into());
+        }
+        // Each level runs at a position with its variables starting at a base, and ends where the next level starts.
+        let mut levels = Vec::with_capacity(self.frames.len() + 1);
+        for (i, frame) in self.frames.iter().enumerate() {
+            if frame.return_pos == 0 {
+                return Err(ErrorKind::Format("Invalid snapshot!".to_string()).into());
+            }
+            let end = self.frames.get(i + 1).map_or(self.var_base, |next| next.var_base);
+            levels.push((frame.return_pos - 1, frame.var_base, end));
+        }
+        levels.push((self.pos, self.var_base, self.variables.len()));
+        let valid = levels.into_iter().all(|(pos, base, end)| {
+            return base.checked_add(local_variable_count(byte_codes, variable_count, pos)).is_some_and(|len| len <= end);
+        });
+        if !valid {
+            return Err(ErrorKind::Format("Invalid snapshot!".to_string()).into());
+        }
+        return Ok(());
+    }
+}
+
+// Variables allocated at a position of the main script or a function, where a `ByteCode::Enter` has only its arguments.
+fn local_variable_count(byte_codes: &[ByteCode], variable_count: usize, pos: usize) -> usize {
+    if let Some(ByteCode::Enter(_, param_count, _)) = byte_codes.get(pos) {
+        return *param_count as usize;
+    }
+    for byte_code in byte_codes[..pos.min(byte_codes.len())].iter().rev() {
+        if let ByteCode::Enter(_, param_count, var_count) = byte_code {
+            return (*param_count).max(*var_count) as usize;
+        }
+    }
+    return variable_count;
+}
+
+/// A run which host functions can suspend by returning an `ErrorKind::Yield`.
+///
+/// Only host functions called by the script itself can yield, not those called back by `NativeCallContext::call_fn_ptr`,
+/// and the debugger does not pause inside those either.
+pub struct Vm<'a, B: DynamicValue+std::fmt::Debug> {
+    program: Program<'a, B>,
+    variable_names: &'a [String],
+    state: RunState<B>,
+    // Position to resume at.
+    pos: usize,
+    status: Status,
+}
+
+impl<'a, B: DynamicValue+std::fmt::Debug> Vm<'a, B> {
+    pub fn new(program: &'a LinkedProgram<'_, B>, init_vars: &[B]) -> Result<Self> {
+        program.check_initial_variables(init_vars)?;
+        let variable_count = program.program.variable_count as usize;
+        return Self::with_program(prepare(program)?, &program.program.variable_names, variable_count, init_vars);
+    }
+    /// Prepares byte codes like `run_byte_codes`, whose variables have no names.
+    pub fn from_byte_codes(executer: &'a Executer<B>, byte_codes: &'a Vec<ByteCode>, init_vars: &[B]) -> Result<Self> {
+        let program = Program::new(executer, byte_codes, &[], &[], &RunLimits::default())?;
+        return Self::with_program(program, &[], count_variables(byte_codes)? as usize, init_vars);
+    }
+    /// Continues a run saved by `snapshot` from the same program, e.g. after a restart of the process.
+    pub fn from_snapshot(program: &'a LinkedProgram<'_, B>, snapshot: VmSnapshot<B>) -> Result<Self> {
+        let variable_names = &program.program.variable_names;
+        let variable_count = program.program.variable_count as usize;
+        let program = prepare(program)?;
+        snapshot.check(program.byte_codes, variable_count)?;
+        let values = snapshot.values.into_iter().map(|value| Rc::new(RefCell::new(value))).collect::<Vec<_>>();
+        let state = RunState {
+            variables: snapshot.variables.iter().map(|id| values[*id].clone()).collect(),
+            variable_stack: snapshot.stack.iter().map(|id| values[*id].clone()).collect(),
+            call_stack: CallStack {
+                frames: snapshot.frames,
+                handlers: snapshot.handlers,
+                var_base: snapshot.var_base,
+                base_levels: 0,
+            },
+        };
+        program.instruction_count.set(snapshot.instruction_count);
+        return Ok(Self {
+            program,
+            variable_names,
+            state,
+            pos: snapshot.pos,
+            status: snapshot.status,
+        });
+    }
+    fn with_program(program: Program<'a, B>, variable_names: &'a [String], variable_count: usize, init_vars: &[B]) -> Result<Self> {
+        let variables = initial_variables(variable_count, init_vars)?;
+        return Ok(Self {
+            program,
+            variable_names,
+            state: RunState::new(variables, 0),
+            pos: 0,
+            status: Status::NotStarted,
+        });
+    }
+    /// Saves the state of a run which has not finished yet.
+    pub fn snapshot(&self) -> Result<VmSnapshot<B>> {
+        if self.status == Status::Finished {
+            return Err(self.status_error());
+        }
+        let mut values = Vec::<B>::new();
+        let mut value_ids = HashMap::<*const RefCell<B>,usize>::new();
+        let mut save = |value: &Rc<RefCell<B>>| {
+            return *value_ids.entry(Rc::as_ptr(value)).or_insert_with(|| {
+                values.push(value.borrow().clone()); // Never panics when single-threaded.
+                values.len() - 1
+            });
+        };
+        let variables = self.state.variables.iter().map(&mut save).collect();
+        let stack = self.state.variable_stack.iter().map(&mut save).collect();
+        let call_stack = &self.state.call_stack;
+        return Ok(VmSnapshot {
+            version: FORMAT_VERSION,
+            byte_code_count: self.program.byte_codes.len(),
+            pos: self.pos,
+            status: self.status,
+            values,
+            variables,
+            stack,
+            frames: call_stack.frames.clone(),
+            handlers: call_stack.handlers.clone(),
+            var_base: call_stack.var_base,
+            instruction_count: self.program.instruction_count.get(),
+        });
+    }
+    /// Sets the limits of the whole run, counting the instructions executed before every yield.
+    pub fn set_limits(&mut self, limits: &RunLimits) {
+        self.program.limits = *limits;
+    }
+    /// Runs from the start until the script finishes, a host function yields or a breakpoint is reached.
+    pub fn start(&mut self) -> Result<VmStatus<B>> {
+        if self.status != Status::NotStarted {
+            return Err(self.status_error());
+        }
+        return self.run_from(0, true);
+    }
+    /// Continues a suspended run, with `value` as the result of the host function which yielded.
+    pub fn resume(&mut self, value: B) -> Result<VmStatus<B>> {
+        if self.status != Status::Suspended {
+            return Err(self.status_error());
+        }
+        self.state.variable_stack.push(Rc::new(RefCell::new(value)));
+        return self.run_from(self.pos, true);
+    }
+    pub fn is_suspended(&self) -> bool {
+        return self.status == Status::Suspended;
+    }
+    pub fn is_paused(&self) -> bool {
+        return self.status == Status::Paused;
+    }
+    /// Continues a paused run, or starts it, until the next breakpoint.
+    pub fn continue_run(&mut self) -> Result<VmStatus<B>> {
+        return self.debug(None);
+    }
+    /// Runs a single byte code of a paused run, or the first one.
+    pub fn step(&mut self) -> Result<VmStatus<B>> {
+        return self.debug(Some(Step::Into));
+    }
+    /// Runs a single byte code like `step`, but a call to a script function until it returns.
+    pub fn step_over(&mut self) -> Result<VmStatus<B>> {
+        return self.debug(Some(Step::Over(self.state.call_stack.frames.len())));
+    }
+    pub fn set_breakpoint(&mut self, address: usize) {
+        self.program.debugger.breakpoints.insert(address);
+    }
+    /// Sets breakpoints where the byte codes of a script line start, after those of other lines.
+    ///
+    /// Returns false if there are none, e.g. when built with the "no_position" feature.
+    pub fn set_line_breakpoint(&mut self, line: u32) -> bool {
+        let mut found = false;
+        let mut previous_line = None;
+        for (address, position) in self.program.positions {
+            if position.line == line && previous_line != Some(line) {
+                self.program.debugger.breakpoints.insert(*address as usize);
+                found = true;
+            }
+            previous_line = Some(position.line);
+        }
+        return found;
+    }
+    pub fn remove_breakpoint(&mut self, address: usize) -> bool {
+        return self.program.debugger.breakpoints.remove(&address);
+    }
+    pub fn clear_breakpoints(&mut self) {
+        self.program.debugger.breakpoints.clear();
+    }
+    /// Address of the next byte code to run.
+    pub fn address(&self) -> usize {
+        return self.pos;
+    }
+    pub fn source_position(&self) -> Option<SourcePosition> {
+        return self.program.source_position(self.pos);
+    }
+    /// Number of script function calls in progress.
+    pub fn call_depth(&self) -> usize {
+        return self.state.call_stack.frames.len();
+    }
+    /// Values on the stack, the top one last.
+    pub fn stack(&self) -> &[Rc<RefCell<B>>] {
+        return &self.state.variable_stack;
+    }
+    /// Variables of the main script with the names from `CompiledProgram::variable_names`.
+    ///
+    /// Variables of separate blocks may share a slot, which is named after the last one compiled,
+    /// and hidden ones are named in parentheses, e.g. "(loop_index)".
+    pub fn variables(&self) -> Vec<(&str, &Rc<RefCell<B>>)> {
+        let call_stack = &self.state.call_stack;
+        let count = match (call_stack.frames.len(), call_stack.frames.get(1)) {
+            (0, _) => self.state.variables.len(),
+            (_, Some(frame)) => frame.var_base,
+            (_, None) => call_stack.var_base,
+        };
+        return self.state.variables[..count]
+            .iter()
+            .enumerate()
+            .map(|(index, value)| (self.variable_names.get(index).map(|name| name.as_str()).unwrap_or(""), value))
+            .collect();
+    }
+    /// Variables of the script function running, starting with its parameters, which have no names.
+    ///
+    /// Those of the main script outside of functions.
+    pub fn locals(&self) -> &[Rc<RefCell<B>>] {
+        return &self.state.variables[self.state.call_stack.var_base..];
+    }
+    fn debug(&mut self, step: Option<Step>) -> Result<VmStatus<B>> {
+        if self.status != Status::NotStarted && self.status != Status::Paused {
+            return Err(self.status_error());
+        }
+        let check_first = step.is_none() && self.status == Status::NotStarted;
+        self.program.debugger.step = step;
+        return self.run_from(self.pos, check_first);
+    }
+    #[cold]
+    fn status_error(&self) -> crate::Error {
+        let message = match self.status {
+            Status::NotStarted => "The run has not started yet!",
+            Status::Suspended => "The run is suspended by a yield, and has to be resumed with a value!",
+            Status::Paused => "The run is paused by the debugger!",
+            Status::Finished => "The run has already finished!",
+        };
+        return ErrorKind::Resume(message.to_string()).into();
+    }
+    // Runs from `pos`, which is checked for a breakpoint first, unless continuing from it.
+    fn run_from(&mut self, pos: usize, check_first: bool) -> Result<VmStatus<B>> {
+        if check_first && self.program.debugger.breakpoints.contains(&pos) {
+            self.pos = pos;
+            self.status = Status::Paused;
+            return Ok(VmStatus::Paused(pos));
+        }
+        // A failed run cannot be resumed.
+        self.status = Status::Finished;
+        let mut failed_pos = pos;
+        let res = execute_from(&self.program, &mut self.state, pos, &mut failed_pos);
+        self.program.debugger.step = None;
+        match res {
+            Ok(value) => {
+                return Ok(VmStatus::Finished(value));
+            }
+            Err(err) => match err.into_kind() {
+                ErrorKind::Yield(value) => {
+                    self.pos = failed_pos + 1;
+                    self.status = Status::Suspended;
+                    return Ok(VmStatus::Yielded(value));
+                }
+                ErrorKind::Paused => {
+                    self.pos = failed_pos;
+                    self.status = Status::Paused;
+                    return Ok(VmStatus::Paused(failed_pos));
+                }
+                kind => {
+                    return Err(self.program.locate_error(kind.into(), failed_pos, 0));
+                }
+            },
+        }
+    }
+}
+
+fn prepare<'a, B: DynamicValue+std::fmt::Debug>(program: &'a LinkedProgram<'_, B>) -> Result<Program<'a, B>> {
+    return Program::new(
+        program.executer,
+        &program.byte_codes,
+        &program.program.constants,
+        &program.program.positions,
+        &RunLimits::default(),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_value::{compile, new_executer, Value};
+    use crate::CompiledProgram;
+
+    fn yielded(status: Result<VmStatus<Value>>) -> DynamicConstant {
+        match status {
+            Ok(VmStatus::Yielded(value)) => return value,
+            status => panic!("Unexpected status {:?}", status),
+        }
+    }
+
+    fn finished(status: Result<VmStatus<Value>>) -> Value {
+        match status {
+            Ok(VmStatus::Finished(value)) => return value,
+            status => panic!("Unexpected status {:?}", status),
+        }
+    }
+
+    #[test]
+    fn yield_and_resume() {
+        let executer = new_executer();
+        let byte_codes = compile(&executer, "let a = wait_for(1); let b = wait_for(a + 1); a + b");
+        let mut vm = Vm::from_byte_codes(&executer, &byte_codes, &[]).unwrap();
+        assert!(matches!(yielded(vm.start()), DynamicConstant::Integer(1)));
+        assert!(vm.is_suspended());
+        assert!(matches!(yielded(vm.resume(Value::Integer(10))), DynamicConstant::Integer(11)));
+        assert_eq!(finished(vm.resume(Value::Integer(5))), Value::Integer(15));
+        assert!(matches!(vm.resume(Value::Unit).map_err(|err| err.into_kind()), Err(ErrorKind::Resume(_))));
+    }
+
+    #[cfg(not(feature = "no_function"))]
+    #[test]
+    fn yield_inside_functions_and_loops() {
+        let executer = new_executer();
+        let program = CompiledProgram::from_script(&executer, &[], "fn wait(n) { wait_for(n) + 1 } let s = 0; for i in 0..3 { s += wait(i); } s").unwrap();
+        let linked_program = program.link(&executer).unwrap();
+        let mut vm = Vm::new(&linked_program, &[]).unwrap();
+        let mut status = vm.start();
+        for i in 0..3 {
+            assert!(matches!(yielded(status), DynamicConstant::Integer(v) if v == i));
+            status = vm.resume(Value::Integer(10));
+        }
+        assert_eq!(finished(status), Value::Integer(33));
+    }
+
+    #[cfg(not(feature = "no_function"))]
+    #[test]
+    fn snapshot_and_restore() {
+        let executer = new_executer();
+        let program = CompiledProgram::from_script(&executer, &[], "fn f(n) { wait_for(n) + n } let a = [1, 2]; let s = 0; for i in 0..2 { s += f(i); } a[1] + s").unwrap();
+        let linked_program = program.link(&executer).unwrap();
+        let mut vm = Vm::new(&linked_program, &[]).unwrap();
+        assert!(matches!(yielded(vm.start()), DynamicConstant::Integer(0)));
+        let snapshot = vm.snapshot().unwrap();
+        drop(vm);
+        let mut vm = Vm::from_snapshot(&linked_program, snapshot.clone()).unwrap();
+        assert_eq!(vm.call_depth(), 1);
+        assert!(matches!(yielded(vm.resume(Value::Integer(10))), DynamicConstant::Integer(1)));
+        assert_eq!(finished(vm.resume(Value::Integer(20))), Value::Integer(33));
+        assert!(matches!(vm.snapshot().map_err(|err| err.into_kind()), Err(ErrorKind::Resume(_))));
+        // The same snapshot can be restored again.
+        let mut vm = Vm::from_snapshot(&linked_program, snapshot.clone()).unwrap();
+        assert!(matches!(yielded(vm.resume(Value::Integer(0))), DynamicConstant::Integer(1)));
+        // Snapshots missing variables of the main script or of the function call are rejected.
+        for len in [0, snapshot.var_base, snapshot.variables.len() - 1] {
+            let mut truncated = snapshot.clone();
+            truncated.variables.truncate(len);
+            assert!(matches!(Vm::from_snapshot(&linked_program, truncated).map(|_| ()).map_err(|err| err.into_kind()), Err(ErrorKind::Format(_))));
+        }
+    }
+
+    #[test]
+    fn snapshot_of_another_program_is_rejected() {
+        let executer = new_executer();
+        let program = CompiledProgram::from_script(&executer, &[], "wait_for(1) + 1").unwrap();
+        let linked_program = program.link(&executer).unwrap();
+        let mut vm = Vm::new(&linked_program, &[]).unwrap();
+        yielded(vm.start());
+        let snapshot = vm.snapshot().unwrap();
+        let other_program = CompiledProgram::from_script(&executer, &[], "let x = [1, 2, 3]; let y = x[0]; y + x[1] + x[2]").unwrap();
+        let other_linked_program = other_program.link(&executer).unwrap();
+        assert!(matches!(Vm::from_snapshot(&other_linked_program, snapshot).map(|_| ()).map_err(|err| err.into_kind()), Err(ErrorKind::Format(_))));
+    }
+
+    #[test]
+    fn truncated_snapshot_is_rejected() {
+        let executer = new_executer();
+        let program = CompiledProgram::from_script(&executer, &[], "let x = 1; wait_for(x) + x").unwrap();
+        let linked_program = program.link(&executer).unwrap();
+        let mut vm = Vm::new(&linked_program, &[]).unwrap();
+        yielded(vm.start());
+        let mut snapshot = vm.snapshot().unwrap();
+        assert!(Vm::from_snapshot(&linked_program, snapshot.clone()).is_ok());
+        snapshot.variables.clear();
+        assert!(matches!(Vm::from_snapshot(&linked_program, snapshot).map(|_| ()).map_err(|err| err.into_kind()), Err(ErrorKind::Format(_))));
+    }
+
+    fn paused(status: Result<VmStatus<Value>>) -> usize {
+        match status {
+            Ok(VmStatus::Paused(address)) => return address,
+            status => panic!("Unexpected status {:?}", status),
+        }
+    }
+
+    #[cfg(not(feature = "no_function"))]
+    fn debugged_program(executer: &Executer<Value>) -> CompiledProgram {
+        return CompiledProgram::from_script(executer, &[], "fn f(n) { n + 1 } let a = 1; let b = f(a); a + b").unwrap();
+    }
+
+    #[cfg(not(feature = "no_function"))]
+    fn address_of(program: &CompiledProgram, is_byte_code: impl Fn(&ByteCode) -> bool) -> usize {
+        return program.byte_codes.iter().position(is_byte_code).unwrap();
+    }
+
+    #[cfg(not(feature = "no_function"))]
+    #[test]
+    fn breakpoints() {
+        let executer = new_executer();
+        let program = debugged_program(&executer);
+        let in_function = address_of(&program, |byte_code| matches!(byte_code, ByteCode::Enter(..))) + 1;
+        let after_call = address_of(&program, |byte_code| matches!(byte_code, ByteCode::Call(..))) + 1;
+        let linked_program = program.link(&executer).unwrap();
+        let mut vm = Vm::new(&linked_program, &[]).unwrap();
+        vm.set_breakpoint(in_function);
+        vm.set_breakpoint(after_call);
+        assert_eq!(paused(vm.continue_run()), in_function);
+        assert!(vm.is_paused());
+        assert_eq!(vm.call_depth(), 1);
+        assert_eq!(*vm.locals()[0].borrow(), Value::Integer(1));
+        let variables = vm.variables();
+        assert_eq!(variables[0].0, "a");
+        assert_eq!(*variables[0].1.borrow(), Value::Integer(1));
+        assert_eq!(paused(vm.continue_run()), after_call);
+        assert_eq!(vm.call_depth(), 0);
+        assert_eq!(*vm.stack().last().unwrap().borrow(), Value::Integer(2));
+        assert!(vm.remove_breakpoint(after_call));
+        assert!(!vm.remove_breakpoint(after_call));
+        assert_eq!(finished(vm.continue_run()), Value::Integer(3));
+    }
+
+    #[cfg(not(feature = "no_function"))]
+    #[test]
+    fn steps() {
+        let executer = new_executer();
+        let program = debugged_program(&executer);
+        let call = address_of(&program, |byte_code| matches!(byte_code, ByteCode::Call(..)));
+        let linked_program = program.link(&executer).unwrap();
+        let mut vm = Vm::new(&linked_program, &[]).unwrap();
+        assert_eq!(paused(vm.step()), 1);
+        assert_eq!(vm.stack().len(), 1);
+        while vm.address() < call {
+            paused(vm.step());
+        }
+        assert_eq!(paused(vm.step_over()), call + 1);
+        assert_eq!(vm.call_depth(), 0);
+        let mut vm = Vm::new(&linked_program, &[]).unwrap();
+        vm.set_breakpoint(call);
+        assert_eq!(paused(vm.continue_run()), call);
+        paused(vm.step());
+        assert_eq!(vm.call_depth(), 1);
+        vm.clear_breakpoints();
+        assert_eq!(finished(vm.continue_run()), Value::Integer(3));
+    }
+
+    #[test]
+    fn line_breakpoints() {
+        let executer = new_executer();
+        let program = CompiledProgram::from_script(&executer, &[], "let a = 1;\nlet b = a + 1;\nb").unwrap();
+        let linked_program = program.link(&executer).unwrap();
+        let mut vm = Vm::new(&linked_program, &[]).unwrap();
+        assert_eq!(vm.set_line_breakpoint(2), cfg!(not(feature = "no_position")));
+        if cfg!(not(feature = "no_position")) {
+            paused(vm.continue_run());
+            assert_eq!(vm.source_position().map(|position| position.line), Some(2));
+        }
+        assert_eq!(finished(vm.continue_run()), Value::Integer(2));
+    }
+
+    #[test]
+    fn resume_needs_a_suspended_run() {
+        let executer = new_executer();
+        let byte_codes = compile(&executer, "1 + 2");
+        let mut vm = Vm::from_byte_codes(&executer, &byte_codes, &[]).unwrap();
+        assert!(matches!(vm.resume(Value::Unit).map_err(|err| err.into_kind()), Err(ErrorKind::Resume(_))));
+        assert_eq!(finished(vm.start()), Value::Integer(3));
+        assert!(matches!(vm.start().map_err(|err| err.into_kind()), Err(ErrorKind::Resume(_))));
+    }
+}